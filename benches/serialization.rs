@@ -0,0 +1,87 @@
+use std::{hint::black_box, io};
+
+use bytecode_vm::{
+    deserialize_code, deserialize_text, serialize_code, serialize_text, Instruction, Machine,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// Prints Fibonacci(30). The stack holds n, a and b, with a and b the
+// last two Fibonacci numbers and n the steps left
+fn fibonacci() -> Vec<Instruction> {
+    vec![
+        Instruction::Push(30),
+        Instruction::Push(0),
+        Instruction::Push(1),
+        Instruction::Push(0),
+        Instruction::Eq(0, 3, 12),
+        Instruction::DropRange(0, 1),
+        Instruction::Copy(0),
+        Instruction::Copy(2),
+        Instruction::Add(0, 1),
+        Instruction::DropRange(2, 1),
+        Instruction::Dec(2),
+        Instruction::Jmp(3),
+        Instruction::Out(2),
+    ]
+}
+
+// A program large enough for the encoding to dominate, built from a mix of
+// instructions with small and large operands and strings
+fn large_program() -> Vec<Instruction> {
+    let body = [
+        Instruction::Push(u64::MAX),
+        Instruction::OutStr("Hello, world!".to_string()),
+        Instruction::Gt(0, 1, 1000),
+        Instruction::StrCat(2, 3),
+        Instruction::Push128(u128::MAX / 3),
+    ];
+    fibonacci()
+        .into_iter()
+        .chain(body)
+        .cycle()
+        .take(10_000)
+        .collect()
+}
+
+fn bench_serialization(c: &mut Criterion) {
+    let code = large_program();
+    let mut binary = Vec::new();
+    serialize_code(&code, &mut binary).unwrap();
+    let mut text = Vec::new();
+    serialize_text(&code, &mut text).unwrap();
+
+    c.bench_function("serialize_code", |b| {
+        b.iter(|| {
+            let mut output = Vec::with_capacity(binary.len());
+            serialize_code(black_box(&code), &mut output).unwrap();
+            output
+        })
+    });
+    c.bench_function("deserialize_code", |b| {
+        b.iter(|| deserialize_code(&mut black_box(&binary[..])).unwrap())
+    });
+    c.bench_function("serialize_text", |b| {
+        b.iter(|| {
+            let mut output = Vec::with_capacity(text.len());
+            serialize_text(black_box(&code), &mut output).unwrap();
+            output
+        })
+    });
+    c.bench_function("deserialize_text", |b| {
+        b.iter(|| deserialize_text(&mut black_box(&text[..])).unwrap())
+    });
+}
+
+fn bench_run(c: &mut Criterion) {
+    let code = fibonacci();
+    c.bench_function("run_fibonacci", |b| {
+        b.iter(|| {
+            let mut machine = Machine::new(black_box(code.clone()));
+            machine.run(&mut &[][..], &mut io::sink()).unwrap();
+            machine
+        })
+    });
+}
+
+criterion_group!(benches, bench_serialization, bench_run);
+criterion_main!(benches);
@@ -0,0 +1,575 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+};
+
+use crate::{unfuse, DebugInfo, Instruction, VmError};
+
+mod macros;
+
+// Text assembler for .basm sources. Each line holds at most one instruction:
+// an uppercase mnemonic followed by space-separated decimal operands, or a
+// quoted string for OUTSTR. A line may start with a `name:` label which
+// operands refer to as `@name`. Everything after `;` is a comment.
+// Aliases such as DUP (COPY 0) and DOUBLE (COPY 0, ADD 0 1) expand to
+// one or more instructions. Macros are expanded first, see macros.rs
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+// Source location of each assembled instruction, indexed by pc
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    pub locations: Vec<SourceLocation>,
+}
+
+impl SourceMap {
+    pub fn location(&self, pc: u64) -> Option<SourceLocation> {
+        self.locations.get(pc as usize).copied()
+    }
+
+    // Attaches the source location of the instruction at pc to the error
+    pub fn locate(&self, pc: u64, error: VmError) -> VmError {
+        match self.location(pc) {
+            Some(location) => VmError::Located {
+                pc,
+                line: location.line,
+                column: Some(location.column),
+                source_file: None,
+                error: Box::new(error),
+            },
+            None => error,
+        }
+    }
+
+    pub fn to_debug_info(&self, source_file: Option<String>) -> DebugInfo {
+        DebugInfo {
+            line_map: self.locations.iter().map(|l| Some(l.line)).collect(),
+            source_file,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyOutput {
+    pub instructions: Vec<Instruction>,
+    pub source_map: Option<SourceMap>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    source_map: bool,
+    // Mnemonics that expand to a fixed sequence of instructions. They are
+    // looked up before the instruction mnemonics and take no operands
+    aliases: HashMap<String, Vec<Instruction>>,
+}
+
+// An instruction line that still has unresolved label references
+struct Statement<'a> {
+    location: SourceLocation,
+    mnemonic: &'a str,
+    operands: &'a str,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        let mut assembler = Assembler {
+            source_map: false,
+            aliases: HashMap::new(),
+        };
+        assembler.register_alias("DUP", vec![Instruction::Copy(0)]);
+        assembler.register_alias("OVER", vec![Instruction::Copy(1)]);
+        assembler.register_alias("DOUBLE", vec![Instruction::Copy(0), Instruction::Add(0, 1)]);
+        assembler.register_alias("DROP", vec![Instruction::DropRange(0, 1)]);
+        assembler.register_alias("NIP", vec![Instruction::DropRange(1, 1)]);
+        assembler
+    }
+
+    // Makes name expand to the given instructions, replacing any alias
+    // previously registered under it
+    pub fn register_alias(&mut self, name: &str, expansion: Vec<Instruction>) {
+        self.aliases.insert(name.to_string(), expansion);
+    }
+
+    pub fn with_source_map(mut self, source_map: bool) -> Self {
+        self.source_map = source_map;
+        self
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<AssemblyOutput, AssembleError> {
+        let mut labels = HashMap::new();
+        let mut statements = Vec::new();
+        // Number of instructions the statements so far expand to
+        let mut pc = 0;
+
+        let lines = macros::expand(source)?;
+        for line in &lines {
+            let line_number = line.number;
+            let line = line.text.as_str();
+            let error = |column: usize, message: String| AssembleError {
+                line: line_number,
+                column: column as u32 + 1,
+                message,
+            };
+
+            // rest always points into line, so its offset is the column
+            let column = |rest: &str| rest.as_ptr() as usize - line.as_ptr() as usize;
+            let mut rest = strip_comment(line).trim_start();
+
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if let Some(label) = rest[..word_end].strip_suffix(':') {
+                if !is_label(label) {
+                    return Err(error(
+                        column(rest),
+                        format!("invalid label name '{}'", label),
+                    ));
+                }
+                if labels.insert(label, pc).is_some() {
+                    return Err(error(column(rest), format!("duplicate label '{}'", label)));
+                }
+                rest = rest[word_end..].trim_start();
+            }
+            if rest.is_empty() {
+                continue;
+            }
+
+            let location = SourceLocation {
+                line: line_number,
+                column: column(rest) as u32 + 1,
+            };
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let mnemonic = &rest[..word_end];
+            rest = rest[word_end..].trim_start();
+            pc += self.aliases.get(mnemonic).map_or(1, Vec::len) as u64;
+            statements.push(Statement {
+                location,
+                mnemonic,
+                operands: rest.trim_end(),
+            });
+        }
+
+        let mut instructions = Vec::with_capacity(pc as usize);
+        let mut locations = Vec::with_capacity(pc as usize);
+        for statement in &statements {
+            let error = |message| AssembleError {
+                line: statement.location.line,
+                column: statement.location.column,
+                message,
+            };
+            match self.aliases.get(statement.mnemonic) {
+                Some(_) if !statement.operands.is_empty() => {
+                    return Err(error(format!(
+                        "alias '{}' takes no operands",
+                        statement.mnemonic
+                    )));
+                }
+                Some(expansion) => instructions.extend(expansion.iter().cloned()),
+                None => instructions.push(
+                    parse_instruction(statement.mnemonic, statement.operands, &labels)
+                        .map_err(error)?,
+                ),
+            }
+            // Every instruction of an alias maps to the alias line
+            locations.resize(instructions.len(), statement.location);
+        }
+
+        let source_map = self.source_map.then_some(SourceMap { locations });
+
+        Ok(AssemblyOutput {
+            instructions,
+            source_map,
+        })
+    }
+}
+
+// Assembles a source without producing a source map
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, AssembleError> {
+    Ok(Assembler::new().assemble(source)?.instructions)
+}
+
+// Writes one instruction per line in the syntax accepted by assemble
+pub fn serialize_text<W: Write>(instructions: &[Instruction], writer: &mut W) -> io::Result<()> {
+    for instruction in unfuse(instructions).iter() {
+        writeln!(writer, "{}", instruction)?;
+    }
+    Ok(())
+}
+
+// Reads instructions written by serialize_text, or any other assembler source
+pub fn deserialize_text<R: Read>(reader: &mut R) -> io::Result<Vec<Instruction>> {
+    let mut source = String::new();
+    reader.read_to_string(&mut source)?;
+    assemble(&source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// The characters of line outside string literals, with their byte offsets
+fn unquoted(line: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut in_string = false;
+    let mut escaped = false;
+    line.char_indices().filter(move |&(_, c)| {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ => return !in_string,
+        }
+        false
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    match unquoted(line).find(|&(_, c)| c == ';') {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// An identifier, or a label the macro expander made from %%name, which is
+// name followed by $ and the number of the expansion
+fn is_label(name: &str) -> bool {
+    match name.split_once('$') {
+        Some((name, expansion)) => {
+            is_identifier(name)
+                && !expansion.is_empty()
+                && expansion.chars().all(|c| c.is_ascii_digit())
+        }
+        None => is_identifier(name),
+    }
+}
+
+fn parse_instruction(
+    mnemonic: &str,
+    operands: &str,
+    labels: &HashMap<&str, u64>,
+) -> Result<Instruction, String> {
+    match mnemonic {
+        "OUTSTR" => return Ok(Instruction::OutStr(parse_string(operands)?)),
+        "OUTSTRNONL" => return Ok(Instruction::OutStrNoNl(parse_string(operands)?)),
+        _ => (),
+    }
+
+    let operands = operands
+        .split_whitespace()
+        .map(|operand| match operand.strip_prefix('@') {
+            Some(label) => labels
+                .get(label)
+                .copied()
+                .ok_or_else(|| format!("undefined label '{}'", label)),
+            None => operand
+                .parse()
+                .map_err(|_| format!("invalid operand '{}'", operand)),
+        })
+        .collect::<Result<Vec<u64>, _>>()?;
+
+    Instruction::from_mnemonic(mnemonic, &operands).ok_or_else(|| {
+        format!(
+            "unknown instruction '{}' with {} operands",
+            mnemonic,
+            operands.len()
+        )
+    })
+}
+
+fn parse_string(operand: &str) -> Result<String, String> {
+    let inner = operand
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .filter(|_| operand.len() >= 2)
+        .ok_or_else(|| format!("expected a quoted string, found '{}'", operand))?;
+
+    let mut string = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('r') => string.push('\r'),
+            Some('t') => string.push('\t'),
+            Some('\\') => string.push('\\'),
+            Some('"') => string.push('"'),
+            other => return Err(format!("invalid escape '\\{}'", other.unwrap_or(' '))),
+        }
+    }
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialize_code, serialize_code, Machine};
+
+    const COUNTDOWN: &str = "\
+; prints 3, 2, 1
+    PUSH 3
+loop:
+    OUT 0        ; current value
+    DEC 0
+    PUSH 0
+    EQ 0 1 @end
+    OUTBYTE 0    ; never reached with a zero on top
+end:    OUTSTR \"done; \\\"ok\\\"\"
+";
+
+    #[test]
+    fn test_assemble() {
+        let instructions = assemble(COUNTDOWN).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Push(3),
+                Instruction::Out(0),
+                Instruction::Dec(0),
+                Instruction::Push(0),
+                Instruction::Eq(0, 1, 6),
+                Instruction::OutByte(0),
+                Instruction::OutStr("done; \"ok\"".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_crlf() {
+        assert_eq!(
+            assemble(&COUNTDOWN.replace('\n', "\r\n")).unwrap(),
+            assemble(COUNTDOWN).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let output = Assembler::new()
+            .with_source_map(true)
+            .assemble(COUNTDOWN)
+            .unwrap();
+        let source_map = output.source_map.unwrap();
+
+        assert_eq!(source_map.locations.len(), output.instructions.len());
+        assert_eq!(
+            source_map.location(0),
+            Some(SourceLocation { line: 2, column: 5 })
+        );
+        assert_eq!(
+            source_map.location(6),
+            Some(SourceLocation { line: 9, column: 9 })
+        );
+        assert_eq!(source_map.location(7), None);
+
+        assert!(Assembler::new()
+            .assemble(COUNTDOWN)
+            .unwrap()
+            .source_map
+            .is_none());
+    }
+
+    #[test]
+    fn test_source_map_locates_error() {
+        let output = Assembler::new()
+            .with_source_map(true)
+            .assemble("PUSH 1\n  IN\n")
+            .unwrap();
+        let mut machine = Machine {
+            code: output.instructions,
+            stack: vec![],
+            pc: 0,
+            ..Default::default()
+        };
+        let error = machine
+            .run(&mut &b"\xff\n"[..], &mut Vec::new())
+            .map_err(|e| output.source_map.as_ref().unwrap().locate(machine.pc, e))
+            .unwrap_err();
+
+        assert!(matches!(
+            error,
+            VmError::Located {
+                pc: 1,
+                line: 2,
+                column: Some(3),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = assemble("PUSH 1\nPUSH\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1));
+
+        let error = assemble("  JMP @nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 3));
+        assert_eq!(error.message, "undefined label 'nowhere'");
+
+        assert!(assemble("a:\na:\n").is_err());
+        assert!(assemble("OUTSTR hello").is_err());
+        assert!(assemble("PUSH -1").is_err());
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let instructions = vec![
+            Instruction::Push(u64::MAX),
+            Instruction::In(),
+            Instruction::Add(0, 1),
+            Instruction::Gt(1, 0, 7),
+            Instruction::OutStr("tab\there \"quoted\" \\ ; not a comment\r\n".to_string()),
+            Instruction::OutStrNoNl(String::new()),
+            Instruction::StrStore(2, 1, 0),
+            Instruction::TwoSwap(),
+            Instruction::fuse(vec![Instruction::Copy(0), Instruction::Add(0, 1)]).unwrap(),
+        ];
+        let mut text = Vec::new();
+        serialize_text(&instructions, &mut text).unwrap();
+        let from_text = deserialize_text(&mut &text[..]).unwrap();
+
+        let mut binary = Vec::new();
+        serialize_code(&instructions, &mut binary).unwrap();
+        let from_binary = deserialize_code(&mut &binary[..]).unwrap();
+
+        assert_eq!(from_text, from_binary);
+        assert_eq!(from_text[..8], instructions[..8]);
+        assert_eq!(
+            String::from_utf8(text).unwrap().lines().nth(3),
+            Some("GT 1 0 7")
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut assembler = Assembler::new().with_source_map(true);
+        assembler.register_alias("TWICE", vec![Instruction::Out(0), Instruction::Out(0)]);
+        let output = assembler
+            .assemble("PUSH 2\nDOUBLE\nTWICE\nend: JMP @end\n")
+            .unwrap();
+
+        assert_eq!(
+            output.instructions,
+            vec![
+                Instruction::Push(2),
+                Instruction::Copy(0),
+                Instruction::Add(0, 1),
+                Instruction::Out(0),
+                Instruction::Out(0),
+                Instruction::Jmp(5),
+            ]
+        );
+        let source_map = output.source_map.unwrap();
+        assert_eq!(source_map.location(2).unwrap().line, 2);
+        assert_eq!(source_map.location(4).unwrap().line, 3);
+        assert_eq!(source_map.location(5).unwrap().line, 4);
+
+        let mut machine = Machine::new(assemble("PUSH 1\nPUSH 2\nPUSH 3\nNIP\nDROP\n").unwrap());
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert_eq!(
+            machine.code[3..],
+            [Instruction::DropRange(1, 1), Instruction::DropRange(0, 1)]
+        );
+        assert_eq!(machine.stack, [1]);
+
+        let error = assemble("DUP 1").unwrap_err();
+        assert_eq!(error.message, "alias 'DUP' takes no operands");
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "\
+%macro countdown(from)
+    PUSH 0
+    PUSH %from
+%%loop:
+    OUT 0
+    DEC 0
+    EQ 0 1 @%%end
+    JMP @%%loop
+%%end:
+%endmacro
+%macro twice(a, b)
+    %countdown %a
+    %countdown %b
+%endmacro
+start: %twice 2 1
+";
+        let output = Assembler::new()
+            .with_source_map(true)
+            .assemble(source)
+            .unwrap();
+        let mut machine = Machine::new(output.instructions);
+        let mut stdout = Vec::new();
+        machine.run(&mut &[][..], &mut stdout).unwrap();
+
+        assert_eq!(stdout, b"2\n1\n1\n");
+        assert_eq!(machine.code.len(), 12);
+        assert_eq!(machine.code[5], Instruction::Jmp(2));
+        assert_eq!(machine.code[11], Instruction::Jmp(8));
+        assert_eq!(output.source_map.unwrap().location(11).unwrap().line, 15);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let recursive = "%macro a()\n%b\n%endmacro\n%macro b()\n%a\n%endmacro\n%a\n";
+        let error = assemble(recursive).unwrap_err();
+        assert_eq!(
+            (error.line, error.message.as_str()),
+            (7, "recursive macro 'a'")
+        );
+
+        assert!(assemble("%macro a(x)\nPUSH %x\n").is_err());
+        assert!(assemble("%undefined 1").is_err());
+        assert!(assemble("%macro a(x)\nPUSH %x\n%endmacro\n%a").is_err());
+
+        let error = assemble("PUSH 1\ntop$1: OUT 0").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 4));
+        assert!(assemble("%macro a()\nx$1: OUT 0\n%endmacro\n").is_err());
+    }
+
+    #[test]
+    fn test_macro_labels_and_strings() {
+        // Labels of an expansion cannot collide with the ones in the source,
+        // and % in a string is not a parameter
+        let source = "\
+%macro show(x)
+%%top:
+    OUTSTR \"%x; %%top\"
+    OUT %x
+%endmacro
+__1_top: PUSH 7
+top: %show 0
+";
+        let code = assemble(source).unwrap();
+        assert_eq!(code[1], Instruction::OutStr("%x; %%top".to_string()));
+        assert_eq!(code[2], Instruction::Out(0));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use super::{is_identifier, strip_comment, unquoted, AssembleError};
+
+// Macro pre-pass of the assembler. A macro is defined by
+//
+//     %macro name(param, other)
+//         ...
+//     %endmacro
+//
+// and invoked on its own line, optionally after a label, as `%name arg arg`.
+// In the body, `%param` is replaced by the argument and `%%label` by
+// `label$n`, where n numbers the expansion. Source may not contain `$`
+// outside strings, so these never collide with its labels. Strings are left
+// alone. Bodies may invoke other macros, but not themselves, directly or
+// indirectly
+
+// A line of source after expansion, with the number of the line it comes
+// from. Lines produced by a macro come from the invocation
+pub(super) struct Line {
+    pub number: u32,
+    pub text: String,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+fn error(line: u32, message: String) -> AssembleError {
+    AssembleError {
+        line,
+        column: 1,
+        message,
+    }
+}
+
+pub(super) fn expand(source: &str) -> Result<Vec<Line>, AssembleError> {
+    let mut macros = HashMap::new();
+    let mut top_level = Vec::new();
+
+    let mut lines = (1..).zip(source.lines());
+    while let Some((number, line)) = lines.next() {
+        check_reserved(number, line)?;
+        let code = strip_comment(line).trim();
+        if let Some(header) = code.strip_prefix("%macro ") {
+            let (name, params) = parse_header(header).map_err(|m| error(number, m))?;
+            let mut body = Vec::new();
+            loop {
+                let Some((number, line)) = lines.next() else {
+                    return Err(error(number, format!("macro '{}' has no %endmacro", name)));
+                };
+                if strip_comment(line).trim() == "%endmacro" {
+                    break;
+                }
+                check_reserved(number, line)?;
+                body.push(line.to_string());
+            }
+            if macros.insert(name, Macro { params, body }).is_some() {
+                return Err(error(number, "duplicate macro definition".to_string()));
+            }
+        } else if code == "%endmacro" {
+            return Err(error(number, "%endmacro without %macro".to_string()));
+        } else {
+            top_level.push((number, line));
+        }
+    }
+
+    // Macros may be invoked before they are defined
+    let mut expander = Expander {
+        macros: &macros,
+        expansions: 0,
+        active: Vec::new(),
+        output: Vec::new(),
+    };
+    for (number, line) in top_level {
+        expander.line(number, line)?;
+    }
+    Ok(expander.output)
+}
+
+// Only the expander may write `$`, so that its labels are unique
+fn check_reserved(number: u32, line: &str) -> Result<(), AssembleError> {
+    match unquoted(strip_comment(line)).find(|&(_, c)| c == '$') {
+        Some((column, _)) => Err(AssembleError {
+            line: number,
+            column: column as u32 + 1,
+            message: "'$' is reserved for macro labels".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Parses `name(param, ...)`
+fn parse_header(header: &str) -> Result<(String, Vec<String>), String> {
+    let invalid = || format!("invalid macro header '{}'", header.trim());
+    let (name, params) = header.trim().split_once('(').ok_or_else(invalid)?;
+    let params = params.strip_suffix(')').ok_or_else(invalid)?;
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(invalid());
+    }
+    let params: Vec<String> = params
+        .split(',')
+        .map(str::trim)
+        .filter(|param| !param.is_empty())
+        .map(str::to_string)
+        .collect();
+    if !params.iter().all(|param| is_identifier(param)) {
+        return Err(invalid());
+    }
+    Ok((name.to_string(), params))
+}
+
+struct Expander<'a> {
+    macros: &'a HashMap<String, Macro>,
+    expansions: u32,
+    // Macros being expanded, innermost last
+    active: Vec<&'a str>,
+    output: Vec<Line>,
+}
+
+impl<'a> Expander<'a> {
+    fn line(&mut self, number: u32, text: &str) -> Result<(), AssembleError> {
+        let code = strip_comment(text).trim();
+        let (label, rest) = match code.split_once(char::is_whitespace) {
+            Some((first, rest)) if first.ends_with(':') => (Some(first), rest.trim_start()),
+            _ => (None, code),
+        };
+        let Some(invocation) = rest.strip_prefix('%') else {
+            self.output.push(Line {
+                number,
+                text: text.to_string(),
+            });
+            return Ok(());
+        };
+
+        if let Some(label) = label {
+            self.output.push(Line {
+                number,
+                text: label.to_string(),
+            });
+        }
+        let mut words = invocation.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let (name, definition) = self
+            .macros
+            .get_key_value(name)
+            .ok_or_else(|| error(number, format!("undefined macro '{}'", name)))?;
+        if self.active.contains(&name.as_str()) {
+            return Err(error(number, format!("recursive macro '{}'", name)));
+        }
+        if args.len() != definition.params.len() {
+            return Err(error(
+                number,
+                format!(
+                    "macro '{}' takes {} arguments, found {}",
+                    name,
+                    definition.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+
+        self.expansions += 1;
+        let expansion = self.expansions;
+        self.active.push(name);
+        for body_line in &definition.body {
+            let text = substitute(body_line, &definition.params, &args, expansion);
+            self.line(number, &text)?;
+        }
+        self.active.pop();
+        Ok(())
+    }
+}
+
+// Replaces `%param` with its argument and `%%label` with a label unique
+// to the expansion. Anything else after `%`, and anything in a string, is
+// kept
+fn substitute(line: &str, params: &[String], args: &[&str], expansion: u32) -> String {
+    let mut result = String::with_capacity(line.len());
+    // Everything before this offset is in result
+    let mut copied = 0;
+    for (start, _) in unquoted(line).filter(|&(_, c)| c == '%') {
+        // The second % of a %% already replaced
+        if start < copied {
+            continue;
+        }
+        let rest = &line[start + 1..];
+        let local = rest.starts_with('%');
+        let name_start = if local { 1 } else { 0 };
+        let name_len = rest[name_start..]
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len() - name_start);
+        let name = &rest[name_start..name_start + name_len];
+
+        let replacement = match params.iter().position(|param| param == name) {
+            _ if local && !name.is_empty() => format!("{}${}", name, expansion),
+            Some(index) if !local => args[index].to_string(),
+            _ => continue,
+        };
+        result.push_str(&line[copied..start]);
+        result.push_str(&replacement);
+        copied = start + 1 + name_start + name_len;
+    }
+    result.push_str(&line[copied..]);
+    result
+}
@@ -0,0 +1,107 @@
+use std::thread;
+
+use crate::{ExecutionStats, Machine, MachineState, VmError};
+
+// What one run of a batch wrote and how it went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchRunResult {
+    pub output: Vec<u8>,
+    pub stats: ExecutionStats,
+}
+
+impl Machine {
+    // Runs a fork of the machine on each input, spread over the available
+    // cores. Every run starts from the current state and the machine itself
+    // is left unchanged. Like fork, the runs have no native functions and
+    // every run fails while the machine has open files. The results are in
+    // the order of the inputs
+    pub fn run_batch(&self, inputs: Vec<Vec<u8>>) -> Vec<Result<BatchRunResult, VmError>> {
+        // Copied for each run as it starts, so only the running forks are in
+        // memory at once
+        let state = match MachineState::of(self) {
+            Ok(state) => state,
+            // Only open files stop a fork, and they stop every run
+            Err(_) => {
+                return inputs
+                    .iter()
+                    .map(|_| Err(VmError::ForkWithOpenFiles))
+                    .collect();
+            }
+        };
+        let threads = thread::available_parallelism().map_or(1, usize::from);
+        let chunk_size = inputs.len().div_ceil(threads).max(1);
+        let mut chunks = Vec::new();
+        let mut inputs = inputs.into_iter().peekable();
+        while inputs.peek().is_some() {
+            chunks.push(inputs.by_ref().take(chunk_size).collect::<Vec<_>>());
+        }
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    let state = &state;
+                    scope.spawn(move || {
+                        chunk
+                            .into_iter()
+                            .map(|input| run_one(state.clone().into_machine(), &input))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("batch worker panicked"))
+                .collect()
+        })
+    }
+}
+
+fn run_one(mut machine: Machine, input: &[u8]) -> Result<BatchRunResult, VmError> {
+    let mut output = Vec::new();
+    let stats = machine.run_with_stats(&mut &input[..], &mut output)?;
+    Ok(BatchRunResult { output, stats })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, RunResult};
+
+    #[test]
+    fn test_run_batch() {
+        let mut machine = Machine::new(assemble("PUSH 1\nIN\nADD 0 1\nOUT 0").unwrap());
+        machine.stack = vec![10];
+        let inputs = (0..20).map(|i| format!("{}\n", i).into_bytes()).collect();
+
+        let results = machine.run_batch(inputs);
+
+        assert_eq!(results.len(), 20);
+        for (i, result) in results.into_iter().enumerate() {
+            let result = result.unwrap();
+            assert_eq!(result.output, format!("{}\n", i + 1).into_bytes());
+            assert_eq!(result.stats.result, RunResult::Finished);
+            assert_eq!(result.stats.instructions_executed, 4);
+        }
+        assert_eq!(machine.stack, vec![10]);
+        assert_eq!(machine.pc, 0);
+    }
+
+    #[test]
+    fn test_run_batch_errors() {
+        let machine = Machine::new(assemble("IN\nCOPYRANGE 1 1").unwrap());
+        let results = machine.run_batch(vec![b"1\n".to_vec()]);
+
+        assert!(matches!(results[..], [Err(VmError::StackUnderflow { .. })]));
+        assert!(machine.run_batch(Vec::new()).is_empty());
+
+        let mut machine = Machine::new(Vec::new());
+        machine.file_table.push(Some(
+            std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap(),
+        ));
+        assert!(matches!(
+            machine.run_batch(vec![Vec::new()])[..],
+            [Err(VmError::ForkWithOpenFiles)]
+        ));
+    }
+}
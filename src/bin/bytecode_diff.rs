@@ -0,0 +1,162 @@
+use std::fs::File;
+
+use bytecode_vm::{read_bytecode, Instruction};
+
+// Unchanged instructions shown around each change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    // Instruction a of the first file equals instruction b of the second
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+// Shortest edit script turning a into b, by Myers' algorithm
+fn diff<T, F: Fn(&T, &T) -> bool>(a: &[T], b: &[T], eq: F) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let index = |k: isize| (k + max) as usize;
+    // v[k] is the furthest x reached on diagonal k = x - y
+    let mut v = vec![0isize; 2 * max as usize + 2];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                v[index(k + 1)]
+            } else {
+                v[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && eq(&a[x as usize], &b[y as usize]) {
+                x += 1;
+                y += 1;
+            }
+            v[index(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[index(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(prev_y as usize));
+            } else {
+                edits.push(Edit::Delete(prev_x as usize));
+            }
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits.reverse();
+    edits
+}
+
+// The instruction with its jump target cleared, so branches that only
+// moved because code was inserted or removed compare equal
+fn without_target(instruction: &Instruction) -> Instruction {
+    let mut instruction = instruction.clone();
+    if let Some(target) = instruction.jump_target_mut() {
+        *target = 0;
+    }
+    instruction
+}
+
+// Prints the edits as hunks of changes with their surrounding context
+fn print_hunks(edits: &[Edit], a: &[Instruction], b: &[Instruction]) {
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|&i| !matches!(edits[i], Edit::Equal(_, _)))
+        .collect();
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes closer than twice the context share a hunk
+        let start = changes[i].saturating_sub(CONTEXT);
+        let mut last = changes[i];
+        while i + 1 < changes.len() && changes[i + 1] - last <= 2 * CONTEXT {
+            i += 1;
+            last = changes[i];
+        }
+        let end = (last + CONTEXT + 1).min(edits.len());
+        i += 1;
+
+        let hunk = &edits[start..end];
+        let (a_start, b_start) = position(&edits[start..], a.len(), b.len());
+        let a_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Insert(_)))
+            .count();
+        let b_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Delete(_)))
+            .count();
+        println!("@@ -{},{} +{},{} @@", a_start, a_len, b_start, b_len);
+        for edit in hunk {
+            match *edit {
+                Edit::Equal(x, y) => println!("  {:>4} {:>4} {}", x, y, a[x]),
+                Edit::Delete(x) => println!("- {:>4}      {}", x, a[x]),
+                Edit::Insert(y) => println!("+      {:>4} {}", y, b[y]),
+            }
+        }
+    }
+}
+
+// Indices in both files of the first of the edits
+fn position(edits: &[Edit], a_len: usize, b_len: usize) -> (usize, usize) {
+    let next_a = edits.iter().find_map(|e| match *e {
+        Edit::Equal(x, _) | Edit::Delete(x) => Some(x),
+        Edit::Insert(_) => None,
+    });
+    let next_b = edits.iter().find_map(|e| match *e {
+        Edit::Equal(_, y) | Edit::Insert(y) => Some(y),
+        Edit::Delete(_) => None,
+    });
+    (next_a.unwrap_or(a_len), next_b.unwrap_or(b_len))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let (ignore_targets, first, second) = match args.as_slice() {
+        [_, first, second] => (false, first, second),
+        [_, flag, first, second] if flag == "--ignore-targets" => (true, first, second),
+        _ => {
+            eprintln!("Usage: {} [--ignore-targets] <first> <second>", args[0]);
+            std::process::exit(2);
+        }
+    };
+
+    let a = read_bytecode(&mut File::open(first)?)?;
+    let b = read_bytecode(&mut File::open(second)?)?;
+    let edits = if ignore_targets {
+        diff(&a, &b, |x, y| without_target(x) == without_target(y))
+    } else {
+        diff(&a, &b, |x, y| x == y)
+    };
+
+    if edits.iter().all(|e| matches!(e, Edit::Equal(_, _))) {
+        return Ok(());
+    }
+    println!("--- {}", first);
+    println!("+++ {}", second);
+    print_hunks(&edits, &a, &b);
+    // Like diff, exit with 1 when the files differ
+    std::process::exit(1);
+}
@@ -0,0 +1,41 @@
+use std::fs;
+
+use bytecode_vm::{read_bytecode_with_debug, Encoding};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let (verbose, filename) = match args.as_slice() {
+        [_, filename] => (false, filename),
+        [_, flag, filename] if flag == "-v" => (true, filename),
+        _ => {
+            eprintln!("Usage: {} [-v] <filename>", args[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let bytes = fs::read(filename)?;
+    let encoding = Encoding::detect(&bytes)?;
+    let (code, debug_info) = read_bytecode_with_debug(&mut &bytes[..])?;
+
+    if let Some(source_file) = debug_info.as_ref().and_then(|d| d.source_file.as_ref()) {
+        println!("; source: {}", source_file);
+    }
+
+    // Offsets are relative to the start of the code, after any header
+    let mut offset = 0;
+    for (pc, instruction) in code.iter().enumerate() {
+        let encoded = instruction.to_bytes(encoding);
+        print!("{:>3} [{:#04x}] {}", pc, offset, instruction);
+        if let Some(line) = debug_info.as_ref().and_then(|d| d.line(pc as u64)) {
+            print!("  ; line {}", line);
+        }
+        println!();
+        if verbose {
+            let hex: Vec<String> = encoded.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("          {}", hex.join(" "));
+        }
+        offset += encoded.len();
+    }
+
+    Ok(())
+}
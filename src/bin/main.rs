@@ -1,27 +1,48 @@
 use std::{
     fs::File,
-    io::{stdin, stdout},
+    io::{stdin, stdout, Write},
 };
 
-use bytecode_vm::{deserialize_code, Machine};
+use bytecode_vm::{read_bytecode_with_debug, Instruction, Machine, RunResult};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <filename>", args[0]);
+    if args.len() < 2 {
+        eprintln!("Usage: {} <filename> [args...]", args[0]);
         std::process::exit(1);
     }
     let filename = &args[1];
 
-    let mut file = File::open(filename)?;
+    let (code, debug_info) = if filename.starts_with("http://") || filename.starts_with("https://")
+    {
+        (load_from_url(filename)?, None)
+    } else {
+        read_bytecode_with_debug(&mut File::open(filename)?)?
+    };
+
+    let mut vm = Machine::new(code);
+    // The program sees only the arguments after its own filename
+    vm.set_args(args[2..].to_vec());
 
-    let mut vm = Machine {
-        code: deserialize_code(&mut file)?,
-        stack: vec![],
-        pc: 0,
+    let result = match debug_info {
+        Some(debug_info) => vm.run_with_debug(&mut stdin(), &mut stdout(), &debug_info)?,
+        None => vm.run(&mut stdin(), &mut stdout())?,
     };
 
-    vm.run(&mut stdin(), &mut stdout())?;
+    if let RunResult::ProcessExit(code) = result {
+        stdout().flush()?;
+        std::process::exit(code);
+    }
 
     Ok(())
 }
+
+#[cfg(feature = "http_loader")]
+fn load_from_url(url: &str) -> Result<Vec<Instruction>, Box<dyn std::error::Error>> {
+    Ok(bytecode_vm::load_bytecode_from_url(url)?)
+}
+
+#[cfg(not(feature = "http_loader"))]
+fn load_from_url(_url: &str) -> Result<Vec<Instruction>, Box<dyn std::error::Error>> {
+    Err("loading from a URL needs the http_loader feature".into())
+}
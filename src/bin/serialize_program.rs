@@ -1,24 +1,36 @@
 use std::fs::File;
 
-pub use bytecode_vm::{deserialize_code, serialize_code, Instruction, Machine};
+pub use bytecode_vm::{write_bytecode, Encoding, Endianness, Instruction, Machine};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <filename>", args[0]);
-        std::process::exit(1);
-    }
-    let filename = &args[1];
+    let (canonical, filename) = match args.as_slice() {
+        [_, filename] => (false, filename),
+        [_, flag, filename] if flag == "--canonical" => (true, filename),
+        _ => {
+            eprintln!("Usage: {} [--canonical] <filename>", args[0]);
+            std::process::exit(1);
+        }
+    };
 
     let mut file = File::create(filename)?;
 
+    // Echoes the input until InByte pushes the end-of-input sentinel
     let code: Vec<Instruction> = vec![
         Instruction::InByte(),
-        Instruction::OutByte(0),
+        Instruction::Push(u64::MAX),
+        Instruction::Eq(0, 1, 5),
+        Instruction::OutByte(1),
         Instruction::Jmp(0),
     ];
 
-    serialize_code(&code, &mut file)?;
+    // Canonical bytecode is always little-endian, otherwise use the host byte order
+    let endianness = if canonical {
+        Endianness::Little
+    } else {
+        Endianness::native()
+    };
+    write_bytecode(&code, Encoding::Fixed(endianness), &mut file)?;
 
     Ok(())
 }
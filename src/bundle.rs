@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    deserialize_string,
+    encoding::{read_operand, write_operand},
+    serialize_string, Encoding, Instruction,
+};
+
+// Several bytecode modules linked into one program. The code starts with a
+// jump to the entry module, followed by every module in the order given.
+// Each module ends with a jump to the end of the program, so running off
+// the end of one module halts instead of falling into the next
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Bundle {
+    pub code: Vec<Instruction>,
+    // Name and start pc of every module, in the order they were given
+    pub symbols: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundleError {
+    // No module has the name given as the entry point
+    UnknownEntry(String),
+    // Two modules have the same name
+    DuplicateModule(String),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::UnknownEntry(name) => write!(f, "no module named {}", name),
+            BundleError::DuplicateModule(name) => write!(f, "module {} given twice", name),
+        }
+    }
+}
+
+impl Error for BundleError {}
+
+impl Bundle {
+    // Start pc of the module with the given name
+    pub fn module_start(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|(module, _)| module == name)
+            .map(|&(_, start)| start)
+    }
+}
+
+// Links the modules into one program starting at the first instruction of
+// entry. Jump targets and lookup tables are moved along with their module,
+// and a jump to the end of a module becomes a jump to the end of the
+// program. MultiRet jumps to a pc taken from the stack, which cannot be
+// adjusted, so modules using it must compute their return addresses from
+// the symbol table
+pub fn bundle_bytecode_files(
+    files: &[(&str, Vec<Instruction>)],
+    entry: &str,
+) -> Result<Bundle, BundleError> {
+    let mut starts = HashMap::new();
+    let mut symbols = Vec::with_capacity(files.len());
+    // The jump to the entry module comes first
+    let mut start = 1;
+    for (name, code) in files {
+        if starts.insert(*name, start).is_some() {
+            return Err(BundleError::DuplicateModule(name.to_string()));
+        }
+        symbols.push((name.to_string(), start));
+        start += code.len() as u64 + 1;
+    }
+    let end = start;
+    let entry_start = *starts
+        .get(entry)
+        .ok_or_else(|| BundleError::UnknownEntry(entry.to_string()))?;
+
+    let mut code = Vec::with_capacity(end as usize);
+    code.push(Instruction::Jmp(entry_start));
+    for ((_, module), (_, start)) in files.iter().zip(&symbols) {
+        let len = module.len() as u64;
+        for instruction in module {
+            let mut instruction = instruction.clone();
+            if let Some(target) = instruction.jump_target_mut() {
+                *target = if *target >= len { end } else { *target + start };
+            }
+            if let Instruction::LookupTable(_, table_start, _, _) = &mut instruction {
+                *table_start += start;
+            }
+            code.push(instruction);
+        }
+        code.push(Instruction::Jmp(end));
+    }
+    Ok(Bundle { code, symbols })
+}
+
+pub(crate) fn write_symbol_table<W: Write>(
+    writer: &mut W,
+    symbols: &[(String, u64)],
+    encoding: Encoding,
+) -> io::Result<()> {
+    write_operand(writer, symbols.len() as u64, encoding)?;
+    for (name, start) in symbols {
+        serialize_string(writer, name, encoding)?;
+        write_operand(writer, *start, encoding)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_symbol_table<R: Read>(
+    reader: &mut R,
+    encoding: Encoding,
+) -> io::Result<Vec<(String, u64)>> {
+    let len = read_operand(reader, encoding)?;
+    let mut symbols = Vec::new();
+    for _ in 0..len {
+        let name = deserialize_string(reader, encoding)?;
+        symbols.push((name, read_operand(reader, encoding)?));
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, read_bundle, read_bytecode, write_bundle, Machine};
+
+    fn modules() -> Vec<(&'static str, Vec<Instruction>)> {
+        vec![
+            // Prints 1 to 3
+            (
+                "count",
+                assemble("PUSH 1\nOUT 0\nINC 0\nPUSH 4\nEQ 0 1 7\nDROPRANGE 0 1\nJMP 1").unwrap(),
+            ),
+            (
+                "greet",
+                assemble("OUTSTR \"hi\"\nJMP 3\nOUTSTR \"skipped\"").unwrap(),
+            ),
+        ]
+    }
+
+    fn run(code: Vec<Instruction>) -> String {
+        let mut machine = Machine::new(code);
+        machine.run_with_strings("").unwrap().1
+    }
+
+    #[test]
+    fn test_bundle_entry_points() {
+        let bundle = bundle_bytecode_files(&modules(), "greet").unwrap();
+        assert_eq!(
+            bundle.symbols,
+            vec![("count".to_string(), 1), ("greet".to_string(), 9)]
+        );
+        assert_eq!(bundle.code.len(), 13);
+        assert_eq!(bundle.code[0], Instruction::Jmp(9));
+        // Jumps to the end of a module go to the end of the program
+        assert_eq!(bundle.code[10], Instruction::Jmp(13));
+        assert_eq!(bundle.code[12], Instruction::Jmp(13));
+        assert_eq!(run(bundle.code), "hi\n");
+
+        let bundle = bundle_bytecode_files(&modules(), "count").unwrap();
+        assert_eq!(bundle.module_start("greet"), Some(9));
+        assert_eq!(run(bundle.code), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn test_bundle_errors() {
+        assert_eq!(
+            bundle_bytecode_files(&modules(), "main"),
+            Err(BundleError::UnknownEntry("main".to_string()))
+        );
+        let mut modules = modules();
+        modules.push(("count", vec![]));
+        assert_eq!(
+            bundle_bytecode_files(&modules, "count"),
+            Err(BundleError::DuplicateModule("count".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_write_read_bundle() {
+        let bundle = bundle_bytecode_files(&modules(), "count").unwrap();
+        for encoding in [Encoding::Varint, Encoding::Compact] {
+            let mut buf = Vec::new();
+            write_bundle(&bundle, encoding, &mut buf).unwrap();
+            assert_eq!(read_bundle(&mut &buf[..]).unwrap(), bundle);
+            assert_eq!(read_bytecode(&mut &buf[..]).unwrap(), bundle.code);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::{self, BufReader, Read, Write};
+
+use crate::{Machine, VmError};
+
+// Output written by the instruction at `pc`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputChunk {
+    pub pc: u64,
+    pub bytes: Vec<u8>,
+}
+
+// A writer that buffers output and attributes it to the instruction
+// that produced it
+#[derive(Debug, Default)]
+pub struct OutputCapture {
+    chunks: Vec<OutputChunk>,
+    pending: Vec<u8>,
+}
+
+impl OutputCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Attributes everything written since the previous call to `pc`
+    pub fn end_instruction(&mut self, pc: u64) {
+        if !self.pending.is_empty() {
+            self.chunks.push(OutputChunk {
+                pc,
+                bytes: std::mem::take(&mut self.pending),
+            });
+        }
+    }
+
+    pub fn into_chunks(self) -> Vec<OutputChunk> {
+        self.chunks
+    }
+}
+
+impl Write for OutputCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Machine {
+    pub fn run_captured<R: Read>(&mut self, input: &mut R) -> Result<Vec<OutputChunk>, VmError> {
+        self.validate()?;
+        let mut input = BufReader::new(input);
+        let mut capture = OutputCapture::new();
+        loop {
+            let pc = self.pc;
+            let running = self.step(&mut input, &mut capture)?;
+            capture.end_instruction(pc);
+            if !running {
+                break;
+            }
+        }
+        self.finish();
+        Ok(capture.into_chunks())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction;
+
+    #[test]
+    fn test_run_captured() {
+        let mut machine = Machine {
+            code: vec![
+                Instruction::OutStr("a".to_string()),
+                Instruction::In(),
+                Instruction::Out(0),
+                Instruction::OutByte(0),
+            ],
+            stack: vec![],
+            pc: 0,
+            ..Default::default()
+        };
+        let chunks = machine.run_captured(&mut &b"66\n"[..]).unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                OutputChunk {
+                    pc: 0,
+                    bytes: b"a\n".to_vec()
+                },
+                OutputChunk {
+                    pc: 2,
+                    bytes: b"66\n".to_vec()
+                },
+                OutputChunk {
+                    pc: 3,
+                    bytes: b"B".to_vec()
+                },
+            ]
+        );
+    }
+}
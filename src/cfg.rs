@@ -0,0 +1,90 @@
+use std::collections::BTreeSet;
+
+use crate::Instruction;
+
+// A run of instructions that is only entered at start and only left
+// after end, both inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl BasicBlock {
+    // Index of the last instruction of the block that starts at the first
+    // of the instructions: the first terminator, or the last instruction
+    // if none of them is one. Jumps into the middle are not considered
+    pub fn ends_at(instructions: &[Instruction]) -> usize {
+        instructions
+            .iter()
+            .position(Instruction::is_terminator)
+            .unwrap_or(instructions.len().saturating_sub(1))
+    }
+}
+
+// Splits the program into basic blocks in order. A block starts at the
+// first instruction, after every terminator and at every jump target
+pub fn basic_blocks(instructions: &[Instruction]) -> Vec<BasicBlock> {
+    let mut starts: BTreeSet<usize> = instructions
+        .iter()
+        .filter_map(Instruction::jump_target)
+        .map(|target| target as usize)
+        .chain(
+            instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, instruction)| instruction.is_terminator())
+                .map(|(pc, _)| pc + 1),
+        )
+        .filter(|&start| start < instructions.len())
+        .collect();
+    if !instructions.is_empty() {
+        starts.insert(0);
+    }
+
+    let mut starts = starts.into_iter().peekable();
+    let mut blocks = Vec::new();
+    while let Some(start) = starts.next() {
+        let limit = starts.peek().copied().unwrap_or(instructions.len());
+        blocks.push(BasicBlock {
+            start,
+            end: start + BasicBlock::ends_at(&instructions[start..limit]),
+        });
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn test_ends_at() {
+        let code = assemble("PUSH 1\nOUT 0\nEQ 0 0 0\nPUSH 2").unwrap();
+        assert_eq!(BasicBlock::ends_at(&code), 2);
+        assert_eq!(BasicBlock::ends_at(&code[3..]), 0);
+        assert_eq!(BasicBlock::ends_at(&[]), 0);
+    }
+
+    #[test]
+    fn test_basic_blocks() {
+        let code = assemble(
+            "PUSH 3\n\
+             DEC 0\nOUT 0\nPUSH 0\nEQ 0 1 6\nJMP 1\n\
+             EXITCODE 0\nOUTSTR \"unreachable\"",
+        )
+        .unwrap();
+        assert_eq!(
+            basic_blocks(&code),
+            vec![
+                BasicBlock { start: 0, end: 0 },
+                BasicBlock { start: 1, end: 4 },
+                BasicBlock { start: 5, end: 5 },
+                BasicBlock { start: 6, end: 6 },
+                BasicBlock { start: 7, end: 7 },
+            ]
+        );
+        assert_eq!(basic_blocks(&[]), vec![]);
+    }
+}
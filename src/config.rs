@@ -0,0 +1,120 @@
+use crate::HashAlgorithm;
+
+// Capabilities granted to the program running on a machine. The default
+// policy allows everything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    // Reading the input and writing the output
+    pub allow_io: bool,
+    pub allow_file_ops: bool,
+    pub allow_sleep: bool,
+    pub allow_rand: bool,
+    pub allow_system_calls: bool,
+    // ClearStack and ClearGlobals
+    pub allow_state_reset: bool,
+}
+
+impl SandboxPolicy {
+    // A policy that denies every capability
+    pub fn deny_all() -> Self {
+        SandboxPolicy {
+            allow_io: false,
+            allow_file_ops: false,
+            allow_sleep: false,
+            allow_rand: false,
+            allow_system_calls: false,
+            allow_state_reset: false,
+        }
+    }
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            allow_io: true,
+            allow_file_ops: true,
+            allow_sleep: true,
+            allow_rand: true,
+            allow_system_calls: true,
+            allow_state_reset: true,
+        }
+    }
+}
+
+// Line ending written by the instructions that end their output with a newline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NewlineMode {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl NewlineMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NewlineMode::Lf => "\n",
+            NewlineMode::Crlf => "\r\n",
+        }
+    }
+}
+
+// Everything that controls how a machine runs a program. The default
+// config allows everything and sets no limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmConfig {
+    pub sandbox_policy: SandboxPolicy,
+    // Resource quotas, accumulated across runs of the same machine.
+    // None means unlimited
+    pub max_stack_depth: Option<usize>,
+    // See ResourceUsage::heap_bytes
+    pub max_heap_bytes: Option<usize>,
+    pub max_output_bytes: Option<u64>,
+    pub max_input_bytes: Option<u64>,
+    // Number of instructions the machine may execute
+    pub step_limit: Option<u64>,
+    // Value pushed by InByte at the end of the input
+    pub eof_sentinel: u64,
+    // Hash function used by Hash64
+    pub hash_algorithm: HashAlgorithm,
+    // Rejects instructions whose result depends on the environment, so a
+    // run that succeeds is reproducible from the program and its input
+    pub deterministic_mode: bool,
+    pub newline_mode: NewlineMode,
+    // Suspends the machine before every In, InByte and Out so the host
+    // can inspect it, see Machine::resume_io
+    pub io_break_mode: bool,
+    // Number of global variables Machine::with_config creates, all 0
+    pub initial_globals: usize,
+    // Records the time spent executing each kind of instruction, see
+    // Machine::take_timing
+    pub time_instructions: bool,
+    // Records a snapshot before every instruction so the host can undo
+    // them with Machine::step_back, keeping at most max_history_depth
+    pub reverse_mode: bool,
+    pub max_history_depth: usize,
+    // Tracks a TypeTag for every stack value, see Machine::stack_tags
+    pub type_tagging: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            sandbox_policy: SandboxPolicy::default(),
+            max_stack_depth: None,
+            max_heap_bytes: None,
+            max_output_bytes: None,
+            max_input_bytes: None,
+            step_limit: None,
+            eof_sentinel: u64::MAX,
+            hash_algorithm: HashAlgorithm::default(),
+            deterministic_mode: false,
+            newline_mode: NewlineMode::default(),
+            io_break_mode: false,
+            initial_globals: 0,
+            time_instructions: false,
+            reverse_mode: false,
+            max_history_depth: 1024,
+            type_tagging: false,
+        }
+    }
+}
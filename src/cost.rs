@@ -0,0 +1,66 @@
+use crate::{Instruction, Machine};
+
+// Abstract compute units charged for executing an instruction
+pub type InstructionCost = u64;
+
+// Stack shuffling is cheapest, string and hash work costs more, calls into
+// the host and anything touching the input, output or files costs most
+pub fn instruction_cost(instruction: &Instruction) -> InstructionCost {
+    use Instruction::*;
+    match instruction {
+        Push(_) | Copy(_) | Dec(_) | Inc(_) | Jmp(_) | TwoDup() | TwoDrop() | TwoSwap() => 1,
+        SatInc(_) | SatDec(_) | WrapInc(_) | WrapDec(_) => 1,
+        MeasureStart(_) | MeasureEnd(_) => 1,
+        CopyRange(_, _) | DropRange(_, _) | SatAdd(_, _) | SatSub(_, _) => 2,
+        WrapAdd(_, _) | WrapSub(_, _) | Push128(_) => 2,
+        Add128() | Compare128(_, _, _) => 3,
+        Mul128() | ClearStack() | ClearGlobals() => 5,
+        Add(_, _) | Gt(_, _, _) | Eq(_, _, _) | CondMove(_, _, _) | Select(_, _, _) => 2,
+        AbsJmpIfNeg(_, _) | NotEq(_, _, _) | GtOrEq(_, _, _) | TypeAssert(_, _) => 2,
+        FAdd(_, _) | FSub(_, _) | FMul(_, _) | FDiv(_, _) => 3,
+        FGt(_, _, _) | FLt(_, _, _) | FEq(_, _, _) => 3,
+        Gcd(_, _) | Lcm(_, _) | ModInverse(_, _) | LookupTable(_, _, _, _) | Checksum(_, _) => 5,
+        CmpPush(_, _) | MultiRet(_) | ExitCode(_) | GetArg(_) => 2,
+        StoreGlobal(_, _) | StoreGlobalPop(_) => 2,
+        StrLen(_) | StrLoad(_, _) | StrStore(_, _, _) | StrAlloc(_) | StrFree(_) => 5,
+        StrCat(_, _) | StrCmp(_, _, _) | StrSlice(_, _, _) | StrToInt(_) | IntToStr(_) => 10,
+        Hash64(_, _) => 10,
+        Crc32(_, _) => 10,
+        In() | InByte() | InPeek() | Out(_) | OutByte(_) | OutStr(_) | OutStrNoNl(_) => 20,
+        OutStrHandle(_) | OutStrHandleLn(_) | OutCSV(_) | InEnv() => 20,
+        OutFormatted(_, _) | InBytes(_, _) | OutBytes(_, _) => 30,
+        CallNative(_) => 50,
+        FdOpen(_, _) | FdRead(_, _) | FdWrite(_, _) | FdClose(_) => 100,
+        Fused(instructions) => program_estimated_cost(instructions),
+    }
+}
+
+// The cost of executing every instruction exactly once, ignoring branches
+pub fn program_estimated_cost(instructions: &[Instruction]) -> u64 {
+    instructions.iter().map(instruction_cost).sum()
+}
+
+impl Machine {
+    // The cost of every instruction executed so far, across runs
+    pub fn consumed_cost(&self) -> u64 {
+        self.consumed_cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn test_estimated_and_consumed_cost() {
+        let code = assemble("PUSH 0\nPUSH 3\nDEC 0\nEQ 0 1 5\nJMP 2\nCALLNATIVE 0").unwrap();
+        assert_eq!(program_estimated_cost(&code), 1 + 1 + 1 + 2 + 1 + 50);
+
+        let mut machine = Machine::new(code);
+        machine.register_native(0, Box::new(|_: &mut Machine| Ok(())));
+        machine.run(&mut &b""[..], &mut Vec::new()).unwrap();
+        // Both PUSHes, DEC, EQ and JMP twice, the last DEC and EQ, CALLNATIVE
+        assert_eq!(machine.consumed_cost(), 2 + 2 * 4 + 3 + 50);
+    }
+}
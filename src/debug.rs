@@ -0,0 +1,202 @@
+use std::{
+    borrow::Cow,
+    io::{self, BufReader, Read, Write},
+};
+
+use crate::{
+    deserialize_string,
+    encoding::{read_operand, write_operand},
+    serialize_string, unfused_indices, Encoding, Instruction, Machine, RunResult, VmError,
+};
+
+// Maps instructions back to the source they were compiled from
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    // Source line of each instruction, indexed by pc
+    pub line_map: Vec<Option<u32>>,
+    pub source_file: Option<String>,
+}
+
+impl DebugInfo {
+    pub fn line(&self, pc: u64) -> Option<u32> {
+        self.line_map.get(pc as usize).copied().flatten()
+    }
+
+    // Attaches the source location of the instruction at pc to the error
+    pub fn locate(&self, pc: u64, error: VmError) -> VmError {
+        match self.line(pc) {
+            Some(line) => VmError::Located {
+                pc,
+                line,
+                column: None,
+                source_file: self.source_file.clone(),
+                error: Box::new(error),
+            },
+            None => error,
+        }
+    }
+
+    // The debug info for the code once its Fused instructions are replaced
+    // by their components, which all get the line of the Fused instruction
+    pub(crate) fn unfused(&self, instructions: &[Instruction]) -> Cow<'_, DebugInfo> {
+        if !instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Fused(_)))
+        {
+            return Cow::Borrowed(self);
+        }
+        let new_index = unfused_indices(instructions);
+        let line_map = self
+            .line_map
+            .iter()
+            .enumerate()
+            .flat_map(|(pc, &line)| {
+                let count = match new_index.get(pc..pc + 2) {
+                    Some(&[start, end]) => end - start,
+                    _ => 1,
+                };
+                std::iter::repeat_n(line, count as usize)
+            })
+            .collect();
+        Cow::Owned(DebugInfo {
+            line_map,
+            source_file: self.source_file.clone(),
+        })
+    }
+
+    pub(crate) fn serialize<W: Write>(&self, writer: &mut W, encoding: Encoding) -> io::Result<()> {
+        write_operand(writer, self.line_map.len() as u64, encoding)?;
+        for line in &self.line_map {
+            // 0 marks an instruction without a line
+            write_operand(writer, line.map_or(0, |l| l as u64 + 1), encoding)?;
+        }
+        match &self.source_file {
+            Some(file) => {
+                writer.write_all(&[1])?;
+                serialize_string(writer, file, encoding)
+            }
+            None => writer.write_all(&[0]),
+        }
+    }
+
+    pub(crate) fn deserialize<R: Read>(reader: &mut R, encoding: Encoding) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid debug info");
+
+        let len = read_operand(reader, encoding)?;
+        let line_map = (0..len)
+            .map(|_| match read_operand(reader, encoding)? {
+                0 => Ok(None),
+                line => u32::try_from(line - 1).map(Some).map_err(|_| invalid()),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut has_file = [0];
+        reader.read_exact(&mut has_file)?;
+        let source_file = match has_file[0] {
+            0 => None,
+            1 => Some(deserialize_string(reader, encoding)?),
+            _ => return Err(invalid()),
+        };
+
+        Ok(DebugInfo {
+            line_map,
+            source_file,
+        })
+    }
+}
+
+impl Machine {
+    // Like run, but errors carry the source line of the failing instruction
+    pub fn run_with_debug<W: Write, R: Read>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        debug_info: &DebugInfo,
+    ) -> Result<RunResult, VmError> {
+        self.validate()?;
+        let mut input = BufReader::new(input);
+        loop {
+            match self.step(&mut input, output) {
+                Ok(true) => (),
+                Ok(false) => return Ok(self.finish()),
+                Err(e) => return Err(debug_info.locate(self.pc, e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        read_bytecode, read_bytecode_with_debug, write_bytecode_with_debug, Endianness, Instruction,
+    };
+
+    fn debug_info() -> DebugInfo {
+        DebugInfo {
+            line_map: vec![Some(1), None, Some(70000)],
+            source_file: Some("main.src".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_debug_info_round_trip() {
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::OutStr("x".to_string()),
+            Instruction::Out(0),
+        ];
+        for encoding in [
+            Encoding::Fixed(Endianness::Little),
+            Encoding::Varint,
+            Encoding::Compact,
+        ] {
+            let mut buf = Vec::new();
+            write_bytecode_with_debug(&code, &debug_info(), encoding, &mut buf).unwrap();
+
+            let (read_code, read_debug) = read_bytecode_with_debug(&mut &buf[..]).unwrap();
+            assert_eq!(read_code, code);
+            assert_eq!(read_debug, Some(debug_info()));
+
+            // Readers that don't care about debug info skip it
+            assert_eq!(read_bytecode(&mut &buf[..]).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn test_run_with_debug_locates_error() {
+        let mut machine = Machine {
+            code: vec![
+                Instruction::Push(1),
+                Instruction::Push(2),
+                Instruction::In(),
+            ],
+            stack: vec![],
+            pc: 0,
+            ..Default::default()
+        };
+        let mut input = Failing;
+        let error = machine
+            .run_with_debug(&mut input, &mut Vec::new(), &debug_info())
+            .unwrap_err();
+
+        match error {
+            VmError::Located {
+                pc, line, error, ..
+            } => {
+                assert_eq!(pc, 2);
+                assert_eq!(line, 70000);
+                assert!(matches!(*error, VmError::Io(_)));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+
+use crate::Instruction;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackDepthError {
+    // The instruction at pc may run with only minimum_depth values on the
+    // stack while it needs required
+    PossibleUnderflow {
+        pc: u64,
+        minimum_depth: usize,
+        required: usize,
+    },
+}
+
+impl fmt::Display for StackDepthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackDepthError::PossibleUnderflow {
+                pc,
+                minimum_depth,
+                required,
+            } => write!(
+                f,
+                "instruction {} needs {} values on the stack but may run with {}",
+                pc, required, minimum_depth
+            ),
+        }
+    }
+}
+
+// Finds the instructions that may run with fewer values on the stack than
+// they address, starting the program with initial_depth values. The
+// smallest depth each instruction can see is propagated along every jump,
+// so the check is conservative: it may report an underflow that never
+// happens, but never misses one it can see. After an instruction whose
+// stack effect is unknown the depth is assumed to be 0, and MultiRet may
+// return to any instruction. Values that instructions such as OutCSV and
+// Hash64 read below a count taken from the stack are not checked
+pub fn check_stack_depths(
+    instructions: &[Instruction],
+    initial_depth: usize,
+) -> Vec<StackDepthError> {
+    let len = instructions.len();
+    // Smallest depth seen at each pc, None if it is unreachable
+    let mut depths: Vec<Option<usize>> = vec![None; len];
+    let mut pending = vec![(0, initial_depth)];
+    while let Some((pc, depth)) = pending.pop() {
+        let Some(slot) = depths.get_mut(pc) else {
+            continue;
+        };
+        if slot.is_some_and(|known| known <= depth) {
+            continue;
+        }
+        *slot = Some(depth);
+
+        let instruction = &instructions[pc];
+        let next = depth_after(instruction, depth);
+        for successor in successors(instructions, pc) {
+            pending.push((successor, next));
+        }
+        if matches!(instruction, Instruction::MultiRet(_)) {
+            pending.extend((0..len).map(|pc| (pc, next)));
+        }
+    }
+
+    let mut errors = Vec::new();
+    for (pc, depth) in depths.into_iter().enumerate() {
+        let Some(depth) = depth else {
+            continue;
+        };
+        let required = required_depth(&instructions[pc]);
+        if depth < required {
+            errors.push(StackDepthError::PossibleUnderflow {
+                pc: pc as u64,
+                minimum_depth: depth,
+                required,
+            });
+        }
+    }
+    errors
+}
+
+// Smallest depth after running the instruction with depth values
+fn depth_after(instruction: &Instruction, depth: usize) -> usize {
+    match instruction.stack_effect() {
+        (usize::MAX, usize::MAX) => 0,
+        (pops, pushes) => depth.saturating_sub(pops).saturating_add(pushes),
+    }
+}
+
+// Instructions that may run after the one at pc
+fn successors(instructions: &[Instruction], pc: usize) -> Vec<usize> {
+    let instruction = &instructions[pc];
+    let mut successors: Vec<usize> = instruction
+        .jump_target()
+        .map(|target| target as usize)
+        .into_iter()
+        .collect();
+    match instruction {
+        Instruction::Jmp(_) | Instruction::MultiRet(_) | Instruction::ExitCode(_) => {}
+        Instruction::LookupTable(_, start, size, _) => {
+            // The entries are Push(key), Jmp(target) pairs. Entries past
+            // the end of the program are left to validation
+            for entry in 0..*size {
+                let jmp = entry
+                    .checked_mul(2)
+                    .and_then(|offset| start.checked_add(offset))
+                    .and_then(|pc| pc.checked_add(1))
+                    .filter(|&pc| pc < instructions.len() as u64);
+                let Some(jmp) = jmp else {
+                    break;
+                };
+                if let Instruction::Jmp(target) = instructions[jmp as usize] {
+                    successors.push(target as usize);
+                }
+            }
+        }
+        _ => successors.push(pc + 1),
+    }
+    successors
+}
+
+// Number of values the instruction needs on the stack. Positions too
+// large to ever be on the stack need usize::MAX
+fn required_depth(instruction: &Instruction) -> usize {
+    use Instruction::*;
+    let depth = |values: u64| usize::try_from(values).unwrap_or(usize::MAX);
+    let position = |pos: &u64| depth(pos.saturating_add(1));
+    let range = |pos: &u64, count: &u64| {
+        if *count == 0 {
+            0
+        } else {
+            depth(pos.saturating_add(*count))
+        }
+    };
+    match instruction {
+        Out(a)
+        | Copy(a)
+        | Dec(a)
+        | Inc(a)
+        | OutByte(a)
+        | GetArg(a)
+        | ExitCode(a)
+        | StrAlloc(a)
+        | StrLen(a)
+        | StrFree(a)
+        | OutStrHandle(a)
+        | OutStrHandleLn(a)
+        | StrToInt(a)
+        | IntToStr(a)
+        | MultiRet(a)
+        | OutCSV(a)
+        | SatInc(a)
+        | SatDec(a)
+        | WrapInc(a)
+        | WrapDec(a)
+        | FdClose(a)
+        | MeasureStart(a)
+        | MeasureEnd(a)
+        | StoreGlobal(_, a)
+        | TypeAssert(a, _)
+        | AbsJmpIfNeg(a, _)
+        | LookupTable(a, _, _, _) => position(a),
+        Add(a, b)
+        | StrLoad(a, b)
+        | StrCat(a, b)
+        | CmpPush(a, b)
+        | Hash64(a, b)
+        | FdOpen(a, b)
+        | FdRead(a, b)
+        | FdWrite(a, b)
+        | SatAdd(a, b)
+        | SatSub(a, b)
+        | WrapAdd(a, b)
+        | WrapSub(a, b)
+        | FAdd(a, b)
+        | FSub(a, b)
+        | FMul(a, b)
+        | FDiv(a, b)
+        | Gcd(a, b)
+        | Lcm(a, b)
+        | ModInverse(a, b)
+        | OutFormatted(a, b)
+        | InBytes(a, b)
+        | OutBytes(a, b)
+        | Gt(a, b, _)
+        | Eq(a, b, _)
+        | NotEq(a, b, _)
+        | GtOrEq(a, b, _)
+        | FGt(a, b, _)
+        | FLt(a, b, _)
+        | FEq(a, b, _)
+        | StrCmp(a, b, _) => position(a).max(position(b)),
+        Crc32(a, b) => position(a).max(position(b)),
+        StrStore(a, b, c) | StrSlice(a, b, c) | CondMove(a, b, c) | Select(a, b, c) => {
+            position(a).max(position(b)).max(position(c))
+        }
+        // Pairs take the slot above their low word too
+        Compare128(a, b, _) => position(a).max(position(b)).saturating_add(1),
+        CopyRange(pos, count) | DropRange(pos, count) | Checksum(pos, count) => range(pos, count),
+        TwoDup() | TwoDrop() => 2,
+        TwoSwap() | Add128() | Mul128() => 4,
+        InEnv() | StoreGlobalPop(_) => 1,
+        Fused(components) => {
+            // The depth needed before the first component so none underflows.
+            // After a component with an unknown effect the depth may be 0
+            let mut required: i128 = 0;
+            let mut change: Option<i128> = Some(0);
+            for component in components.iter() {
+                let needed = required_depth(component) as i128;
+                required = match change {
+                    Some(change) => required.max(needed - change),
+                    None if needed > 0 => return usize::MAX,
+                    None => required,
+                };
+                change = match component.stack_effect() {
+                    (usize::MAX, usize::MAX) => None,
+                    (pops, pushes) => change.map(|change| change + pushes as i128 - pops as i128),
+                };
+            }
+            usize::try_from(required).unwrap_or(usize::MAX)
+        }
+        Push(_) | In() | OutStr(_) | Jmp(_) | InByte() | OutStrNoNl(_) | CallNative(_)
+        | Push128(_) | ClearStack() | ClearGlobals() | InPeek() => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn test_straight_line() {
+        let code = assemble("PUSH 1\nPUSH 2\nADD 0 1\nOUT 0\nADD 0 1").unwrap();
+        assert_eq!(
+            check_stack_depths(&code, 0),
+            vec![StackDepthError::PossibleUnderflow {
+                pc: 4,
+                minimum_depth: 1,
+                required: 2
+            }]
+        );
+        assert_eq!(check_stack_depths(&code, 1), vec![]);
+    }
+
+    #[test]
+    fn test_branches_take_the_minimum() {
+        // The loop drops a value every iteration, so OUT 1 may underflow
+        // on the second pass even though the first one is fine
+        let code =
+            assemble("PUSH 1\nPUSH 2\nPUSH 3\nOUT 1\nDROPRANGE 0 1\nEQ 0 0 3\nOUT 0").unwrap();
+        assert_eq!(
+            check_stack_depths(&code, 0),
+            vec![
+                StackDepthError::PossibleUnderflow {
+                    pc: 3,
+                    minimum_depth: 0,
+                    required: 2
+                },
+                StackDepthError::PossibleUnderflow {
+                    pc: 4,
+                    minimum_depth: 0,
+                    required: 1
+                },
+                StackDepthError::PossibleUnderflow {
+                    pc: 5,
+                    minimum_depth: 0,
+                    required: 1
+                },
+                StackDepthError::PossibleUnderflow {
+                    pc: 6,
+                    minimum_depth: 0,
+                    required: 1
+                },
+            ]
+        );
+
+        // Code that is never reached is not checked
+        let code = assemble("PUSH 1\nJMP 3\nADD 0 1\nOUT 0").unwrap();
+        assert_eq!(check_stack_depths(&code, 0), vec![]);
+    }
+
+    #[test]
+    fn test_large_operands() {
+        for (code, pc) in [
+            (vec![Instruction::Copy(u64::MAX)], 0),
+            (
+                vec![Instruction::Push(1), Instruction::CopyRange(0, u64::MAX)],
+                1,
+            ),
+            (
+                vec![
+                    Instruction::Push(1),
+                    Instruction::Compare128(u64::MAX, 0, 0),
+                ],
+                1,
+            ),
+        ] {
+            assert!(
+                matches!(
+                    check_stack_depths(&code, 0)[..],
+                    [StackDepthError::PossibleUnderflow { pc: error_pc, required: usize::MAX, .. }] if error_pc == pc
+                ),
+                "{:?}",
+                code
+            );
+        }
+
+        // The depth after CopyRange saturates instead of overflowing
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::CopyRange(0, u64::MAX),
+            Instruction::CopyRange(0, u64::MAX),
+        ];
+        assert_eq!(
+            check_stack_depths(&code, 1),
+            vec![StackDepthError::PossibleUnderflow {
+                pc: 1,
+                minimum_depth: 2,
+                required: usize::MAX
+            }]
+        );
+
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::LookupTable(0, u64::MAX, 2, 0),
+        ];
+        assert_eq!(check_stack_depths(&code, 0), vec![]);
+    }
+
+    #[test]
+    fn test_unknown_effects() {
+        let code = assemble("PUSH 1\nPUSH 2\nCALLNATIVE 0\nOUT 0").unwrap();
+        assert_eq!(
+            check_stack_depths(&code, 0),
+            vec![StackDepthError::PossibleUnderflow {
+                pc: 3,
+                minimum_depth: 0,
+                required: 1
+            }]
+        );
+    }
+}
@@ -0,0 +1,869 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    bundle::{read_symbol_table, write_symbol_table},
+    deserialize_code_with, deserialize_string, serialize_code_with, serialize_string, unfuse,
+    unfused_indices, Bundle, DebugInfo, Instruction,
+};
+
+// Bytecode files with a header start with these bytes, followed by a version
+// byte, an endianness byte and a flags byte. Files without it are read as
+// little-endian version 1
+pub const MAGIC: [u8; 4] = *b"BCVM";
+
+// The code is followed by a debug section. Only valid together with
+// FLAG_INSTRUCTION_COUNT, which tells where the code ends
+pub(crate) const FLAG_DEBUG_INFO: u8 = 1;
+// The flags byte is followed by the instruction count as a u32 in the
+// endianness of the file
+pub(crate) const FLAG_INSTRUCTION_COUNT: u8 = 2;
+// The code is a MessagePack array instead of tagged instructions. Never
+// combined with other flags
+pub(crate) const FLAG_MSGPACK: u8 = 4;
+// The instruction count is followed by the symbol table of a bundle: the
+// number of modules as an operand, then the name and start pc of each
+pub(crate) const FLAG_SYMBOLS: u8 = 8;
+
+// Upper bound on the capacity reserved from an instruction count, so a
+// corrupt count cannot make us allocate unbounded memory up front
+const MAX_PREALLOCATED_INSTRUCTIONS: usize = 1 << 16;
+
+// Tag used in place of OutStr when the string is stored in the string table
+const OUT_STR_REF: u8 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    pub fn native() -> Self {
+        if cfg!(target_endian = "big") {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Endianness::Little => 0x01,
+            Endianness::Big => 0x02,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0x01 => Ok(Endianness::Little),
+            0x02 => Ok(Endianness::Big),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid endianness",
+            )),
+        }
+    }
+}
+
+// How instruction operands and string lengths are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    // Version 1: every operand takes 8 bytes in the given byte order
+    Fixed(Endianness),
+    // Version 2: operands are unsigned LEB128 varints
+    Varint,
+    // Version 3: each operand takes 0 (the value 0), 1, 2 or 8
+    // little-endian bytes, chosen by a 2-bit size code. Instructions without
+    // operands are just the tag. A single operand of a tag below 16 has its
+    // code in the tag byte, whose top two bits are then set. Otherwise the
+    // tag is followed by a byte holding the number of operands in its top
+    // two bits and the codes, first operand in the lowest bits. String
+    // lengths and the operands of instructions with four of them are varints
+    Compact,
+}
+
+impl Encoding {
+    pub fn version(self) -> u8 {
+        match self {
+            Encoding::Fixed(_) => 1,
+            Encoding::Varint => 2,
+            Encoding::Compact => 3,
+        }
+    }
+
+    fn endianness(self) -> Endianness {
+        match self {
+            Encoding::Fixed(endianness) => endianness,
+            // LEB128 and the compact operands have a fixed byte order
+            Encoding::Varint | Encoding::Compact => Endianness::Little,
+        }
+    }
+
+    // The encoding of a bytecode file, given its first bytes
+    pub fn detect(bytes: &[u8]) -> io::Result<Self> {
+        match bytes.strip_prefix(&MAGIC[..]) {
+            Some([version, endianness, ..]) => Encoding::from_header(*version, *endianness),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated bytecode header",
+            )),
+            // Headerless files are version 1
+            None => Ok(Encoding::Fixed(Endianness::Little)),
+        }
+    }
+
+    pub(crate) fn from_header(version: u8, endianness: u8) -> io::Result<Self> {
+        let endianness = Endianness::from_byte(endianness)?;
+        match version {
+            1 => Ok(Encoding::Fixed(endianness)),
+            2 => Ok(Encoding::Varint),
+            3 => Ok(Encoding::Compact),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported bytecode version",
+            )),
+        }
+    }
+}
+
+pub fn serialize_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+pub fn deserialize_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut buf = [0];
+        reader.read_exact(&mut buf)?;
+        let bits = (buf[0] & 0x7f) as u64;
+        if shift >= 64 || (shift == 63 && bits > 1) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "varint overflows u64",
+            ));
+        }
+        value |= bits << shift;
+        if buf[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub(crate) fn write_operand<W: Write>(
+    writer: &mut W,
+    value: u64,
+    encoding: Encoding,
+) -> io::Result<()> {
+    match encoding {
+        Encoding::Fixed(Endianness::Little) => writer.write_all(&value.to_le_bytes()),
+        Encoding::Fixed(Endianness::Big) => writer.write_all(&value.to_be_bytes()),
+        Encoding::Varint | Encoding::Compact => serialize_varint(writer, value),
+    }
+}
+
+pub(crate) fn read_operand<R: Read>(reader: &mut R, encoding: Encoding) -> io::Result<u64> {
+    match encoding {
+        Encoding::Fixed(endianness) => {
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf)?;
+            Ok(match endianness {
+                Endianness::Little => u64::from_le_bytes(buf),
+                Endianness::Big => u64::from_be_bytes(buf),
+            })
+        }
+        Encoding::Varint | Encoding::Compact => deserialize_varint(reader),
+    }
+}
+
+// Byte length of each 2-bit size code of the compact encoding
+const COMPACT_SIZES: [usize; 4] = [0, 1, 2, 8];
+
+// Marks a compact instruction whose opcode and size code share a byte. No
+// opcode has both top bits set, the next two bits hold the size code of the
+// only operand and the low four bits the opcode
+const COMPACT_SHORT_FORM: u8 = 0b1100_0000;
+
+// Writes an instruction with up to 3 operands in the compact encoding
+pub(crate) fn write_compact<W: Write>(
+    writer: &mut W,
+    opcode: u8,
+    operands: &[u64],
+) -> io::Result<()> {
+    assert!(operands.len() < 4, "instructions have at most 3 operands");
+    debug_assert!(opcode & COMPACT_SHORT_FORM != COMPACT_SHORT_FORM);
+    let codes: Vec<u8> = operands
+        .iter()
+        .map(|&operand| match operand {
+            0 => 0,
+            1..=0xff => 1,
+            0x100..=0xffff => 2,
+            _ => 3,
+        })
+        .collect();
+    match codes[..] {
+        [] => writer.write_all(&[opcode])?,
+        [code] if opcode < 0x10 => writer.write_all(&[COMPACT_SHORT_FORM | code << 4 | opcode])?,
+        _ => {
+            let sizes = codes
+                .iter()
+                .enumerate()
+                .fold((operands.len() as u8) << 6, |sizes, (i, &code)| {
+                    sizes | code << (2 * i)
+                });
+            writer.write_all(&[opcode, sizes])?;
+        }
+    }
+    for (operand, code) in operands.iter().zip(codes) {
+        writer.write_all(&operand.to_le_bytes()[..COMPACT_SIZES[code as usize]])?;
+    }
+    Ok(())
+}
+
+// The opcode and size code held by a compact short form byte
+pub(crate) fn compact_short_form(byte: u8) -> Option<(u8, u8)> {
+    (byte & COMPACT_SHORT_FORM == COMPACT_SHORT_FORM).then_some((byte & 0x0f, byte >> 4 & 0b11))
+}
+
+pub(crate) fn read_compact_operand<R: Read>(reader: &mut R, code: u8) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf[..COMPACT_SIZES[code as usize]])?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn read_compact_operands<R: Read>(reader: &mut R) -> io::Result<Vec<u64>> {
+    let mut sizes = [0];
+    reader.read_exact(&mut sizes)?;
+    let count = sizes[0] >> 6;
+    (0..count)
+        .map(|i| read_compact_operand(reader, sizes[0] >> (2 * i) & 0b11))
+        .collect()
+}
+
+// Writes the header followed by the instructions in the given encoding
+pub fn write_bytecode<W: Write>(
+    instructions: &[Instruction],
+    encoding: Encoding,
+    writer: &mut W,
+) -> io::Result<()> {
+    let code = unfuse(instructions);
+    write_header(writer, &code, encoding, FLAG_INSTRUCTION_COUNT)?;
+    serialize_code_with(&code, encoding, writer)
+}
+
+pub fn write_bytecode_with_debug<W: Write>(
+    instructions: &[Instruction],
+    debug_info: &DebugInfo,
+    encoding: Encoding,
+    writer: &mut W,
+) -> io::Result<()> {
+    let code = unfuse(instructions);
+    write_header(
+        writer,
+        &code,
+        encoding,
+        FLAG_DEBUG_INFO | FLAG_INSTRUCTION_COUNT,
+    )?;
+    serialize_code_with(&code, encoding, writer)?;
+    debug_info.unfused(instructions).serialize(writer, encoding)
+}
+
+// Writes the code of the bundle with its symbol table in the header
+pub fn write_bundle<W: Write>(
+    bundle: &Bundle,
+    encoding: Encoding,
+    writer: &mut W,
+) -> io::Result<()> {
+    let code = unfuse(&bundle.code);
+    let new_index = unfused_indices(&bundle.code);
+    let symbols: Vec<_> = bundle
+        .symbols
+        .iter()
+        .map(|(name, pc)| {
+            let pc = new_index.get(*pc as usize).copied().unwrap_or(*pc);
+            (name.clone(), pc)
+        })
+        .collect();
+    write_header(
+        writer,
+        &code,
+        encoding,
+        FLAG_SYMBOLS | FLAG_INSTRUCTION_COUNT,
+    )?;
+    write_symbol_table(writer, &symbols, encoding)?;
+    serialize_code_with(&code, encoding, writer)
+}
+
+// Reads a bytecode file along with its symbol table. Files that are not
+// bundles have an empty one
+pub fn read_bundle<R: Read>(reader: &mut R) -> io::Result<Bundle> {
+    let (code, _, symbols) = read_sections(reader)?;
+    Ok(Bundle { code, symbols })
+}
+
+// Writes a header marking the code as MessagePack, followed by the code
+#[cfg(feature = "msgpack")]
+pub fn write_msgpack_bytecode<W: Write>(
+    instructions: &[Instruction],
+    writer: &mut W,
+) -> io::Result<()> {
+    let encoding = Encoding::Fixed(Endianness::Little);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[
+        encoding.version(),
+        encoding.endianness().to_byte(),
+        FLAG_MSGPACK,
+    ])?;
+    crate::msgpack::serialize_msgpack(instructions, writer)
+}
+
+fn write_header<W: Write>(
+    writer: &mut W,
+    instructions: &[Instruction],
+    encoding: Encoding,
+    flags: u8,
+) -> io::Result<()> {
+    let count = u32::try_from(instructions.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many instructions"))?;
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[encoding.version(), encoding.endianness().to_byte(), flags])?;
+    match encoding.endianness() {
+        Endianness::Little => writer.write_all(&count.to_le_bytes()),
+        Endianness::Big => writer.write_all(&count.to_be_bytes()),
+    }
+}
+
+// Reads a bytecode file with or without a header
+pub fn read_bytecode<R: Read>(reader: &mut R) -> io::Result<Vec<Instruction>> {
+    Ok(read_bytecode_with_debug(reader)?.0)
+}
+
+pub fn read_bytecode_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<Instruction>> {
+    read_bytecode(&mut File::open(path)?)
+}
+
+// Reads a bytecode file along with its debug section, if it has one
+pub fn read_bytecode_with_debug<R: Read>(
+    reader: &mut R,
+) -> io::Result<(Vec<Instruction>, Option<DebugInfo>)> {
+    let (code, debug_info, _) = read_sections(reader)?;
+    Ok((code, debug_info))
+}
+
+// The code, debug section and symbol table of a bytecode file
+type Sections = (Vec<Instruction>, Option<DebugInfo>, Vec<(String, u64)>);
+
+fn read_sections<R: Read>(reader: &mut R) -> io::Result<Sections> {
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    reader.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+    if magic != MAGIC {
+        // No header, the bytes we consumed are already part of the code
+        let code = deserialize_code_with(
+            &mut Cursor::new(magic).chain(reader),
+            Encoding::Fixed(Endianness::Little),
+            0,
+        )?;
+        return Ok((code, None, Vec::new()));
+    }
+
+    let mut header = [0; 3];
+    reader.read_exact(&mut header)?;
+    let encoding = Encoding::from_header(header[0], header[1])?;
+    let flags = header[2];
+    if flags == FLAG_MSGPACK {
+        #[cfg(feature = "msgpack")]
+        return Ok((
+            crate::msgpack::deserialize_msgpack(reader)?,
+            None,
+            Vec::new(),
+        ));
+        #[cfg(not(feature = "msgpack"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "MessagePack bytecode needs the msgpack feature",
+        ));
+    }
+    check_flags(flags)?;
+
+    let count = if flags & FLAG_INSTRUCTION_COUNT != 0 {
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        Some(match encoding.endianness() {
+            Endianness::Little => u32::from_le_bytes(buf),
+            Endianness::Big => u32::from_be_bytes(buf),
+        } as u64)
+    } else {
+        None
+    };
+    let capacity = count.map_or(0, |count| {
+        (count as usize).min(MAX_PREALLOCATED_INSTRUCTIONS)
+    });
+    let symbols = if flags & FLAG_SYMBOLS != 0 {
+        read_symbol_table(reader, encoding)?
+    } else {
+        Vec::new()
+    };
+
+    if flags & FLAG_DEBUG_INFO == 0 {
+        // Without a debug section the code runs to the end of the file,
+        // so the count is only a capacity hint
+        let code = deserialize_code_with(reader, encoding, capacity)?;
+        return Ok((code, None, symbols));
+    }
+
+    let mut code = Vec::with_capacity(capacity);
+    for _ in 0..count.unwrap_or_default() {
+        code.push(Instruction::deserialize(reader, encoding)?);
+    }
+    let debug_info = DebugInfo::deserialize(reader, encoding)?;
+    Ok((code, Some(debug_info), symbols))
+}
+
+// Rejects flags of a file with a header that is not MessagePack
+pub(crate) fn check_flags(flags: u8) -> io::Result<()> {
+    let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    if flags & !(FLAG_DEBUG_INFO | FLAG_INSTRUCTION_COUNT | FLAG_SYMBOLS) != 0 {
+        return invalid("unsupported bytecode flags");
+    }
+    if flags & FLAG_DEBUG_INFO != 0 && flags & FLAG_INSTRUCTION_COUNT == 0 {
+        return invalid("debug info without an instruction count");
+    }
+    Ok(())
+}
+
+fn collect_strings<'a>(instructions: &'a [Instruction], table: &mut Vec<&'a str>) {
+    for instruction in instructions {
+        match instruction {
+            Instruction::OutStr(s) if !table.contains(&s.as_str()) => table.push(s),
+            _ => (),
+        }
+    }
+}
+
+fn serialize_with_table<W: Write>(
+    instruction: &Instruction,
+    table: &[&str],
+    writer: &mut W,
+) -> io::Result<()> {
+    match instruction {
+        Instruction::OutStr(s) => {
+            let index = table.iter().position(|t| t == s).unwrap() as u64;
+            writer.write_all(&[OUT_STR_REF])?;
+            write_operand(writer, index, Encoding::Fixed(Endianness::Little))
+        }
+        _ => instruction.serialize(writer, Encoding::Fixed(Endianness::Little)),
+    }
+}
+
+// Writes every distinct OutStr string once into a table in front of the code,
+// with the instructions referring to their string by index
+pub fn serialize_code_with_strings<W: Write>(
+    instructions: &[Instruction],
+    writer: &mut W,
+) -> io::Result<()> {
+    let instructions = unfuse(instructions);
+    let mut table = Vec::new();
+    collect_strings(&instructions, &mut table);
+
+    write_operand(
+        writer,
+        table.len() as u64,
+        Encoding::Fixed(Endianness::Little),
+    )?;
+    for string in &table {
+        serialize_string(writer, string, Encoding::Fixed(Endianness::Little))?;
+    }
+    for instruction in instructions.iter() {
+        serialize_with_table(instruction, &table, writer)?;
+    }
+    Ok(())
+}
+
+pub fn deserialize_code_with_strings<R: Read>(reader: &mut R) -> io::Result<Vec<Instruction>> {
+    let len = read_operand(reader, Encoding::Fixed(Endianness::Little))?;
+    let table = (0..len)
+        .map(|_| deserialize_string(reader, Encoding::Fixed(Endianness::Little)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut instructions = Vec::new();
+    loop {
+        let mut tag = [0];
+        match reader.read_exact(&mut tag) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let instruction = if tag[0] == OUT_STR_REF {
+            let index = read_operand(reader, Encoding::Fixed(Endianness::Little))?;
+            let string = table.get(index as usize).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid string table index")
+            })?;
+            Instruction::OutStr(string.clone())
+        } else {
+            Instruction::deserialize(
+                &mut (&tag[..]).chain(&mut *reader),
+                Encoding::Fixed(Endianness::Little),
+            )?
+        };
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialize_code;
+
+    #[test]
+    fn test_varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            serialize_varint(&mut buf, value).unwrap();
+            assert_eq!(deserialize_varint(&mut &buf[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_varint_sizes() {
+        let mut buf = Vec::new();
+        serialize_varint(&mut buf, 127).unwrap();
+        assert_eq!(buf, [0x7f]);
+
+        buf.clear();
+        serialize_varint(&mut buf, 300).unwrap();
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_varint_overflow() {
+        let buf = [0xff; 10];
+        assert!(deserialize_varint(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn test_read_bytecode_versions() {
+        let code = vec![
+            Instruction::Push(300),
+            Instruction::OutStr("Hello".to_string()),
+            Instruction::Gt(0, 1, 2),
+            Instruction::InByte(),
+        ];
+        for encoding in [
+            Encoding::Fixed(Endianness::Little),
+            Encoding::Fixed(Endianness::Big),
+            Encoding::Varint,
+            Encoding::Compact,
+        ] {
+            let mut buf = Vec::new();
+            write_bytecode(&code, encoding, &mut buf).unwrap();
+            assert_eq!(read_bytecode(&mut &buf[..]).unwrap(), code);
+        }
+
+        // Headerless files are read as version 1
+        let mut buf = Vec::new();
+        serialize_code(&code, &mut buf).unwrap();
+        assert_eq!(read_bytecode(&mut &buf[..]).unwrap(), code);
+    }
+
+    #[test]
+    fn test_varint_shrinks_examples() {
+        let examples: [&[u8]; 3] = [
+            include_bytes!("../fibonacci.bytecode"),
+            include_bytes!("../hello_world.bytecode"),
+            include_bytes!("../echo.bytecode"),
+        ];
+        for example in examples {
+            let code = read_bytecode(&mut &example[..]).unwrap();
+            let mut fixed = Vec::new();
+            let mut varint = Vec::new();
+            write_bytecode(&code, Encoding::Fixed(Endianness::Little), &mut fixed).unwrap();
+            write_bytecode(&code, Encoding::Varint, &mut varint).unwrap();
+
+            assert_eq!(fixed.len(), example.len() + MAGIC.len() + 3 + 4);
+            assert!(varint.len() < fixed.len());
+            assert_eq!(read_bytecode(&mut &varint[..]).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn test_string_table_round_trip() {
+        let code = vec![
+            Instruction::OutStr("Hello".to_string()),
+            Instruction::Push(1),
+            Instruction::OutStr("World".to_string()),
+            Instruction::OutStr("Hello".to_string()),
+        ];
+        let mut buf = Vec::new();
+        serialize_code_with_strings(&code, &mut buf).unwrap();
+        assert_eq!(deserialize_code_with_strings(&mut &buf[..]).unwrap(), code);
+    }
+
+    #[test]
+    fn test_string_table_deduplicates() {
+        let code = vec![Instruction::OutStr("a long repeated string".to_string()); 10];
+        let mut inline = Vec::new();
+        let mut table = Vec::new();
+        serialize_code(&code, &mut inline).unwrap();
+        serialize_code_with_strings(&code, &mut table).unwrap();
+
+        // count + one string + 10 references
+        assert_eq!(table.len(), 8 + (8 + 22) + 10 * 9);
+        assert!(table.len() < inline.len());
+    }
+
+    #[test]
+    fn test_string_table_invalid_index() {
+        let mut buf = Vec::new();
+        write_operand(&mut buf, 0, Encoding::Fixed(Endianness::Little)).unwrap();
+        buf.push(OUT_STR_REF);
+        write_operand(&mut buf, 0, Encoding::Fixed(Endianness::Little)).unwrap();
+        assert!(deserialize_code_with_strings(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn test_big_endian_operands() {
+        let mut buf = Vec::new();
+        write_bytecode(
+            &[Instruction::Push(1)],
+            Encoding::Fixed(Endianness::Big),
+            &mut buf,
+        )
+        .unwrap();
+
+        assert_eq!(&buf[..MAGIC.len()], MAGIC);
+        assert_eq!(
+            &buf[MAGIC.len()..],
+            [
+                1,
+                0x02,
+                FLAG_INSTRUCTION_COUNT,
+                0,
+                0,
+                0,
+                1,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                1
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_endianness() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend([1, 0x03, 0]);
+        assert!(read_bytecode(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn test_instruction_count_header() {
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::fuse(vec![Instruction::Copy(0), Instruction::Add(0, 1)]).unwrap(),
+            Instruction::Out(0),
+        ];
+        let mut buf = Vec::new();
+        write_bytecode(&code, Encoding::Fixed(Endianness::Big), &mut buf).unwrap();
+        assert_eq!(buf[MAGIC.len() + 2], FLAG_INSTRUCTION_COUNT);
+        assert_eq!(buf[MAGIC.len() + 3..MAGIC.len() + 7], 4u32.to_be_bytes());
+
+        let expected = read_bytecode(&mut &buf[..]).unwrap();
+        assert_eq!(expected.len(), 4);
+
+        // The count is only a hint, a wrong one reads the same code
+        for hint in [0u32, 1, 1000, u32::MAX] {
+            buf[MAGIC.len() + 3..MAGIC.len() + 7].copy_from_slice(&hint.to_be_bytes());
+            assert_eq!(read_bytecode(&mut &buf[..]).unwrap(), expected);
+        }
+
+        // Files without the count are still read
+        let mut legacy = MAGIC.to_vec();
+        legacy.extend([1, 0x01, 0]);
+        serialize_code(&code, &mut legacy).unwrap();
+        assert_eq!(read_bytecode(&mut &legacy[..]).unwrap(), expected);
+
+        // The count is what tells where the code ends and debug info starts
+        legacy[MAGIC.len() + 2] = FLAG_DEBUG_INFO;
+        assert!(read_bytecode(&mut &legacy[..]).is_err());
+    }
+
+    #[test]
+    fn test_fused_round_trip() {
+        let run = |code: Vec<Instruction>| {
+            let mut output = Vec::new();
+            crate::Machine::new(code)
+                .run(&mut &[][..], &mut output)
+                .unwrap();
+            output
+        };
+        // The Jmp skips over the OutStr, past the fused Copy and Add
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::fuse(vec![Instruction::Copy(0), Instruction::Add(0, 1)]).unwrap(),
+            Instruction::Jmp(4),
+            Instruction::OutStr("skipped".to_string()),
+            Instruction::Out(0),
+        ];
+        let expected = run(code.clone());
+        assert_eq!(expected, b"2\n");
+
+        let mut binary = Vec::new();
+        write_bytecode(&code, Encoding::Varint, &mut binary).unwrap();
+        let from_binary = read_bytecode(&mut &binary[..]).unwrap();
+        assert_eq!(from_binary[3], Instruction::Jmp(5));
+        assert_eq!(run(from_binary), expected);
+
+        let mut text = Vec::new();
+        crate::serialize_text(&code, &mut text).unwrap();
+        let from_text = crate::deserialize_text(&mut &text[..]).unwrap();
+        assert_eq!(run(from_text), expected);
+
+        // Every component gets the line of the Fused instruction
+        let debug_info = DebugInfo {
+            line_map: vec![Some(1), Some(2), Some(3), None, Some(5)],
+            source_file: None,
+        };
+        let mut buf = Vec::new();
+        write_bytecode_with_debug(&code, &debug_info, Encoding::Varint, &mut buf).unwrap();
+        let (_, debug_info) = read_bytecode_with_debug(&mut &buf[..]).unwrap();
+        assert_eq!(
+            debug_info.unwrap().line_map,
+            [Some(1), Some(2), Some(2), Some(3), None, Some(5)]
+        );
+
+        // Lookup table starts move like jump targets
+        let mut buf = Vec::new();
+        let code = [code[1].clone(), Instruction::LookupTable(0, 2, 0, 1)];
+        write_bytecode(&code, Encoding::Varint, &mut buf).unwrap();
+        assert_eq!(
+            read_bytecode(&mut &buf[..]).unwrap()[2],
+            Instruction::LookupTable(0, 3, 0, 2)
+        );
+    }
+
+    #[test]
+    fn test_detect_encoding() {
+        for encoding in [Encoding::Fixed(Endianness::Big), Encoding::Varint] {
+            let mut buf = Vec::new();
+            write_bytecode(&[Instruction::Push(1)], encoding, &mut buf).unwrap();
+            assert_eq!(Encoding::detect(&buf).unwrap(), encoding);
+        }
+        assert_eq!(
+            Encoding::detect(include_bytes!("../echo.bytecode")).unwrap(),
+            Encoding::Fixed(Endianness::Little)
+        );
+        assert!(Encoding::detect(&MAGIC).is_err());
+    }
+
+    #[test]
+    fn test_compact_operand_sizes() {
+        for (instruction, len) in [
+            // The size code of a single operand shares the tag byte
+            (Instruction::Push(0), 1),
+            (Instruction::Push(5), 2),
+            (Instruction::Push(300), 3),
+            (Instruction::Jmp(300), 3),
+            (Instruction::Push(u64::MAX), 9),
+            // Tags from 16 up have no room for it
+            (Instruction::StrFree(5), 3),
+            // Tag, sizes, then 0, 1 and 8 operand bytes
+            (Instruction::Gt(0, 1, 70000), 11),
+            (Instruction::In(), 1),
+            (Instruction::TwoDup(), 1),
+            // Too many operands for the sizes byte, so varints
+            (Instruction::LookupTable(1, 300, 0, 7), 6),
+        ] {
+            let bytes = instruction.to_bytes(Encoding::Compact);
+            assert_eq!(bytes.len(), len, "{:?}", instruction);
+            assert_eq!(
+                Instruction::deserialize(&mut &bytes[..], Encoding::Compact).unwrap(),
+                instruction
+            );
+        }
+
+        // Operand count 2, sizes 1 and 0
+        assert_eq!(
+            Instruction::Add(5, 0).to_bytes(Encoding::Compact)[1..],
+            [0b1000_0001, 5]
+        );
+        // Short form of Jmp, size code 2
+        assert_eq!(
+            Instruction::Jmp(300).to_bytes(Encoding::Compact),
+            [0b1110_1000, 44, 1]
+        );
+
+        // Short forms of tags without a single size-coded operand are rejected
+        for bytes in [&[0b1100_0101, 0][..], &[0b1100_0010], &[0b1100_0011]] {
+            assert!(Instruction::deserialize(&mut &bytes[..], Encoding::Compact).is_err());
+        }
+    }
+
+    #[test]
+    fn test_opcode_starts_every_encoding() {
+        for instruction in [
+            Instruction::Push(300),
+            Instruction::OutStr("hi".to_string()),
+            Instruction::Gt(0, 1, 70000),
+        ] {
+            for encoding in [
+                Encoding::Fixed(Endianness::Big),
+                Encoding::Varint,
+                Encoding::Compact,
+            ] {
+                let byte = instruction.to_bytes(encoding)[0];
+                assert_eq!(
+                    Some(compact_short_form(byte).map_or(byte, |(opcode, _)| opcode)),
+                    instruction.opcode(),
+                    "{:?}",
+                    instruction
+                );
+            }
+        }
+        assert_eq!(
+            Instruction::Fused(Box::new([Instruction::Push(1), Instruction::Out(0)])).opcode(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compact_shrinks_examples() {
+        let examples: [&[u8]; 3] = [
+            include_bytes!("../fibonacci.bytecode"),
+            include_bytes!("../hello_world.bytecode"),
+            include_bytes!("../echo.bytecode"),
+        ];
+        for example in examples {
+            let code = read_bytecode(&mut &example[..]).unwrap();
+            let mut fixed = Vec::new();
+            let mut varint = Vec::new();
+            let mut compact = Vec::new();
+            write_bytecode(&code, Encoding::Fixed(Endianness::Little), &mut fixed).unwrap();
+            write_bytecode(&code, Encoding::Varint, &mut varint).unwrap();
+            write_bytecode(&code, Encoding::Compact, &mut compact).unwrap();
+
+            assert!(compact.len() < fixed.len());
+            assert!(compact.len() <= varint.len());
+            assert_eq!(compact[MAGIC.len()], 3);
+            assert_eq!(read_bytecode(&mut &compact[..]).unwrap(), code);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::{error::Error, fmt, io};
+
+use crate::quota::QuotaError;
+
+// A problem found in a program before running it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    // The instruction at pc jumps past the end of the program
+    InvalidJumpTarget { pc: u64, target: u64 },
+    // The table of the LookupTable at pc is malformed
+    InvalidLookupTable { pc: u64, reason: &'static str },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidJumpTarget { pc, target } => {
+                write!(f, "instruction {} jumps to invalid target {}", pc, target)
+            }
+            ValidationError::InvalidLookupTable { pc, reason } => {
+                write!(f, "lookup table of instruction {}: {}", pc, reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    // Reading the input or writing the output failed
+    Io(io::Error),
+    // The sandbox policy does not allow the instruction to run
+    SandboxViolation {
+        instruction: &'static str,
+    },
+    // The machine used up its quota of the given resource
+    QuotaExceeded {
+        resource: &'static str,
+    },
+    // No native function is registered under the index
+    UnknownNative {
+        index: u64,
+    },
+    // The stack holds fewer values than the instruction needs
+    StackUnderflow {
+        instruction: &'static str,
+    },
+    // A stack value is out of range for how the instruction uses it
+    InvalidValue {
+        value: u64,
+    },
+    // The heap block was already freed
+    HeapDoubleFree {
+        address: u64,
+    },
+    // The address is not inside a live heap block
+    InvalidHeapAddress {
+        address: u64,
+    },
+    // No live string is stored under the handle
+    InvalidStringHandle {
+        handle: u64,
+    },
+    // An offset past the end of a string
+    IndexOutOfBounds {
+        index: u64,
+        len: u64,
+    },
+    // The instruction read text that is not a valid expected_type
+    ParseError {
+        raw_input: String,
+        expected_type: &'static str,
+        instruction: &'static str,
+    },
+    // Fewer values than a return with n results needs
+    CallingConventionViolation {
+        expected: u64,
+        found: u64,
+    },
+    // An instruction was given the wrong number of operands
+    OperandCountMismatch {
+        instruction: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    // No open file has the descriptor
+    InvalidFileDescriptor {
+        fd: u64,
+    },
+    // The instruction is not allowed in deterministic mode
+    NonDeterministicInstructionForbidden {
+        instruction: &'static str,
+    },
+    // Patching the instruction at pc would invalidate the program
+    IncompatiblePatch {
+        pc: u64,
+        reason: &'static str,
+    },
+    // resume_io was called on a machine that is not suspended at I/O
+    NotSuspendedAtIo,
+    // The program failed validation before it started
+    InvalidProgram(Vec<ValidationError>),
+    // The result of the instruction does not fit in a u64
+    ArithmeticOverflow {
+        instruction: &'static str,
+    },
+    // The format string of OutFormatted is invalid at the byte position
+    // or does not match its arguments
+    FormatError {
+        position: usize,
+        reason: &'static str,
+    },
+    // TypeAssert found a value that does not fit in the expected type
+    TypeError {
+        value: u64,
+        expected: &'static str,
+    },
+    // step_back was called with no recorded history left
+    HistoryEmpty,
+    // The arithmetic instruction has no result for its operands
+    ArithmeticError {
+        reason: &'static str,
+    },
+    // There is no global at the index, see VmConfig::initial_globals
+    InvalidGlobal {
+        index: u64,
+    },
+    // The host could not allocate the memory the instruction asked for
+    AllocationFailed {
+        bytes: u64,
+    },
+    // The machine has open files, which a fork cannot get its own copy of
+    ForkWithOpenFiles,
+    // The instruction can only run when the crate is built with the feature
+    FeatureDisabled {
+        instruction: &'static str,
+        feature: &'static str,
+    },
+    // An error raised by the instruction at pc, which comes from
+    // the given source line
+    Located {
+        pc: u64,
+        line: u32,
+        column: Option<u32>,
+        source_file: Option<String>,
+        error: Box<VmError>,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Io(e) => write!(f, "I/O error: {}", e),
+            VmError::SandboxViolation { instruction } => {
+                write!(f, "{} is not allowed by the sandbox policy", instruction)
+            }
+            VmError::QuotaExceeded { resource } => write!(f, "{} quota exceeded", resource),
+            VmError::UnknownNative { index } => {
+                write!(f, "no native function registered under index {}", index)
+            }
+            VmError::StackUnderflow { instruction } => {
+                write!(f, "stack underflow in {}", instruction)
+            }
+            VmError::InvalidValue { value } => write!(f, "invalid value {}", value),
+            VmError::HeapDoubleFree { address } => {
+                write!(f, "heap block at {} freed twice", address)
+            }
+            VmError::InvalidHeapAddress { address } => {
+                write!(f, "invalid heap address {}", address)
+            }
+            VmError::InvalidStringHandle { handle } => {
+                write!(f, "invalid string handle {}", handle)
+            }
+            VmError::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} out of bounds for length {}", index, len)
+            }
+            VmError::ParseError {
+                raw_input,
+                expected_type,
+                instruction,
+            } => write!(
+                f,
+                "{} cannot parse {:?} as {}",
+                instruction, raw_input, expected_type
+            ),
+            VmError::CallingConventionViolation { expected, found } => write!(
+                f,
+                "return needs {} values on the stack, found {}",
+                expected, found
+            ),
+            VmError::OperandCountMismatch {
+                instruction,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} operands, found {}",
+                instruction, expected, found
+            ),
+            VmError::InvalidFileDescriptor { fd } => write!(f, "invalid file descriptor {}", fd),
+            VmError::NonDeterministicInstructionForbidden { instruction } => {
+                write!(f, "{} is not allowed in deterministic mode", instruction)
+            }
+            VmError::IncompatiblePatch { pc, reason } => {
+                write!(f, "cannot patch the instruction at {}: {}", pc, reason)
+            }
+            VmError::NotSuspendedAtIo => write!(f, "the machine is not suspended at I/O"),
+            VmError::InvalidProgram(errors) => {
+                write!(f, "invalid program")?;
+                for (i, error) in errors.iter().enumerate() {
+                    let separator = if i == 0 { ": " } else { ", " };
+                    write!(f, "{}{}", separator, error)?;
+                }
+                Ok(())
+            }
+            VmError::ArithmeticOverflow { instruction } => {
+                write!(f, "arithmetic overflow in {}", instruction)
+            }
+            VmError::FormatError { position, reason } => {
+                write!(f, "invalid format at byte {}: {}", position, reason)
+            }
+            VmError::TypeError { value, expected } => {
+                write!(f, "value {} is not a valid {}", value, expected)
+            }
+            VmError::HistoryEmpty => write!(f, "no instruction to step back over"),
+            VmError::ArithmeticError { reason } => write!(f, "arithmetic error: {}", reason),
+            VmError::InvalidGlobal { index } => write!(f, "no global at index {}", index),
+            VmError::AllocationFailed { bytes } => write!(f, "failed to allocate {} bytes", bytes),
+            VmError::ForkWithOpenFiles => write!(f, "cannot fork a machine with open files"),
+            VmError::FeatureDisabled {
+                instruction,
+                feature,
+            } => write!(f, "{} needs the {} feature", instruction, feature),
+            VmError::Located {
+                pc,
+                line,
+                column,
+                source_file,
+                error,
+            } => {
+                match source_file {
+                    Some(file) => write!(f, "{}:{}", file, line)?,
+                    None => write!(f, "line {}", line)?,
+                }
+                if let Some(column) = column {
+                    write!(f, ":{}", column)?;
+                }
+                write!(f, " (pc {}): {}", pc, error)
+            }
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Io(e) => Some(e),
+            VmError::Located { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> Self {
+        match e.get_ref().and_then(|e| e.downcast_ref::<QuotaError>()) {
+            Some(QuotaError(resource)) => VmError::QuotaExceeded { resource },
+            None => VmError::Io(e),
+        }
+    }
+}
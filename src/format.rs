@@ -0,0 +1,118 @@
+use crate::{StringHeap, VmError};
+
+// Expands the format string used by OutFormatted. A directive is % followed
+// by an optional 0 flag, an optional width and one of
+//   d  the argument in decimal
+//   x  the argument in lowercase hexadecimal
+//   b  the argument in binary
+//   s  the string whose handle is the argument
+// %% writes a single %. Numbers are padded on the left with spaces, or
+// zeros with the 0 flag, strings always with spaces. Every argument must
+// be used exactly once
+pub(crate) fn format_output(
+    format: &[u8],
+    args: &[u64],
+    strings: &StringHeap,
+) -> Result<Vec<u8>, VmError> {
+    let error = |position, reason| VmError::FormatError { position, reason };
+    let mut output = Vec::new();
+    let mut args = args.iter();
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            output.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        if format.get(i) == Some(&b'%') {
+            output.push(b'%');
+            i += 1;
+            continue;
+        }
+        let zero_pad = format.get(i) == Some(&b'0');
+        if zero_pad {
+            i += 1;
+        }
+        let mut width = 0usize;
+        while let Some(digit @ b'0'..=b'9') = format.get(i) {
+            width = width
+                .checked_mul(10)
+                .and_then(|width| width.checked_add((digit - b'0') as usize))
+                .ok_or(error(start, "width too large"))?;
+            i += 1;
+        }
+        let Some(&conversion) = format.get(i) else {
+            return Err(error(start, "unterminated directive"));
+        };
+        i += 1;
+        let &arg = args.next().ok_or(error(start, "too few arguments"))?;
+        let text = match conversion {
+            b'd' => arg.to_string().into_bytes(),
+            b'x' => format!("{:x}", arg).into_bytes(),
+            b'b' => format!("{:b}", arg).into_bytes(),
+            b's' => strings.get(arg)?.to_vec(),
+            _ => return Err(error(start, "unknown conversion")),
+        };
+        let pad = if zero_pad && conversion != b's' {
+            b'0'
+        } else {
+            b' '
+        };
+        output.resize(output.len() + width.saturating_sub(text.len()), pad);
+        output.extend(text);
+    }
+    if args.next().is_some() {
+        return Err(error(format.len(), "too many arguments"));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(format: &str, args: &[u64]) -> Result<String, VmError> {
+        let mut strings = StringHeap::default();
+        let handle = strings.alloc(b"abc".to_vec());
+        assert_eq!(handle, 0);
+        let output = format_output(format.as_bytes(), args, &strings)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(
+            format("%d %x %b %s 100%%", &[42, 255, 5, 0]).unwrap(),
+            "42 ff 101 abc 100%"
+        );
+        assert_eq!(
+            format("[%5d|%05d|%08b|%2x|%5s]", &[42, 42, 5, 255, 0]).unwrap(),
+            "[   42|00042|00000101|ff|  abc]"
+        );
+        assert_eq!(format("no directives", &[]).unwrap(), "no directives");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        for (format_string, args, position, reason) in [
+            ("%d %d", &[1][..], 3, "too few arguments"),
+            ("%d", &[1, 2][..], 2, "too many arguments"),
+            ("x %q", &[1][..], 2, "unknown conversion"),
+            ("%05", &[1][..], 0, "unterminated directive"),
+        ] {
+            match format(format_string, args) {
+                Err(VmError::FormatError {
+                    position: p,
+                    reason: r,
+                }) => assert_eq!((p, r), (position, reason), "{}", format_string),
+                result => panic!("{}: {:?}", format_string, result),
+            }
+        }
+        assert!(matches!(
+            format("%s", &[7]),
+            Err(VmError::InvalidStringHandle { handle: 7 })
+        ));
+    }
+}
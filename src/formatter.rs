@@ -0,0 +1,148 @@
+use std::io::{self, BufReader, Read, Write};
+
+use crate::{Instruction, Machine, RunResult, VmError};
+
+// What an output instruction wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    // The value printed by Out
+    Int(u64),
+    // The byte written by OutByte
+    Byte(u8),
+    // Anything else, such as strings and formatted output
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputEvent<'a> {
+    pub pc: u64,
+    pub kind: OutputKind,
+    // The exact bytes the instruction wrote, line ending included
+    pub bytes: &'a [u8],
+}
+
+// Turns the output of each instruction into what reaches the writer, see
+// Machine::run_with_formatter
+pub trait VmOutputFormatter {
+    fn format(&mut self, output: &mut dyn Write, event: &OutputEvent) -> io::Result<()>;
+}
+
+// Writes the output unchanged, like Machine::run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlainOutputFormatter;
+
+impl VmOutputFormatter for PlainOutputFormatter {
+    fn format(&mut self, output: &mut dyn Write, event: &OutputEvent) -> io::Result<()> {
+        output.write_all(event.bytes)
+    }
+}
+
+// Writes one JSON object per line for each output instruction, such as
+// {"type":"int","value":42,"pc":3}. Text is decoded as UTF-8, replacing
+// invalid sequences, and keeps its line ending
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonOutputFormatter;
+
+impl VmOutputFormatter for JsonOutputFormatter {
+    fn format(&mut self, output: &mut dyn Write, event: &OutputEvent) -> io::Result<()> {
+        match event.kind {
+            OutputKind::Int(value) => write!(output, "{{\"type\":\"int\",\"value\":{}", value)?,
+            OutputKind::Byte(value) => write!(output, "{{\"type\":\"byte\",\"value\":{}", value)?,
+            OutputKind::Text => {
+                write!(output, "{{\"type\":\"text\",\"value\":\"")?;
+                for c in String::from_utf8_lossy(event.bytes).chars() {
+                    match c {
+                        '"' => write!(output, "\\\"")?,
+                        '\\' => write!(output, "\\\\")?,
+                        '\n' => write!(output, "\\n")?,
+                        '\r' => write!(output, "\\r")?,
+                        '\t' => write!(output, "\\t")?,
+                        c if c.is_control() => write!(output, "\\u{:04x}", c as u32)?,
+                        c => write!(output, "{}", c)?,
+                    }
+                }
+                write!(output, "\"")?;
+            }
+        }
+        writeln!(output, ",\"pc\":{}}}", event.pc)
+    }
+}
+
+impl Machine {
+    // Like run, but the output of every instruction goes through formatter
+    // before reaching the writer
+    pub fn run_with_formatter<W: Write, R: Read>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        formatter: &mut dyn VmOutputFormatter,
+    ) -> Result<RunResult, VmError> {
+        self.validate()?;
+        let mut input = BufReader::new(input);
+        let mut bytes = Vec::new();
+        loop {
+            let pc = self.pc;
+            let kind = match self.code.get(pc as usize) {
+                Some(Instruction::Out(pos)) => {
+                    self.stack_value(*pos, "OUT").ok().map(OutputKind::Int)
+                }
+                Some(Instruction::OutByte(pos)) => self
+                    .stack_value(*pos, "OUTBYTE")
+                    .ok()
+                    .and_then(|value| u8::try_from(value).ok())
+                    .map(OutputKind::Byte),
+                _ => None,
+            };
+            let running = self.step(&mut input, &mut bytes)?;
+            if !bytes.is_empty() {
+                let event = OutputEvent {
+                    pc,
+                    kind: kind.unwrap_or(OutputKind::Text),
+                    bytes: &bytes,
+                };
+                formatter.format(output, &event)?;
+                bytes.clear();
+            }
+            if !running {
+                break;
+            }
+        }
+        Ok(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    const SOURCE: &str = "IN\nOUT 0\nOUTBYTE 0\nOUTSTR \"say \\\"hi\\\"\"\n";
+
+    #[test]
+    fn test_plain_formatter() {
+        let mut plain = Vec::new();
+        Machine::new(assemble(SOURCE).unwrap())
+            .run_with_formatter(&mut &b"65\n"[..], &mut plain, &mut PlainOutputFormatter)
+            .unwrap();
+
+        let mut direct = Vec::new();
+        Machine::new(assemble(SOURCE).unwrap())
+            .run(&mut &b"65\n"[..], &mut direct)
+            .unwrap();
+        assert_eq!(plain, direct);
+    }
+
+    #[test]
+    fn test_json_formatter() {
+        let mut output = Vec::new();
+        Machine::new(assemble(SOURCE).unwrap())
+            .run_with_formatter(&mut &b"65\n"[..], &mut output, &mut JsonOutputFormatter)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"type\":\"int\",\"value\":65,\"pc\":1}\n\
+             {\"type\":\"byte\",\"value\":65,\"pc\":2}\n\
+             {\"type\":\"text\",\"value\":\"say \\\"hi\\\"\\n\",\"pc\":3}\n"
+        );
+    }
+}
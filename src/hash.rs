@@ -0,0 +1,39 @@
+// Non-cryptographic hash functions for the Hash64 instruction. Their
+// results are part of the program-visible behavior and must not change
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    // 64-bit FNV-1a
+    #[default]
+    Fnv1a,
+    // 64-bit FNV-1, which multiplies before mixing in each byte
+    Fnv1,
+}
+
+impl HashAlgorithm {
+    pub fn hash(self, bytes: &[u8]) -> u64 {
+        match self {
+            HashAlgorithm::Fnv1a => bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+            }),
+            HashAlgorithm::Fnv1 => bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+                hash.wrapping_mul(FNV_PRIME) ^ byte as u64
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fnv_reference_values() {
+        assert_eq!(HashAlgorithm::Fnv1a.hash(b""), FNV_OFFSET_BASIS);
+        assert_eq!(HashAlgorithm::Fnv1a.hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(HashAlgorithm::Fnv1.hash(b"a"), 0xaf63_bd4c_8601_b7be);
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+
+use crate::VmError;
+
+// First-fit allocator for u64 words. Live blocks are tracked by base
+// address, freed blocks go to a free list that is coalesced on free
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapAllocator {
+    memory: Vec<u64>,
+    // base -> size of every live block
+    allocations: BTreeMap<u64, usize>,
+    // base -> size of every free block, never adjacent to each other
+    free_list: BTreeMap<u64, usize>,
+}
+
+impl HeapAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Allocates size zeroed words and returns the base address of the block
+    pub fn alloc(&mut self, size: usize) -> u64 {
+        // Every block takes at least one word so that bases are unique
+        let size = size.max(1);
+
+        let free = self
+            .free_list
+            .iter()
+            .find(|&(_, &free_size)| free_size >= size)
+            .map(|(&base, &free_size)| (base, free_size));
+        let base = match free {
+            Some((base, free_size)) => {
+                self.free_list.remove(&base);
+                if free_size > size {
+                    self.free_list.insert(base + size as u64, free_size - size);
+                }
+                base
+            }
+            None => {
+                let base = self.memory.len() as u64;
+                self.memory.resize(self.memory.len() + size, 0);
+                base
+            }
+        };
+
+        self.allocations.insert(base, size);
+        base
+    }
+
+    // Frees the block starting at base and zeroes its memory
+    pub fn free(&mut self, base: u64) -> Result<(), VmError> {
+        let Some(size) = self.allocations.remove(&base) else {
+            return Err(if self.free_block_containing(base).is_some() {
+                VmError::HeapDoubleFree { address: base }
+            } else {
+                VmError::InvalidHeapAddress { address: base }
+            });
+        };
+        self.memory[base as usize..base as usize + size].fill(0);
+
+        let (mut base, mut size) = (base, size);
+        if let Some(next_size) = self.free_list.remove(&(base + size as u64)) {
+            size += next_size;
+        }
+        if let Some((prev_base, prev_size)) = base
+            .checked_sub(1)
+            .and_then(|end| self.free_block_containing(end))
+        {
+            base = prev_base;
+            size += prev_size;
+        }
+        self.free_list.insert(base, size);
+        Ok(())
+    }
+
+    pub fn read(&self, address: u64) -> Result<u64, VmError> {
+        self.check_live(address)?;
+        Ok(self.memory[address as usize])
+    }
+
+    pub fn write(&mut self, address: u64, value: u64) -> Result<(), VmError> {
+        self.check_live(address)?;
+        self.memory[address as usize] = value;
+        Ok(())
+    }
+
+    // Number of words in live blocks
+    pub fn allocated_words(&self) -> usize {
+        self.allocations.values().sum()
+    }
+
+    fn check_live(&self, address: u64) -> Result<(), VmError> {
+        match self.allocations.range(..=address).next_back() {
+            Some((&base, &size)) if address < base + size as u64 => Ok(()),
+            _ => Err(VmError::InvalidHeapAddress { address }),
+        }
+    }
+
+    fn free_block_containing(&self, address: u64) -> Option<(u64, usize)> {
+        match self.free_list.range(..=address).next_back() {
+            Some((&base, &size)) if address < base + size as u64 => Some((base, size)),
+            _ => None,
+        }
+    }
+}
+
+// Byte strings addressed by handle. Freed handles are reused
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StringHeap {
+    strings: Vec<Option<Vec<u8>>>,
+    free_handles: Vec<u64>,
+}
+
+impl StringHeap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stores the bytes and returns the handle of the new string
+    pub fn alloc(&mut self, bytes: Vec<u8>) -> u64 {
+        match self.free_handles.pop() {
+            Some(handle) => {
+                self.strings[handle as usize] = Some(bytes);
+                handle
+            }
+            None => {
+                self.strings.push(Some(bytes));
+                self.strings.len() as u64 - 1
+            }
+        }
+    }
+
+    pub fn free(&mut self, handle: u64) -> Result<(), VmError> {
+        self.slot(handle)?.take();
+        self.free_handles.push(handle);
+        Ok(())
+    }
+
+    pub fn get(&self, handle: u64) -> Result<&[u8], VmError> {
+        self.strings
+            .get(handle as usize)
+            .and_then(Option::as_deref)
+            .ok_or(VmError::InvalidStringHandle { handle })
+    }
+
+    pub fn get_mut(&mut self, handle: u64) -> Result<&mut Vec<u8>, VmError> {
+        self.slot(handle)?
+            .as_mut()
+            .ok_or(VmError::InvalidStringHandle { handle })
+    }
+
+    // Number of live strings
+    pub fn len(&self) -> usize {
+        self.strings.len() - self.free_handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Combined length of the live strings
+    pub fn total_bytes(&self) -> usize {
+        self.strings.iter().flatten().map(Vec::len).sum()
+    }
+
+    fn slot(&mut self, handle: u64) -> Result<&mut Option<Vec<u8>>, VmError> {
+        self.strings
+            .get_mut(handle as usize)
+            .filter(|slot| slot.is_some())
+            .ok_or(VmError::InvalidStringHandle { handle })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_read_write() {
+        let mut heap = HeapAllocator::new();
+        let a = heap.alloc(2);
+        let b = heap.alloc(3);
+
+        heap.write(a + 1, 7).unwrap();
+        heap.write(b, 9).unwrap();
+        assert_eq!(heap.read(a).unwrap(), 0);
+        assert_eq!(heap.read(a + 1).unwrap(), 7);
+        assert_eq!(heap.read(b).unwrap(), 9);
+        assert_eq!(heap.allocated_words(), 5);
+
+        assert!(matches!(
+            heap.read(b + 3),
+            Err(VmError::InvalidHeapAddress { address: 5 })
+        ));
+    }
+
+    #[test]
+    fn test_free_reuses_and_zeroes() {
+        let mut heap = HeapAllocator::new();
+        let a = heap.alloc(2);
+        let b = heap.alloc(2);
+        heap.write(a, 1).unwrap();
+        heap.free(a).unwrap();
+
+        assert!(heap.read(a).is_err());
+        let c = heap.alloc(1);
+        assert_eq!(c, a);
+        assert_eq!(heap.read(c).unwrap(), 0);
+
+        // Adjacent free blocks are merged so a larger block fits
+        heap.free(b).unwrap();
+        heap.free(c).unwrap();
+        assert_eq!(heap.alloc(4), a);
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mut heap = HeapAllocator::new();
+        let a = heap.alloc(4);
+        heap.free(a).unwrap();
+
+        assert!(matches!(
+            heap.free(a),
+            Err(VmError::HeapDoubleFree { address: 0 })
+        ));
+        assert!(matches!(
+            heap.free(100),
+            Err(VmError::InvalidHeapAddress { address: 100 })
+        ));
+    }
+
+    #[test]
+    fn test_string_heap() {
+        let mut strings = StringHeap::new();
+        let a = strings.alloc(b"ab".to_vec());
+        let b = strings.alloc(vec![0; 3]);
+        strings.get_mut(b).unwrap()[1] = b'x';
+
+        assert_eq!(strings.get(a).unwrap(), b"ab");
+        assert_eq!(strings.get(b).unwrap(), b"\0x\0");
+        assert_eq!(strings.len(), 2);
+
+        strings.free(a).unwrap();
+        assert!(matches!(
+            strings.get(a),
+            Err(VmError::InvalidStringHandle { handle: 0 })
+        ));
+        assert!(strings.free(a).is_err());
+        assert_eq!(strings.alloc(vec![]), a);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{HeapAllocator, Machine, StringHeap, TypeTag, VmError};
+
+// The program visible state of a machine at one point of a run. The input
+// and output, open files and resource usage are not part of it, since
+// restoring cannot undo reading, writing or the work already done
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    stack: Vec<u64>,
+    pc: u64,
+    heap: HeapAllocator,
+    strings: StringHeap,
+    globals: Vec<u64>,
+    exit_code: Option<i32>,
+    tags: Vec<TypeTag>,
+}
+
+impl Machine {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            stack: self.stack.clone(),
+            pc: self.pc,
+            heap: self.heap.clone(),
+            strings: self.strings.clone(),
+            globals: self.globals.clone(),
+            exit_code: self.exit_code,
+            tags: self.tags.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.stack = snapshot.stack;
+        self.pc = snapshot.pc;
+        self.heap = snapshot.heap;
+        self.strings = snapshot.strings;
+        self.globals = snapshot.globals;
+        self.exit_code = snapshot.exit_code;
+        self.tags = snapshot.tags;
+    }
+
+    // Undoes the last instruction executed, including one that failed, by
+    // restoring the snapshot taken before it. Only recorded when
+    // VmConfig::reverse_mode is set
+    pub fn step_back(&mut self) -> Result<(), VmError> {
+        let snapshot = self.history.pop_back().ok_or(VmError::HistoryEmpty)?;
+        self.restore(snapshot);
+        Ok(())
+    }
+
+    // Records the state before an instruction runs, forgetting the oldest
+    // snapshot once the history holds VmConfig::max_history_depth of them
+    pub(crate) fn record_history(&mut self) {
+        if self.config.max_history_depth == 0 {
+            return;
+        }
+        if self.history.len() >= self.config.max_history_depth {
+            self.history.pop_front();
+        }
+        let snapshot = self.snapshot();
+        self.history.push_back(snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble, Machine, VmConfig, VmError};
+
+    fn machine(source: &str, max_history_depth: usize) -> Machine {
+        Machine::with_config(
+            assemble(source).unwrap(),
+            VmConfig {
+                reverse_mode: true,
+                max_history_depth,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn steps(machine: &mut Machine, n: usize) {
+        for _ in 0..n {
+            assert!(machine.step(&mut &b""[..], &mut Vec::new()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut machine = machine("PUSH 1\nPUSH 2\nADD 0 1\nINC 0\nCOPY 0\nOUT 0", 16);
+        steps(&mut machine, 5);
+        assert_eq!(machine.pc, 5);
+        assert_eq!(machine.stack, vec![4, 4]);
+
+        for _ in 0..3 {
+            machine.step_back().unwrap();
+        }
+        assert_eq!(machine.pc, 2);
+        assert_eq!(machine.stack, vec![1, 2]);
+
+        // Running forward again repeats the undone instructions
+        steps(&mut machine, 2);
+        assert_eq!(machine.stack, vec![4]);
+    }
+
+    #[test]
+    fn test_history_depth() {
+        let mut machine = machine("PUSH 1\nPUSH 2\nPUSH 3\nPUSH 4", 2);
+        steps(&mut machine, 4);
+        machine.step_back().unwrap();
+        machine.step_back().unwrap();
+        assert_eq!(machine.pc, 2);
+        assert!(matches!(machine.step_back(), Err(VmError::HistoryEmpty)));
+
+        let mut machine = Machine::new(assemble("PUSH 1").unwrap());
+        steps(&mut machine, 1);
+        assert!(matches!(machine.step_back(), Err(VmError::HistoryEmpty)));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{error::Error, fmt, io};
+
+use crate::{read_bytecode, Instruction};
+
+// Content-Type servers send bytecode files with
+pub const BYTECODE_MIME_TYPE: &str = "application/x-bytecode-vm";
+
+#[derive(Debug)]
+pub enum LoadError {
+    // The server answered with an error status
+    Http { status: u16 },
+    // The server could not be reached or the transfer broke off
+    Connection(ureq::Error),
+    // The response is not bytecode according to its Content-Type
+    UnexpectedContentType(Option<String>),
+    // The response body is not a valid bytecode file
+    Format(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Http { status } => write!(f, "server responded with status {}", status),
+            LoadError::Connection(e) => write!(f, "connection failed: {}", e),
+            LoadError::UnexpectedContentType(Some(content_type)) => write!(
+                f,
+                "expected content type {}, got {}",
+                BYTECODE_MIME_TYPE, content_type
+            ),
+            LoadError::UnexpectedContentType(None) => {
+                write!(f, "expected content type {}", BYTECODE_MIME_TYPE)
+            }
+            LoadError::Format(e) => write!(f, "invalid bytecode: {}", e),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Connection(e) => Some(e),
+            LoadError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Downloads a bytecode file, with or without a header, over HTTP(S)
+pub fn load_bytecode_from_url(url: &str) -> Result<Vec<Instruction>, LoadError> {
+    let mut response = ureq::get(url).call().map_err(|e| match e {
+        ureq::Error::StatusCode(status) => LoadError::Http { status },
+        e => LoadError::Connection(e),
+    })?;
+
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+    // Parameters such as a charset are allowed
+    let mime_type = content_type
+        .as_deref()
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+    if !mime_type.is_some_and(|mime_type| mime_type.eq_ignore_ascii_case(BYTECODE_MIME_TYPE)) {
+        return Err(LoadError::UnexpectedContentType(content_type));
+    }
+
+    read_bytecode(&mut response.body_mut().as_reader()).map_err(LoadError::Format)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+    use crate::{write_bytecode, Encoding};
+
+    // Serves a single request with the given status, content type and body
+    fn serve(status: &str, content_type: &str, body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/program.bytecode", listener.local_addr().unwrap());
+        let response = [
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                content_type,
+                body.len()
+            )
+            .into_bytes(),
+            body,
+        ]
+        .concat();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(&response).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_load_bytecode_from_url() {
+        let code = vec![Instruction::Push(1), Instruction::Out(0)];
+        let mut body = Vec::new();
+        write_bytecode(&code, Encoding::Varint, &mut body).unwrap();
+
+        let url = serve("200 OK", "application/x-bytecode-vm; charset=binary", body);
+        assert_eq!(load_bytecode_from_url(&url).unwrap(), code);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let url = serve("404 Not Found", "text/plain", b"missing".to_vec());
+        assert!(matches!(
+            load_bytecode_from_url(&url),
+            Err(LoadError::Http { status: 404 })
+        ));
+
+        let url = serve("200 OK", "text/html", b"<html>".to_vec());
+        assert!(matches!(
+            load_bytecode_from_url(&url),
+            Err(LoadError::UnexpectedContentType(Some(content_type))) if content_type == "text/html"
+        ));
+
+        let url = serve("200 OK", BYTECODE_MIME_TYPE, vec![255]);
+        assert!(matches!(
+            load_bytecode_from_url(&url),
+            Err(LoadError::Format(_))
+        ));
+    }
+}
@@ -1,6 +1,127 @@
-use std::io::{self, BufRead, BufReader, Read, Write};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    time::{Duration, Instant},
+};
 
-#[derive(Debug, Clone, PartialEq)]
+use encoding::{
+    compact_short_form, read_compact_operand, read_compact_operands, read_operand, write_compact,
+    write_operand,
+};
+use quota::{QuotaReader, QuotaWriter};
+use vm_io::VmIo;
+
+pub mod assembler;
+pub mod batch;
+mod bundle;
+pub mod capture;
+mod cfg;
+#[cfg(feature = "crc")]
+pub mod checksum;
+mod config;
+mod cost;
+mod debug;
+mod depth;
+pub mod encoding;
+mod error;
+mod format;
+mod formatter;
+mod hash;
+mod heap;
+mod history;
+#[cfg(feature = "http_loader")]
+mod http_loader;
+#[cfg(feature = "mmap")]
+mod mapped;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+pub mod optimize;
+mod pretty;
+mod program;
+mod quota;
+mod replay;
+mod stdlib;
+mod tags;
+mod timing;
+pub mod trace;
+mod vm_io;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use assembler::{
+    assemble, deserialize_text, serialize_text, AssembleError, Assembler, AssemblyOutput, SourceMap,
+};
+pub use batch::BatchRunResult;
+pub use bundle::{bundle_bytecode_files, Bundle, BundleError};
+pub use capture::{OutputCapture, OutputChunk};
+pub use cfg::{basic_blocks, BasicBlock};
+pub use config::{NewlineMode, SandboxPolicy, VmConfig};
+pub use cost::{instruction_cost, program_estimated_cost, InstructionCost};
+pub use debug::DebugInfo;
+pub use depth::{check_stack_depths, StackDepthError};
+#[cfg(feature = "msgpack")]
+pub use encoding::write_msgpack_bytecode;
+pub use encoding::{
+    deserialize_code_with_strings, read_bundle, read_bytecode, read_bytecode_file,
+    read_bytecode_with_debug, serialize_code_with_strings, write_bundle, write_bytecode,
+    write_bytecode_with_debug, Encoding, Endianness,
+};
+pub use error::{ValidationError, VmError};
+pub use formatter::{
+    JsonOutputFormatter, OutputEvent, OutputKind, PlainOutputFormatter, VmOutputFormatter,
+};
+pub use hash::HashAlgorithm;
+pub use heap::{HeapAllocator, StringHeap};
+pub use history::Snapshot;
+#[cfg(feature = "http_loader")]
+pub use http_loader::{load_bytecode_from_url, LoadError, BYTECODE_MIME_TYPE};
+#[cfg(feature = "mmap")]
+pub use mapped::{mmap_bytecode, MappedBytecode};
+#[cfg(feature = "msgpack")]
+pub use msgpack::{deserialize_msgpack, serialize_msgpack};
+pub use program::Program;
+pub use quota::ResourceUsage;
+pub use replay::{ReplayEvent, ReplayPlayer, ReplayRecorder};
+pub use stdlib::{
+    VmStdlib, STDLIB_PRINT_INT, STDLIB_PRINT_NEWLINE, STDLIB_READ_INT, STDLIB_READ_LINE_AS_HANDLE,
+    STDLIB_STR_EQUAL, STDLIB_STR_STARTS_WITH,
+};
+pub use tags::TypeTag;
+pub use timing::{print_timing_report, write_timing_report};
+pub use trace::run_with_json_trace;
+pub use vm_io::{VmRead, VmWrite};
+
+// Stack operands are positions relative to the top of the stack, 0 being
+// the top. How an instruction treats the values it addresses follows one
+// rule: instructions read their operands in place and leave them on the
+// stack, and only the arithmetic modeled on Add consumes them.
+//
+//   reads, leaves the operands    Out, OutByte, OutCSV, OutStrHandle(Ln),
+//                                 Gt, GtOrEq, Eq, NotEq, FGt, FLt, FEq,
+//                                 StrCmp, Compare128, AbsJmpIfNeg,
+//                                 LookupTable, TypeAssert, ExitCode,
+//                                 StoreGlobal
+//   reads, pushes a result        Copy, CopyRange, CmpPush, Checksum,
+//                                 Hash64, Crc32, StrLen, StrLoad, StrCat,
+//                                 StrSlice, StrToInt, IntToStr, StrAlloc,
+//                                 GetArg, FdOpen, FdRead
+//   changes the operand in place  Inc, Dec, SatInc, SatDec, WrapInc, WrapDec
+//   removes the operands, pushes  Add, SatAdd, SatSub, WrapAdd, WrapSub,
+//   the result                    FAdd, FSub, FMul, FDiv, Gcd, Lcm,
+//                                 ModInverse, Select, Add128, Mul128 (the
+//                                 two pairs on top)
+//   removes the operands only     OutFormatted (with its arguments),
+//                                 DropRange, MultiRet (the return address),
+//                                 StoreGlobalPop (the top)
+//
+// CondMove and StrStore write through their destination operand, FdWrite,
+// FdClose and StrFree act on what their operands refer to, and the pair
+// instructions 2DUP, 2DROP and 2SWAP work on the top of the stack
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "msgpack", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     // Pushes a value onto the stack
     Push(u64),
@@ -29,39 +150,262 @@ pub enum Instruction {
     Dec(u64),
     // Increments the value at the specified position in the stack by 1
     Inc(u64),
-    // Reads a byte from the input and pushes it onto the stack
+    // Reads a byte from the input and pushes it onto the stack. Pushes
+    // VmConfig::eof_sentinel instead once the input is exhausted
     InByte(),
     // Reads a value from the stack at the specified position,
     // converts it to a byte, and writes it to the output
     OutByte(u64),
+    // Writes a string to the output without a trailing newline
+    OutStrNoNl(String),
+    // Calls the native function registered under the specified index.
+    // The function reads its arguments from and pushes its results onto the stack
+    CallNative(u64),
+    // Pops a relative position of a byte string holding a variable name and
+    // pushes the value of that environment variable as a byte string, which
+    // is empty if the variable is not set. Byte strings are stored as their
+    // bytes followed by their length
+    InEnv(),
+    // Reads n from the stack at the specified position and pushes program
+    // argument n as a byte string, which is empty if there is no such argument
+    GetArg(u64),
+    // Reads a value from the stack at the specified position and stops
+    // the machine, asking the host to exit the process with that code
+    ExitCode(u64),
+    // Reads a length from the stack at the specified position, allocates
+    // a zeroed string of that many bytes on the string heap and pushes its handle
+    StrAlloc(u64),
+    // Reads a string handle, an offset and a byte from the stack at the
+    // specified positions and stores the byte in the string at the offset
+    StrStore(u64, u64, u64),
+    // Reads a string handle and an offset from the stack at the specified
+    // positions and pushes the byte of the string at the offset
+    StrLoad(u64, u64),
+    // Reads a string handle from the stack at the specified position
+    // and pushes the length of the string
+    StrLen(u64),
+    // Reads two string handles from the stack at the specified positions and
+    // pushes the handle of a new string holding their concatenation
+    StrCat(u64, u64),
+    // Reads a string handle from the stack at the specified position
+    // and frees the string
+    StrFree(u64),
+    // Reads a string handle from the stack at the specified position
+    // and writes the string to the output
+    OutStrHandle(u64),
+    // Like OutStrHandle, but writes a trailing newline
+    OutStrHandleLn(u64),
+    // Reads two string handles from the stack at the specified positions,
+    // jumps to a specified program counter if the strings are equal
+    StrCmp(u64, u64, u64),
+    // Reads a string handle from the stack at the specified position,
+    // parses the string as a decimal integer and pushes the result
+    StrToInt(u64),
+    // Reads a value from the stack at the specified position, formats it as
+    // a decimal string on the string heap and pushes its handle
+    IntToStr(u64),
+    // Reads a string handle, a start offset and a length from the stack at
+    // the specified positions and pushes the handle of a new string holding
+    // that part of the string
+    StrSlice(u64, u64, u64),
+    // Returns n values: removes the return address found below the top n
+    // values and jumps to it, leaving the n values on the stack
+    MultiRet(u64),
+    // If the value at the third specified position is not zero, copies the
+    // value at the first position over the value at the second position
+    CondMove(u64, u64, u64),
+    // Removes the values at the three specified positions and pushes the
+    // first one if the third is not zero, otherwise the second one
+    Select(u64, u64, u64),
+    // Pushes copies of the values at positions 1 and 0, in that order
+    TwoDup(),
+    // Removes the values at positions 0 and 1
+    TwoDrop(),
+    // Exchanges the pair at positions 0 and 1 with the pair at positions 2 and 3
+    TwoSwap(),
+    // Reads a count n from the stack at the specified position and writes
+    // the n values below it, nearest first, as one comma-separated line
+    OutCSV(u64),
+    // Compares two values in the stack at specified positions and pushes
+    // -1 as u64 if the first is less, 0 if they are equal and 1 if it is greater
+    CmpPush(u64, u64),
+    // Reads a count n from the stack at the second specified position and
+    // pushes the CRC-32 of the n bytes ending at the first position, in the
+    // order they were pushed. Running it needs the crc feature
+    Crc32(u64, u64),
+    // Reads a count n from the stack at the second specified position and
+    // pushes the hash of the n bytes ending at the first position, using
+    // VmConfig::hash_algorithm
+    Hash64(u64, u64),
+    // Reads a string handle holding a path and a mode from the stack at the
+    // specified positions, opens the file for reading (0), writing (1) or
+    // appending (2) and pushes its descriptor, or u64::MAX if it fails
+    FdOpen(u64, u64),
+    // Reads a descriptor and a count from the stack at the specified
+    // positions, reads up to count bytes from the file into a new string
+    // and pushes its handle
+    FdRead(u64, u64),
+    // Reads a descriptor and a string handle from the stack at the
+    // specified positions and writes the string to the file
+    FdWrite(u64, u64),
+    // Reads a descriptor from the stack at the specified position
+    // and closes the file
+    FdClose(u64),
+    // Pushes copies of the count values starting at the specified
+    // position and going down the stack, nearest first
+    CopyRange(u64, u64),
+    // Removes the count values starting at the specified position and
+    // going down the stack, shifting the values above them down
+    DropRange(u64, u64),
+    // Like Add, but the sum saturates at u64::MAX instead of wrapping
+    SatAdd(u64, u64),
+    // Subtracts the value at the second position from the value at the
+    // first, removing both like Add. The difference saturates at 0
+    SatSub(u64, u64),
+    // Like Inc and Dec, saturating at u64::MAX and 0
+    SatInc(u64),
+    SatDec(u64),
+    // Like SatAdd and SatSub, but the result wraps around modulo 2^64
+    WrapAdd(u64, u64),
+    WrapSub(u64, u64),
+    // Like Inc and Dec, wrapping around modulo 2^64
+    WrapInc(u64),
+    WrapDec(u64),
+    // 128-bit values take two stack slots, the high word below the low
+    // word. A pair is addressed by the position of its low word, so the
+    // pair on top of the stack is at position 0 and the one below it at 2.
+    // Pushes the value as a pair. In the assembler and in serialized code
+    // the operand is written as the high word followed by the low word
+    Push128(u128),
+    // Pop the two pairs on top of the stack and push their sum or product
+    // as a pair, wrapping around modulo 2^128
+    Add128(),
+    Mul128(),
+    // Compares the pairs at the specified positions and jumps to the
+    // specified instruction if the first is greater
+    Compare128(u64, u64, u64),
+    // Removes every value from the stack
+    ClearStack(),
+    // Sets every global variable to 0
+    ClearGlobals(),
+    // Jumps to the specified instruction if the value in the stack at the
+    // specified position is negative as an i64, that is its high bit is set
+    AbsJmpIfNeg(u64, u64),
+    // Like InByte, but the byte stays in the input and is read again by
+    // the next input instruction
+    InPeek(),
+    // Treat the values in the stack at the specified positions as the bits
+    // of f64 values, remove them like Add and push the bits of the result.
+    // NaN and infinities propagate as IEEE 754 specifies, nothing panics.
+    // FSub subtracts the second value from the first and FDiv divides the
+    // first by the second
+    FAdd(u64, u64),
+    FSub(u64, u64),
+    FMul(u64, u64),
+    FDiv(u64, u64),
+    // Like Gt and Eq, comparing the values in the stack at the specified
+    // positions as f64 bits and jumping to the specified instruction if the
+    // first is greater, less or equal. FEq compares the values, not their
+    // bits, so 0.0 equals -0.0. Every comparison with NaN is false as IEEE
+    // 754 specifies, so none of them jumps when either value is NaN, not even
+    // FEq on two identical NaNs
+    FGt(u64, u64, u64),
+    FLt(u64, u64, u64),
+    FEq(u64, u64, u64),
+    // Remove the values in the stack at the specified positions like Add
+    // and push their greatest common divisor or least common multiple.
+    // gcd(n, 0) is n, so gcd(0, 0) is 0, and lcm(n, 0) is 0. Lcm fails
+    // with ArithmeticOverflow if the result does not fit in a u64
+    Gcd(u64, u64),
+    Lcm(u64, u64),
+    // Removes the handle of a format string and an argument count from the
+    // stack at the specified positions like Add, then pops that many
+    // arguments and writes the formatted string. The argument deepest in
+    // the stack is the first one used, so arguments are pushed in the order
+    // they appear. The format supports %d, %x, %b and %s with an optional
+    // 0 flag and width, see format::format_output. Fails with FormatError
+    // if the format is invalid or uses a different number of arguments
+    OutFormatted(u64, u64),
+    // Compares two values in the stack at specified positions,
+    // jumps to a specified program counter if the values differ
+    NotEq(u64, u64, u64),
+    // Looks up the value in the stack at the specified position in the
+    // table of size entries starting at instruction start and jumps to the
+    // target of the matching entry, or to default_pc if none matches. Each
+    // entry is a Push(key) followed by a Jmp(target), and the keys must be
+    // strictly ascending so the lookup is a binary search. The table is
+    // never executed, so it usually sits after an unconditional jump.
+    // Operands are key_pos, start, size and default_pc. The table is read
+    // from Machine::code, so LookupTable fails in Machine::run_mapped
+    LookupTable(u64, u64, u64, u64),
+    // Pushes the XOR of the count values starting at the specified position
+    // and going down the stack, 0 if count is 0. Lets a program check that
+    // a range of the stack still holds what it expects
+    Checksum(u64, u64),
+    // Fails with TypeError unless the value in the stack at the specified
+    // position fits in the type with the specified tag, see TYPE_U8 and the
+    // following constants. Emitted by compilers that check types and
+    // removed by optimize::strip_type_asserts once they are proven
+    TypeAssert(u64, u64),
+    // Starts timing the section identified by the value at the specified
+    // position. Does nothing when built with the measure_noop feature
+    MeasureStart(u64),
+    // Adds the time since the matching MeasureStart to Machine::take_timers.
+    // Does nothing if the section was not started
+    MeasureEnd(u64),
+    // Operands are count_pos and heap_base_pos. Reads up to count bytes
+    // from the input into consecutive heap words, one byte per word, and
+    // pushes the number of bytes read, which is less than count at the
+    // end of the input
+    InBytes(u64, u64),
+    // Operands are heap_base_pos and count_pos. Writes count heap words
+    // starting at the base to the output as bytes
+    OutBytes(u64, u64),
+    // Compares two values in the stack at specified positions, jumps to
+    // a specified program counter if the first is greater or equal
+    GtOrEq(u64, u64, u64),
+    // Like Add, but pushes the multiplicative inverse of the first value
+    // modulo the second. Fails with ArithmeticError if they are not coprime
+    ModInverse(u64, u64),
+    // Operands are index and stack_pos. Copies the value in the stack at
+    // the position into the global at the index, leaving it on the stack
+    StoreGlobal(u64, u64),
+    // Removes the value on top of the stack and stores it into the global
+    // at the specified index
+    StoreGlobalPop(u64),
+    // Executes a pre-validated sequence of non-branching instructions
+    // as a single step. Serializes to the bytes of its components
+    Fused(Box<[Instruction]>),
 }
 
+// Type tags checked by TypeAssert
+pub const TYPE_U8: u64 = 0;
+pub const TYPE_U16: u64 = 1;
+pub const TYPE_U32: u64 = 2;
+pub const TYPE_U64: u64 = 3;
+
 macro_rules! deserialize_variant {
-    ($variant:ident, $input:ident, $($field:ident),*) => {{
-        let mut buf = [0; 8];
+    ($variant:ident, $input:ident, $encoding:ident, $($field:ident),*) => {{
         $(
-            $input.read_exact(&mut buf)?;
-            let $field = u64::from_le_bytes(buf);
+            let $field = read_operand($input, $encoding)?;
         )*
             Ok(Instruction::$variant($($field),*))
     }}
 }
 
-fn serialize_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+fn serialize_string<W: Write>(writer: &mut W, string: &str, encoding: Encoding) -> io::Result<()> {
     // Serialize the length of the string as a u64 value
     let len = string.len() as u64;
-    writer.write(&len.to_le_bytes())?;
+    write_operand(writer, len, encoding)?;
 
     // Serialize the string as a sequence of bytes
-    writer.write(string.as_bytes())?;
+    writer.write_all(string.as_bytes())?;
     Ok(())
 }
 
-fn deserialize_string<R: Read>(reader: &mut R) -> io::Result<String> {
+fn deserialize_string<R: Read>(reader: &mut R, encoding: Encoding) -> io::Result<String> {
     // Deserialize the length of the string as a u64 value
-    let mut len_buf = [0; 8];
-    reader.read_exact(&mut len_buf)?;
-    let len = u64::from_le_bytes(len_buf);
+    let len = read_operand(reader, encoding)?;
 
     // Read the exact number of bytes specified by the length
     let mut buf = vec![0; len as usize];
@@ -72,113 +416,858 @@ fn deserialize_string<R: Read>(reader: &mut R) -> io::Result<String> {
 }
 
 impl Instruction {
-    fn serialize<W: Write>(&self, output: &mut W) -> io::Result<()> {
-        match &self {
-            Self::Push(a) => {
-                output.write(&[0])?;
-                output.write(&a.to_le_bytes())?;
-            }
-            Self::Out(a) => {
-                output.write(&[1])?;
-                output.write(&a.to_le_bytes())?;
-            }
-            Self::In() => {
-                output.write(&[2])?;
-            }
-            Self::OutStr(a) => {
-                output.write(&[3])?;
-                serialize_string(output, a)?;
-            }
-            Self::Copy(a) => {
-                output.write(&[4])?;
-                output.write(&a.to_le_bytes())?;
-            }
-            Self::Add(a, b) => {
-                output.write(&[5])?;
-                output.write(&a.to_le_bytes())?;
-                output.write(&b.to_le_bytes())?;
-            }
-            Self::Gt(a, b, c) => {
-                output.write(&[6])?;
-                output.write(&a.to_le_bytes())?;
-                output.write(&b.to_le_bytes())?;
-                output.write(&c.to_le_bytes())?;
+    // Fuses a sequence of instructions into a single Fused instruction.
+    // None if the sequence is empty or contains a branching or already
+    // fused instruction
+    pub fn fuse(instructions: Vec<Instruction>) -> Option<Instruction> {
+        if instructions.is_empty()
+            || instructions.iter().any(|i| {
+                i.jump_target().is_some() || matches!(i, Self::Fused(_) | Self::MultiRet(_))
+            })
+        {
+            return None;
+        }
+        Some(Self::Fused(instructions.into_boxed_slice()))
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::Push(_) => "PUSH",
+            Self::Out(_) => "OUT",
+            Self::In() => "IN",
+            Self::OutStr(_) => "OUTSTR",
+            Self::Copy(_) => "COPY",
+            Self::Add(_, _) => "ADD",
+            Self::Gt(_, _, _) => "GT",
+            Self::Eq(_, _, _) => "EQ",
+            Self::Jmp(_) => "JMP",
+            Self::Dec(_) => "DEC",
+            Self::Inc(_) => "INC",
+            Self::InByte() => "INBYTE",
+            Self::OutByte(_) => "OUTBYTE",
+            Self::OutStrNoNl(_) => "OUTSTRNONL",
+            Self::CallNative(_) => "CALLNATIVE",
+            Self::InEnv() => "INENV",
+            Self::GetArg(_) => "GETARG",
+            Self::ExitCode(_) => "EXITCODE",
+            Self::StrAlloc(_) => "STRALLOC",
+            Self::StrStore(_, _, _) => "STRSTORE",
+            Self::StrLoad(_, _) => "STRLOAD",
+            Self::StrLen(_) => "STRLEN",
+            Self::StrCat(_, _) => "STRCAT",
+            Self::StrFree(_) => "STRFREE",
+            Self::OutStrHandle(_) => "OUTSTRHANDLE",
+            Self::OutStrHandleLn(_) => "OUTSTRHANDLELN",
+            Self::StrCmp(_, _, _) => "STRCMP",
+            Self::StrToInt(_) => "STRTOINT",
+            Self::IntToStr(_) => "INTTOSTR",
+            Self::StrSlice(_, _, _) => "STRSLICE",
+            Self::MultiRet(_) => "MULTIRET",
+            Self::CondMove(_, _, _) => "CONDMOVE",
+            Self::Select(_, _, _) => "SELECT",
+            Self::TwoDup() => "2DUP",
+            Self::TwoDrop() => "2DROP",
+            Self::TwoSwap() => "2SWAP",
+            Self::OutCSV(_) => "OUTCSV",
+            Self::CmpPush(_, _) => "CMPPUSH",
+            Self::Crc32(_, _) => "CRC32",
+            Self::Hash64(_, _) => "HASH64",
+            Self::FdOpen(_, _) => "FDOPEN",
+            Self::FdRead(_, _) => "FDREAD",
+            Self::FdWrite(_, _) => "FDWRITE",
+            Self::FdClose(_) => "FDCLOSE",
+            Self::CopyRange(_, _) => "COPYRANGE",
+            Self::DropRange(_, _) => "DROPRANGE",
+            Self::SatAdd(_, _) => "SATADD",
+            Self::SatSub(_, _) => "SATSUB",
+            Self::SatInc(_) => "SATINC",
+            Self::SatDec(_) => "SATDEC",
+            Self::WrapAdd(_, _) => "WRAPADD",
+            Self::WrapSub(_, _) => "WRAPSUB",
+            Self::WrapInc(_) => "WRAPINC",
+            Self::WrapDec(_) => "WRAPDEC",
+            Self::Push128(_) => "PUSH128",
+            Self::Add128() => "ADD128",
+            Self::Mul128() => "MUL128",
+            Self::Compare128(_, _, _) => "COMPARE128",
+            Self::ClearStack() => "CLEARSTACK",
+            Self::ClearGlobals() => "CLEARGLOBALS",
+            Self::AbsJmpIfNeg(_, _) => "ABSJMPIFNEG",
+            Self::InPeek() => "INPEEK",
+            Self::FAdd(_, _) => "FADD",
+            Self::FSub(_, _) => "FSUB",
+            Self::FMul(_, _) => "FMUL",
+            Self::FDiv(_, _) => "FDIV",
+            Self::FGt(_, _, _) => "FGT",
+            Self::FLt(_, _, _) => "FLT",
+            Self::FEq(_, _, _) => "FEQ",
+            Self::Gcd(_, _) => "GCD",
+            Self::Lcm(_, _) => "LCM",
+            Self::OutFormatted(_, _) => "OUTFORMATTED",
+            Self::NotEq(_, _, _) => "NOTEQ",
+            Self::LookupTable(_, _, _, _) => "LOOKUPTABLE",
+            Self::Checksum(_, _) => "CHECKSUM",
+            Self::TypeAssert(_, _) => "TYPEASSERT",
+            Self::MeasureStart(_) => "MEASURESTART",
+            Self::MeasureEnd(_) => "MEASUREEND",
+            Self::InBytes(_, _) => "INBYTES",
+            Self::OutBytes(_, _) => "OUTBYTES",
+            Self::GtOrEq(_, _, _) => "GTOREQ",
+            Self::ModInverse(_, _) => "MODINVERSE",
+            Self::StoreGlobal(_, _) => "STOREGLOBAL",
+            Self::StoreGlobalPop(_) => "STOREGLOBALPOP",
+            Self::Fused(_) => "FUSED",
+        }
+    }
+
+    // Builds a numeric-operand instruction from its mnemonic, the inverse
+    // of mnemonic(). Returns None for unknown mnemonics or a wrong operand count
+    pub(crate) fn from_mnemonic(mnemonic: &str, operands: &[u64]) -> Option<Self> {
+        let instruction = match (mnemonic, operands) {
+            ("PUSH", &[a]) => Self::Push(a),
+            ("OUT", &[a]) => Self::Out(a),
+            ("IN", &[]) => Self::In(),
+            ("COPY", &[a]) => Self::Copy(a),
+            ("ADD", &[a, b]) => Self::Add(a, b),
+            ("GT", &[a, b, c]) => Self::Gt(a, b, c),
+            ("EQ", &[a, b, c]) => Self::Eq(a, b, c),
+            ("JMP", &[a]) => Self::Jmp(a),
+            ("DEC", &[a]) => Self::Dec(a),
+            ("INC", &[a]) => Self::Inc(a),
+            ("INBYTE", &[]) => Self::InByte(),
+            ("OUTBYTE", &[a]) => Self::OutByte(a),
+            ("CALLNATIVE", &[a]) => Self::CallNative(a),
+            ("INENV", &[]) => Self::InEnv(),
+            ("GETARG", &[a]) => Self::GetArg(a),
+            ("EXITCODE", &[a]) => Self::ExitCode(a),
+            ("STRALLOC", &[a]) => Self::StrAlloc(a),
+            ("STRSTORE", &[a, b, c]) => Self::StrStore(a, b, c),
+            ("STRLOAD", &[a, b]) => Self::StrLoad(a, b),
+            ("STRLEN", &[a]) => Self::StrLen(a),
+            ("STRCAT", &[a, b]) => Self::StrCat(a, b),
+            ("STRFREE", &[a]) => Self::StrFree(a),
+            ("OUTSTRHANDLE", &[a]) => Self::OutStrHandle(a),
+            ("OUTSTRHANDLELN", &[a]) => Self::OutStrHandleLn(a),
+            ("STRCMP", &[a, b, c]) => Self::StrCmp(a, b, c),
+            ("STRTOINT", &[a]) => Self::StrToInt(a),
+            ("INTTOSTR", &[a]) => Self::IntToStr(a),
+            ("STRSLICE", &[a, b, c]) => Self::StrSlice(a, b, c),
+            ("MULTIRET", &[a]) => Self::MultiRet(a),
+            ("CONDMOVE", &[a, b, c]) => Self::CondMove(a, b, c),
+            ("SELECT", &[a, b, c]) => Self::Select(a, b, c),
+            ("2DUP", &[]) => Self::TwoDup(),
+            ("2DROP", &[]) => Self::TwoDrop(),
+            ("2SWAP", &[]) => Self::TwoSwap(),
+            ("OUTCSV", &[a]) => Self::OutCSV(a),
+            ("CMPPUSH", &[a, b]) => Self::CmpPush(a, b),
+            ("CRC32", &[a, b]) => Self::Crc32(a, b),
+            ("HASH64", &[a, b]) => Self::Hash64(a, b),
+            ("FDOPEN", &[a, b]) => Self::FdOpen(a, b),
+            ("FDREAD", &[a, b]) => Self::FdRead(a, b),
+            ("FDWRITE", &[a, b]) => Self::FdWrite(a, b),
+            ("FDCLOSE", &[a]) => Self::FdClose(a),
+            ("COPYRANGE", &[a, b]) => Self::CopyRange(a, b),
+            ("DROPRANGE", &[a, b]) => Self::DropRange(a, b),
+            ("SATADD", &[a, b]) => Self::SatAdd(a, b),
+            ("SATSUB", &[a, b]) => Self::SatSub(a, b),
+            ("SATINC", &[a]) => Self::SatInc(a),
+            ("SATDEC", &[a]) => Self::SatDec(a),
+            ("WRAPADD", &[a, b]) => Self::WrapAdd(a, b),
+            ("WRAPSUB", &[a, b]) => Self::WrapSub(a, b),
+            ("WRAPINC", &[a]) => Self::WrapInc(a),
+            ("WRAPDEC", &[a]) => Self::WrapDec(a),
+            ("PUSH128", &[high, low]) => Self::Push128((high as u128) << 64 | low as u128),
+            ("ADD128", &[]) => Self::Add128(),
+            ("MUL128", &[]) => Self::Mul128(),
+            ("COMPARE128", &[a, b, c]) => Self::Compare128(a, b, c),
+            ("CLEARSTACK", &[]) => Self::ClearStack(),
+            ("CLEARGLOBALS", &[]) => Self::ClearGlobals(),
+            ("ABSJMPIFNEG", &[a, b]) => Self::AbsJmpIfNeg(a, b),
+            ("INPEEK", &[]) => Self::InPeek(),
+            ("FADD", &[a, b]) => Self::FAdd(a, b),
+            ("FSUB", &[a, b]) => Self::FSub(a, b),
+            ("FMUL", &[a, b]) => Self::FMul(a, b),
+            ("FDIV", &[a, b]) => Self::FDiv(a, b),
+            ("FGT", &[a, b, c]) => Self::FGt(a, b, c),
+            ("FLT", &[a, b, c]) => Self::FLt(a, b, c),
+            ("FEQ", &[a, b, c]) => Self::FEq(a, b, c),
+            ("GCD", &[a, b]) => Self::Gcd(a, b),
+            ("LCM", &[a, b]) => Self::Lcm(a, b),
+            ("OUTFORMATTED", &[a, b]) => Self::OutFormatted(a, b),
+            ("NOTEQ", &[a, b, c]) => Self::NotEq(a, b, c),
+            ("LOOKUPTABLE", &[a, b, c, d]) => Self::LookupTable(a, b, c, d),
+            ("CHECKSUM", &[a, b]) => Self::Checksum(a, b),
+            ("TYPEASSERT", &[a, b]) => Self::TypeAssert(a, b),
+            ("MEASURESTART", &[a]) => Self::MeasureStart(a),
+            ("MEASUREEND", &[a]) => Self::MeasureEnd(a),
+            ("INBYTES", &[a, b]) => Self::InBytes(a, b),
+            ("OUTBYTES", &[a, b]) => Self::OutBytes(a, b),
+            ("GTOREQ", &[a, b, c]) => Self::GtOrEq(a, b, c),
+            ("MODINVERSE", &[a, b]) => Self::ModInverse(a, b),
+            ("STOREGLOBAL", &[a, b]) => Self::StoreGlobal(a, b),
+            ("STOREGLOBALPOP", &[a]) => Self::StoreGlobalPop(a),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    // Numeric operands in the order they are serialized. Strings
+    // and the components of fused instructions are not included
+    pub fn operands(&self) -> Vec<u64> {
+        match *self {
+            Self::Push(a) => vec![a],
+            Self::Out(a) => vec![a],
+            Self::In() => vec![],
+            Self::Copy(a) => vec![a],
+            Self::Add(a, b) => vec![a, b],
+            Self::Gt(a, b, c) => vec![a, b, c],
+            Self::Eq(a, b, c) => vec![a, b, c],
+            Self::Jmp(a) => vec![a],
+            Self::Dec(a) => vec![a],
+            Self::Inc(a) => vec![a],
+            Self::InByte() => vec![],
+            Self::OutByte(a) => vec![a],
+            Self::CallNative(a) => vec![a],
+            Self::InEnv() => vec![],
+            Self::GetArg(a) => vec![a],
+            Self::ExitCode(a) => vec![a],
+            Self::StrAlloc(a) => vec![a],
+            Self::StrStore(a, b, c) => vec![a, b, c],
+            Self::StrLoad(a, b) => vec![a, b],
+            Self::StrLen(a) => vec![a],
+            Self::StrCat(a, b) => vec![a, b],
+            Self::StrFree(a) => vec![a],
+            Self::OutStrHandle(a) => vec![a],
+            Self::OutStrHandleLn(a) => vec![a],
+            Self::StrCmp(a, b, c) => vec![a, b, c],
+            Self::StrToInt(a) => vec![a],
+            Self::IntToStr(a) => vec![a],
+            Self::StrSlice(a, b, c) => vec![a, b, c],
+            Self::MultiRet(a) => vec![a],
+            Self::CondMove(a, b, c) => vec![a, b, c],
+            Self::Select(a, b, c) => vec![a, b, c],
+            Self::TwoDup() => vec![],
+            Self::TwoDrop() => vec![],
+            Self::TwoSwap() => vec![],
+            Self::OutCSV(a) => vec![a],
+            Self::CmpPush(a, b) => vec![a, b],
+            Self::Crc32(a, b) => vec![a, b],
+            Self::Hash64(a, b) => vec![a, b],
+            Self::FdOpen(a, b) => vec![a, b],
+            Self::FdRead(a, b) => vec![a, b],
+            Self::FdWrite(a, b) => vec![a, b],
+            Self::FdClose(a) => vec![a],
+            Self::CopyRange(a, b) => vec![a, b],
+            Self::DropRange(a, b) => vec![a, b],
+            Self::SatAdd(a, b) | Self::SatSub(a, b) => vec![a, b],
+            Self::SatInc(a) | Self::SatDec(a) => vec![a],
+            Self::WrapAdd(a, b) | Self::WrapSub(a, b) => vec![a, b],
+            Self::WrapInc(a) | Self::WrapDec(a) => vec![a],
+            Self::Push128(value) => vec![(value >> 64) as u64, value as u64],
+            Self::Add128() | Self::Mul128() => vec![],
+            Self::Compare128(a, b, c) => vec![a, b, c],
+            Self::ClearStack() | Self::ClearGlobals() => vec![],
+            Self::AbsJmpIfNeg(a, b) => vec![a, b],
+            Self::InPeek() => vec![],
+            Self::FAdd(a, b) | Self::FSub(a, b) | Self::FMul(a, b) | Self::FDiv(a, b) => {
+                vec![a, b]
             }
-            Self::Eq(a, b, c) => {
-                output.write(&[7])?;
-                output.write(&a.to_le_bytes())?;
-                output.write(&b.to_le_bytes())?;
-                output.write(&c.to_le_bytes())?;
+            Self::FGt(a, b, c) | Self::FLt(a, b, c) | Self::FEq(a, b, c) => vec![a, b, c],
+            Self::Gcd(a, b) | Self::Lcm(a, b) => vec![a, b],
+            Self::OutFormatted(a, b) => vec![a, b],
+            Self::NotEq(a, b, c) => vec![a, b, c],
+            Self::LookupTable(a, b, c, d) => vec![a, b, c, d],
+            Self::Checksum(a, b) => vec![a, b],
+            Self::TypeAssert(a, b) => vec![a, b],
+            Self::MeasureStart(a) => vec![a],
+            Self::MeasureEnd(a) => vec![a],
+            Self::InBytes(a, b) => vec![a, b],
+            Self::OutBytes(a, b) => vec![a, b],
+            Self::GtOrEq(a, b, c) => vec![a, b, c],
+            Self::ModInverse(a, b) => vec![a, b],
+            Self::StoreGlobal(a, b) => vec![a, b],
+            Self::StoreGlobalPop(a) => vec![a],
+            Self::OutStr(_) | Self::OutStrNoNl(_) | Self::Fused(_) => vec![],
+        }
+    }
+
+    // The same instruction with its numeric operands replaced, in the order
+    // returned by operands()
+    pub fn with_operands(&self, operands: &[u64]) -> Result<Self, VmError> {
+        let expected = self.operands().len();
+        if operands.len() != expected {
+            return Err(VmError::OperandCountMismatch {
+                instruction: self.mnemonic(),
+                expected,
+                found: operands.len(),
+            });
+        }
+        match self {
+            Self::OutStr(_) | Self::OutStrNoNl(_) | Self::Fused(_) => Ok(self.clone()),
+            _ => Ok(Self::from_mnemonic(self.mnemonic(), operands)
+                .expect("every numeric instruction has a mnemonic")),
+        }
+    }
+
+    // Whether the instruction only works on the state of the machine.
+    // Impure instructions touch the input, output, files, environment or
+    // host, so passes must not remove or reorder them
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Self::Fused(instructions) => instructions.iter().all(Self::is_pure),
+            Self::InEnv() | Self::GetArg(_) | Self::CallNative(_) | Self::ExitCode(_) => false,
+            _ => !self.is_io() && !self.is_file_op(),
+        }
+    }
+
+    // Number of values the instruction removes from the stack and number it
+    // pushes, wherever the removed values are. Instructions that only read
+    // their operands remove nothing. (usize::MAX, usize::MAX) means the
+    // effect depends on the values on the stack or on the host
+    pub fn stack_effect(&self) -> (usize, usize) {
+        const UNKNOWN: (usize, usize) = (usize::MAX, usize::MAX);
+        match self {
+            Self::Push(_) | Self::Copy(_) | Self::In() | Self::InByte() | Self::InPeek() => (0, 1),
+            Self::CmpPush(_, _) | Self::Checksum(_, _) | Self::Hash64(_, _) => (0, 1),
+            Self::Crc32(_, _) => (0, 1),
+            Self::StrAlloc(_) | Self::StrLoad(_, _) | Self::StrLen(_) | Self::StrCat(_, _) => {
+                (0, 1)
             }
-            Self::Jmp(a) => {
-                output.write(&[8])?;
-                output.write(&a.to_le_bytes())?;
+            Self::StrToInt(_) | Self::IntToStr(_) | Self::StrSlice(_, _, _) => (0, 1),
+            Self::FdOpen(_, _) | Self::FdRead(_, _) | Self::InBytes(_, _) => (0, 1),
+            Self::Add(_, _)
+            | Self::SatAdd(_, _)
+            | Self::SatSub(_, _)
+            | Self::WrapAdd(_, _)
+            | Self::WrapSub(_, _)
+            | Self::FAdd(_, _)
+            | Self::FSub(_, _)
+            | Self::FMul(_, _)
+            | Self::FDiv(_, _)
+            | Self::Gcd(_, _)
+            | Self::Lcm(_, _)
+            | Self::ModInverse(_, _) => (2, 1),
+            // Select removes each distinct operand once
+            Self::Select(a, b, cond) => {
+                let mut operands = [a, b, cond];
+                operands.sort_unstable();
+                let distinct = 1 + operands.windows(2).filter(|w| w[0] != w[1]).count();
+                (distinct, 1)
             }
-            Self::Dec(a) => {
-                output.write(&[9])?;
-                output.write(&a.to_le_bytes())?;
+            Self::MultiRet(_) | Self::StoreGlobalPop(_) => (1, 0),
+            Self::CopyRange(_, count) => (0, *count as usize),
+            Self::DropRange(_, count) => (*count as usize, 0),
+            Self::TwoDup() => (0, 2),
+            Self::TwoDrop() => (2, 0),
+            Self::TwoSwap() => (4, 4),
+            Self::Push128(_) => (0, 2),
+            Self::Add128() | Self::Mul128() => (4, 2),
+            Self::Fused(instructions) => {
+                instructions.iter().map(Self::stack_effect).fold(
+                    (0, 0),
+                    |(pops, pushes), effect| {
+                        if (pops, pushes) == UNKNOWN || effect == UNKNOWN {
+                            return UNKNOWN;
+                        }
+                        // Values the next component removes beyond what the
+                        // previous ones pushed come from below them
+                        (
+                            pops.saturating_add(effect.0.saturating_sub(pushes)),
+                            effect.1.saturating_add(pushes.saturating_sub(effect.0)),
+                        )
+                    },
+                )
             }
-            Self::Inc(a) => {
-                output.write(&[10])?;
-                output.write(&a.to_le_bytes())?;
+            // The native decides, GetArg and InEnv push a string of unknown
+            // length, OutFormatted removes its arguments and ClearStack all
+            Self::CallNative(_)
+            | Self::GetArg(_)
+            | Self::InEnv()
+            | Self::OutFormatted(_, _)
+            | Self::ClearStack() => UNKNOWN,
+            Self::Out(_)
+            | Self::OutStr(_)
+            | Self::OutStrNoNl(_)
+            | Self::OutByte(_)
+            | Self::OutStrHandle(_)
+            | Self::OutStrHandleLn(_)
+            | Self::OutCSV(_)
+            | Self::Gt(_, _, _)
+            | Self::Eq(_, _, _)
+            | Self::NotEq(_, _, _)
+            | Self::GtOrEq(_, _, _)
+            | Self::FGt(_, _, _)
+            | Self::FLt(_, _, _)
+            | Self::FEq(_, _, _)
+            | Self::StrCmp(_, _, _)
+            | Self::Compare128(_, _, _)
+            | Self::AbsJmpIfNeg(_, _)
+            | Self::MeasureStart(_)
+            | Self::MeasureEnd(_)
+            | Self::OutBytes(_, _)
+            | Self::StoreGlobal(_, _)
+            | Self::LookupTable(_, _, _, _)
+            | Self::Jmp(_)
+            | Self::Inc(_)
+            | Self::Dec(_)
+            | Self::SatInc(_)
+            | Self::SatDec(_)
+            | Self::WrapInc(_)
+            | Self::WrapDec(_)
+            | Self::CondMove(_, _, _)
+            | Self::StrStore(_, _, _)
+            | Self::StrFree(_)
+            | Self::FdWrite(_, _)
+            | Self::FdClose(_)
+            | Self::ExitCode(_)
+            | Self::TypeAssert(_, _)
+            | Self::ClearGlobals() => (0, 0),
+        }
+    }
+
+    // Whether the instruction treats stack values as the bits of f64
+    // values. A type-checking pass can use it to warn when a slot is used
+    // by both float and integer instructions
+    pub fn is_float_op(&self) -> bool {
+        matches!(
+            self,
+            Self::FAdd(_, _)
+                | Self::FSub(_, _)
+                | Self::FMul(_, _)
+                | Self::FDiv(_, _)
+                | Self::FGt(_, _, _)
+                | Self::FLt(_, _, _)
+                | Self::FEq(_, _, _)
+        )
+    }
+
+    // Whether the instruction reads the input or writes the output
+    fn is_io(&self) -> bool {
+        matches!(
+            self,
+            Self::In()
+                | Self::InByte()
+                | Self::InPeek()
+                | Self::Out(_)
+                | Self::OutStr(_)
+                | Self::OutStrNoNl(_)
+                | Self::OutByte(_)
+                | Self::OutStrHandle(_)
+                | Self::OutStrHandleLn(_)
+                | Self::OutCSV(_)
+                | Self::OutFormatted(_, _)
+                | Self::InBytes(_, _)
+                | Self::OutBytes(_, _)
+        )
+    }
+
+    // Whether the instruction throws away the state of the machine
+    fn is_state_reset(&self) -> bool {
+        matches!(self, Self::ClearStack() | Self::ClearGlobals())
+    }
+
+    // Whether the instruction works on the file descriptor table
+    fn is_file_op(&self) -> bool {
+        matches!(
+            self,
+            Self::FdOpen(_, _) | Self::FdRead(_, _) | Self::FdWrite(_, _) | Self::FdClose(_)
+        )
+    }
+
+    // Whether the result of the instruction depends on more than the
+    // program and its input. Timestamp, Rand and Sleep belong here once
+    // the machine has them
+    fn is_nondeterministic(&self) -> bool {
+        matches!(self, Self::InEnv() | Self::GetArg(_) | Self::FdOpen(_, _))
+    }
+
+    // Whether the instruction ends a basic block: it jumps, returns or
+    // stops the program, even if it may also fall through
+    pub fn is_terminator(&self) -> bool {
+        self.jump_target().is_some() || matches!(self, Self::MultiRet(_) | Self::ExitCode(_))
+    }
+
+    // The instruction a branch jumps to when taken. MultiRet jumps to a pc
+    // taken from the stack, so it has none
+    pub fn jump_target(&self) -> Option<u64> {
+        match self {
+            Self::Gt(_, _, pc)
+            | Self::Eq(_, _, pc)
+            | Self::Jmp(pc)
+            | Self::StrCmp(_, _, pc)
+            | Self::Compare128(_, _, pc)
+            | Self::AbsJmpIfNeg(_, pc)
+            | Self::FGt(_, _, pc)
+            | Self::FLt(_, _, pc)
+            | Self::FEq(_, _, pc)
+            | Self::NotEq(_, _, pc)
+            | Self::GtOrEq(_, _, pc)
+            | Self::LookupTable(_, _, _, pc) => Some(*pc),
+            _ => None,
+        }
+    }
+
+    pub fn jump_target_mut(&mut self) -> Option<&mut u64> {
+        match self {
+            Self::Gt(_, _, pc)
+            | Self::Eq(_, _, pc)
+            | Self::Jmp(pc)
+            | Self::StrCmp(_, _, pc)
+            | Self::Compare128(_, _, pc)
+            | Self::AbsJmpIfNeg(_, pc)
+            | Self::FGt(_, _, pc)
+            | Self::FLt(_, _, pc)
+            | Self::FEq(_, _, pc)
+            | Self::NotEq(_, _, pc)
+            | Self::GtOrEq(_, _, pc)
+            | Self::LookupTable(_, _, _, pc) => Some(pc),
+            _ => None,
+        }
+    }
+
+    // Like Display, but a jump target with a name in label_map is written
+    // as a label reference the assembler accepts, such as JMP @loop
+    pub fn display_with_labels(&self, label_map: &HashMap<u64, String>) -> String {
+        let Some(label) = self.jump_target().and_then(|target| label_map.get(&target)) else {
+            return self.to_string();
+        };
+        let mut text = self.mnemonic().to_string();
+        // The target is always the last operand
+        let operands = self.operands();
+        for operand in &operands[..operands.len() - 1] {
+            text.push_str(&format!(" {}", operand));
+        }
+        text.push_str(&format!(" @{}", label));
+        text
+    }
+
+    // The bytes the instruction is serialized to in the given encoding
+    pub fn to_bytes(&self, encoding: Encoding) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.serialize(&mut bytes, encoding)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    // The byte an encoded instruction starts with, the same in every
+    // encoding except for the compact short form, which also holds a size
+    // code. Fused instructions have none, they are encoded as their
+    // components
+    pub fn opcode(&self) -> Option<u8> {
+        let opcode = match self {
+            Self::Push(_) => 0,
+            Self::Out(_) => 1,
+            Self::In() => 2,
+            Self::OutStr(_) => 3,
+            Self::Copy(_) => 4,
+            Self::Add(_, _) => 5,
+            Self::Gt(_, _, _) => 6,
+            Self::Eq(_, _, _) => 7,
+            Self::Jmp(_) => 8,
+            Self::Dec(_) => 9,
+            Self::Inc(_) => 10,
+            Self::InByte() => 11,
+            Self::OutByte(_) => 12,
+            Self::OutStrNoNl(_) => 72,
+            Self::CallNative(_) => 80,
+            Self::InEnv() => 14,
+            Self::GetArg(_) => 81,
+            Self::ExitCode(_) => 82,
+            Self::StrAlloc(_) => 83,
+            Self::StrStore(_, _, _) => 84,
+            Self::StrLoad(_, _) => 85,
+            Self::StrLen(_) => 86,
+            Self::StrCat(_, _) => 87,
+            Self::StrFree(_) => 88,
+            Self::OutStrHandle(_) => 15,
+            Self::OutStrHandleLn(_) => 16,
+            Self::StrCmp(_, _, _) => 89,
+            Self::StrToInt(_) => 17,
+            Self::IntToStr(_) => 18,
+            Self::StrSlice(_, _, _) => 90,
+            Self::MultiRet(_) => 19,
+            Self::CondMove(_, _, _) => 91,
+            Self::Select(_, _, _) => 92,
+            Self::TwoDup() => 93,
+            Self::TwoDrop() => 94,
+            Self::TwoSwap() => 95,
+            Self::OutCSV(_) => 96,
+            Self::CmpPush(_, _) => 97,
+            Self::Crc32(_, _) => 98,
+            Self::Hash64(_, _) => 99,
+            Self::FdOpen(_, _) => 100,
+            Self::FdRead(_, _) => 101,
+            Self::FdWrite(_, _) => 102,
+            Self::FdClose(_) => 103,
+            Self::CopyRange(_, _) => 104,
+            Self::DropRange(_, _) => 105,
+            Self::SatAdd(_, _) => 106,
+            Self::SatSub(_, _) => 107,
+            Self::SatInc(_) => 20,
+            Self::SatDec(_) => 21,
+            Self::WrapAdd(_, _) => 22,
+            Self::WrapSub(_, _) => 23,
+            Self::WrapInc(_) => 24,
+            Self::WrapDec(_) => 25,
+            Self::Push128(_) => 108,
+            Self::Add128() => 109,
+            Self::Mul128() => 110,
+            Self::Compare128(_, _, _) => 111,
+            Self::ClearStack() => 112,
+            Self::ClearGlobals() => 113,
+            Self::AbsJmpIfNeg(_, _) => 114,
+            Self::InPeek() => 115,
+            Self::FAdd(_, _) => 116,
+            Self::FSub(_, _) => 117,
+            Self::FMul(_, _) => 118,
+            Self::FDiv(_, _) => 119,
+            Self::FGt(_, _, _) => 120,
+            Self::FLt(_, _, _) => 121,
+            Self::FEq(_, _, _) => 122,
+            Self::Gcd(_, _) => 123,
+            Self::Lcm(_, _) => 124,
+            Self::OutFormatted(_, _) => 125,
+            Self::NotEq(_, _, _) => 126,
+            Self::LookupTable(_, _, _, _) => 26,
+            Self::Checksum(_, _) => 127,
+            Self::TypeAssert(_, _) => 27,
+            Self::MeasureStart(_) => 128,
+            Self::MeasureEnd(_) => 129,
+            Self::InBytes(_, _) => 28,
+            Self::OutBytes(_, _) => 29,
+            Self::GtOrEq(_, _, _) => 130,
+            Self::ModInverse(_, _) => 131,
+            Self::StoreGlobal(_, _) => 132,
+            Self::StoreGlobalPop(_) => 133,
+            Self::Fused(_) => return None,
+        };
+        Some(opcode)
+    }
+
+    // Whether the compact encoding writes the operands with size codes. The
+    // operands of string instructions and of those with more operands than
+    // the sizes byte has room for are varints instead
+    fn has_compact_operands(&self) -> bool {
+        !matches!(self, Self::OutStr(_) | Self::OutStrNoNl(_)) && self.operands().len() < 4
+    }
+
+    fn serialize<W: Write>(&self, output: &mut W, encoding: Encoding) -> io::Result<()> {
+        let opcode = match self {
+            Self::Fused(instructions) => {
+                return instructions
+                    .iter()
+                    .try_for_each(|instruction| instruction.serialize(output, encoding));
             }
-            Self::InByte() => {
-                output.write(&[11])?;
+            _ => self
+                .opcode()
+                .expect("only Fused instructions have no opcode"),
+        };
+        if encoding == Encoding::Compact && self.has_compact_operands() {
+            return write_compact(output, opcode, &self.operands());
+        }
+        output.write_all(&[opcode])?;
+        match self {
+            Self::OutStr(string) | Self::OutStrNoNl(string) => {
+                serialize_string(output, string, encoding)
             }
-            Self::OutByte(a) => {
-                output.write(&[12])?;
-                output.write(&a.to_le_bytes())?;
+            _ => {
+                for operand in self.operands() {
+                    write_operand(output, operand, encoding)?;
+                }
+                Ok(())
             }
         }
-        Ok(())
     }
 
-    fn deserialize<R: Read>(input: &mut R) -> io::Result<Self> {
+    // Reads the rest of an instruction in the compact encoding starting with
+    // byte. Returns None when its operands are varints, as in version 2
+    fn deserialize_compact<R: Read>(byte: u8, input: &mut R) -> io::Result<Option<Self>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid compact operands");
+        let short_form = compact_short_form(byte);
+        let opcode = short_form.map_or(byte, |(opcode, _)| opcode);
+
+        // The opcode followed by zeros decodes to an instruction of the same
+        // kind, which tells how its operands are written
+        let opcode_byte = [opcode];
+        let mut zeros = (&opcode_byte[..]).chain(io::repeat(0));
+        let template = Self::deserialize(&mut zeros, Encoding::Fixed(Endianness::Little))?;
+        if !template.has_compact_operands() {
+            return match short_form {
+                Some(_) => Err(invalid()),
+                None => Ok(None),
+            };
+        }
+        let operands = match short_form {
+            Some((_, code)) => vec![read_compact_operand(input, code)?],
+            None if template.operands().is_empty() => return Ok(Some(template)),
+            None => read_compact_operands(input)?,
+        };
+        if operands.len() != template.operands().len() {
+            return Err(invalid());
+        }
+
+        // Re-encode the operands as version 1 and decode that
+        let mut fixed = vec![opcode];
+        for operand in operands {
+            fixed.extend(operand.to_le_bytes());
+        }
+        Self::deserialize(&mut &fixed[..], Encoding::Fixed(Endianness::Little)).map(Some)
+    }
+
+    fn deserialize<R: Read>(input: &mut R, encoding: Encoding) -> io::Result<Self> {
         let mut tag = [0];
         input.read_exact(&mut tag)?;
+        if encoding == Encoding::Compact {
+            if let Some(instruction) = Self::deserialize_compact(tag[0], input)? {
+                return Ok(instruction);
+            }
+        }
         match tag[0] {
-            0 => deserialize_variant!(Push, input, a),
-            1 => deserialize_variant!(Out, input, a),
+            0 => deserialize_variant!(Push, input, encoding, a),
+            1 => deserialize_variant!(Out, input, encoding, a),
             2 => Ok(Self::In()),
-            3 => Ok(Self::OutStr(deserialize_string(input)?)),
-            4 => deserialize_variant!(Copy, input, a),
-            5 => deserialize_variant!(Add, input, a, b),
-            6 => deserialize_variant!(Gt, input, a, b, c),
-            7 => deserialize_variant!(Eq, input, a, b, c),
-            8 => deserialize_variant!(Jmp, input, a),
-            9 => deserialize_variant!(Dec, input, a),
-            10 => deserialize_variant!(Inc, input, a),
+            3 => Ok(Self::OutStr(deserialize_string(input, encoding)?)),
+            4 => deserialize_variant!(Copy, input, encoding, a),
+            5 => deserialize_variant!(Add, input, encoding, a, b),
+            6 => deserialize_variant!(Gt, input, encoding, a, b, c),
+            7 => deserialize_variant!(Eq, input, encoding, a, b, c),
+            8 => deserialize_variant!(Jmp, input, encoding, a),
+            9 => deserialize_variant!(Dec, input, encoding, a),
+            10 => deserialize_variant!(Inc, input, encoding, a),
             11 => Ok(Self::InByte()),
-            12 => deserialize_variant!(OutByte, input, a),
+            12 => deserialize_variant!(OutByte, input, encoding, a),
+            72 => Ok(Self::OutStrNoNl(deserialize_string(input, encoding)?)),
+            80 => deserialize_variant!(CallNative, input, encoding, a),
+            14 => Ok(Self::InEnv()),
+            81 => deserialize_variant!(GetArg, input, encoding, a),
+            82 => deserialize_variant!(ExitCode, input, encoding, a),
+            83 => deserialize_variant!(StrAlloc, input, encoding, a),
+            84 => deserialize_variant!(StrStore, input, encoding, a, b, c),
+            85 => deserialize_variant!(StrLoad, input, encoding, a, b),
+            86 => deserialize_variant!(StrLen, input, encoding, a),
+            87 => deserialize_variant!(StrCat, input, encoding, a, b),
+            88 => deserialize_variant!(StrFree, input, encoding, a),
+            15 => deserialize_variant!(OutStrHandle, input, encoding, a),
+            16 => deserialize_variant!(OutStrHandleLn, input, encoding, a),
+            89 => deserialize_variant!(StrCmp, input, encoding, a, b, c),
+            17 => deserialize_variant!(StrToInt, input, encoding, a),
+            18 => deserialize_variant!(IntToStr, input, encoding, a),
+            90 => deserialize_variant!(StrSlice, input, encoding, a, b, c),
+            19 => deserialize_variant!(MultiRet, input, encoding, a),
+            91 => deserialize_variant!(CondMove, input, encoding, a, b, c),
+            92 => deserialize_variant!(Select, input, encoding, a, b, c),
+            93 => Ok(Self::TwoDup()),
+            94 => Ok(Self::TwoDrop()),
+            95 => Ok(Self::TwoSwap()),
+            96 => deserialize_variant!(OutCSV, input, encoding, a),
+            97 => deserialize_variant!(CmpPush, input, encoding, a, b),
+            98 => deserialize_variant!(Crc32, input, encoding, a, b),
+            99 => deserialize_variant!(Hash64, input, encoding, a, b),
+            100 => deserialize_variant!(FdOpen, input, encoding, a, b),
+            101 => deserialize_variant!(FdRead, input, encoding, a, b),
+            102 => deserialize_variant!(FdWrite, input, encoding, a, b),
+            103 => deserialize_variant!(FdClose, input, encoding, a),
+            104 => deserialize_variant!(CopyRange, input, encoding, a, b),
+            105 => deserialize_variant!(DropRange, input, encoding, a, b),
+            106 => deserialize_variant!(SatAdd, input, encoding, a, b),
+            107 => deserialize_variant!(SatSub, input, encoding, a, b),
+            20 => deserialize_variant!(SatInc, input, encoding, a),
+            21 => deserialize_variant!(SatDec, input, encoding, a),
+            22 => deserialize_variant!(WrapAdd, input, encoding, a, b),
+            23 => deserialize_variant!(WrapSub, input, encoding, a, b),
+            24 => deserialize_variant!(WrapInc, input, encoding, a),
+            25 => deserialize_variant!(WrapDec, input, encoding, a),
+            108 => {
+                let high = read_operand(input, encoding)?;
+                let low = read_operand(input, encoding)?;
+                Ok(Self::Push128((high as u128) << 64 | low as u128))
+            }
+            109 => Ok(Self::Add128()),
+            110 => Ok(Self::Mul128()),
+            111 => deserialize_variant!(Compare128, input, encoding, a, b, c),
+            112 => Ok(Self::ClearStack()),
+            113 => Ok(Self::ClearGlobals()),
+            114 => deserialize_variant!(AbsJmpIfNeg, input, encoding, a, b),
+            115 => Ok(Self::InPeek()),
+            116 => deserialize_variant!(FAdd, input, encoding, a, b),
+            117 => deserialize_variant!(FSub, input, encoding, a, b),
+            118 => deserialize_variant!(FMul, input, encoding, a, b),
+            119 => deserialize_variant!(FDiv, input, encoding, a, b),
+            120 => deserialize_variant!(FGt, input, encoding, a, b, c),
+            121 => deserialize_variant!(FLt, input, encoding, a, b, c),
+            122 => deserialize_variant!(FEq, input, encoding, a, b, c),
+            123 => deserialize_variant!(Gcd, input, encoding, a, b),
+            124 => deserialize_variant!(Lcm, input, encoding, a, b),
+            125 => deserialize_variant!(OutFormatted, input, encoding, a, b),
+            126 => deserialize_variant!(NotEq, input, encoding, a, b, c),
+            26 => deserialize_variant!(LookupTable, input, encoding, a, b, c, d),
+            127 => deserialize_variant!(Checksum, input, encoding, a, b),
+            27 => deserialize_variant!(TypeAssert, input, encoding, a, b),
+            128 => deserialize_variant!(MeasureStart, input, encoding, a),
+            129 => deserialize_variant!(MeasureEnd, input, encoding, a),
+            28 => deserialize_variant!(InBytes, input, encoding, a, b),
+            29 => deserialize_variant!(OutBytes, input, encoding, a, b),
+            130 => deserialize_variant!(GtOrEq, input, encoding, a, b, c),
+            131 => deserialize_variant!(ModInverse, input, encoding, a, b),
+            132 => deserialize_variant!(StoreGlobal, input, encoding, a, b),
+            133 => deserialize_variant!(StoreGlobalPop, input, encoding, a),
             _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid tag")),
         }
     }
 
+    // Executes the instruction and returns the pc of the next instruction to
+    // run: the target of a taken branch, otherwise the current pc plus one.
+    // execute never changes machine.pc itself, the caller stores the result.
+    // The binaries run programs through Machine, so this is the only pc semantics
     fn execute<W: Write, R: BufRead>(
         &self,
         machine: &mut Machine,
         input: &mut R,
         output: &mut W,
-    ) -> io::Result<usize> {
+    ) -> Result<u64, VmError> {
+        if self.is_io() && !machine.config.sandbox_policy.allow_io
+            || self.is_file_op() && !machine.config.sandbox_policy.allow_file_ops
+            || self.is_state_reset() && !machine.config.sandbox_policy.allow_state_reset
+        {
+            return Err(VmError::SandboxViolation {
+                instruction: self.mnemonic(),
+            });
+        }
+        if machine.config.deterministic_mode && self.is_nondeterministic() {
+            return Err(VmError::NonDeterministicInstructionForbidden {
+                instruction: self.mnemonic(),
+            });
+        }
+
+        let newline = machine.config.newline_mode.as_str();
         match self {
             Instruction::Push(value) => {
                 machine.stack.push(*value);
             }
             Instruction::In() => {
-                let input_str = input.lines().next().unwrap()?;
-                let value: u64 = input_str.parse().unwrap();
+                let input_str = input
+                    .lines()
+                    .next()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+                let input_str = input_str.trim_end_matches('\r');
+                let value: u64 = input_str.parse().map_err(|_| VmError::ParseError {
+                    raw_input: input_str.to_string(),
+                    expected_type: "u64",
+                    instruction: "IN",
+                })?;
 
                 machine.stack.push(value);
             }
             Instruction::Out(pointer) => {
-                writeln!(
+                write!(
                     output,
-                    "{}",
-                    machine.stack[machine.stack.len() - 1 - *pointer as usize]
+                    "{}{}",
+                    machine.stack[machine.stack.len() - 1 - *pointer as usize],
+                    newline
                 )?;
             }
             Instruction::OutStr(value) => {
-                writeln!(output, "{}", value)?;
+                write!(output, "{}{}", value, newline)?;
             }
             Instruction::Add(l, r) => {
                 let l = machine.stack.len() - 1 - *l as usize;
@@ -199,21 +1288,18 @@ impl Instruction {
                 let l_value = machine.stack[machine.stack.len() - 1 - *l as usize];
                 let r_value = machine.stack[machine.stack.len() - 1 - *r as usize];
                 if l_value > r_value {
-                    machine.pc = *pc;
-                    return Ok(0);
+                    return Ok(*pc);
                 }
             }
             Instruction::Eq(l, r, pc) => {
                 let l_value = machine.stack[machine.stack.len() - 1 - *l as usize];
                 let r_value = machine.stack[machine.stack.len() - 1 - *r as usize];
                 if l_value == r_value {
-                    machine.pc = *pc;
-                    return Ok(0);
+                    return Ok(*pc);
                 }
             }
             Instruction::Jmp(value) => {
-                machine.pc = *value;
-                return Ok(0);
+                return Ok(*value);
             }
             Instruction::Dec(pointer) => {
                 let index = machine.stack.len() - 1 - *pointer as usize;
@@ -225,106 +1311,1424 @@ impl Instruction {
             }
             Instruction::InByte() => {
                 let mut buf = [0];
-                input.read_exact(&mut buf)?;
-                let value = u8::from_le_bytes(buf);
-                machine.stack.push(value as u64);
+                let value = match input.read_exact(&mut buf) {
+                    Ok(()) => u8::from_le_bytes(buf) as u64,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        machine.config.eof_sentinel
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                machine.stack.push(value);
             }
             Instruction::OutByte(pointer) => {
-                let value: u8 =
-                    u8::try_from(machine.stack[machine.stack.len() - 1 - *pointer as usize])
-                        .unwrap();
-                output.write(&[value])?;
+                let value = machine.stack_value(*pointer, "OUTBYTE")?;
+                let value = u8::try_from(value).map_err(|_| VmError::InvalidValue { value })?;
+                output.write_all(&[value])?;
+            }
+            Instruction::OutStrNoNl(value) => {
+                write!(output, "{}", value)?;
+            }
+            Instruction::CallNative(index) => {
+                let mut native = machine
+                    .natives
+                    .remove(index)
+                    .ok_or(VmError::UnknownNative { index: *index })?;
+                let result = match &mut native {
+                    Native::Plain(f) => f(machine),
+                    Native::Io(_) if !machine.config.sandbox_policy.allow_io => {
+                        Err(VmError::SandboxViolation {
+                            instruction: "CALLNATIVE",
+                        })
+                    }
+                    Native::Io(f) => f(machine, input, output),
+                };
+                // Keep the function unless it registered a replacement for itself
+                machine.natives.entry(*index).or_insert(native);
+                result?;
+            }
+            Instruction::InEnv() => {
+                if !machine.config.sandbox_policy.allow_system_calls {
+                    return Err(VmError::SandboxViolation {
+                        instruction: "INENV",
+                    });
+                }
+                let pos = machine.stack.pop().ok_or(VmError::StackUnderflow {
+                    instruction: "INENV",
+                })?;
+                let name = machine.read_byte_string(pos, "INENV")?;
+                let value = String::from_utf8(name)
+                    .ok()
+                    .and_then(|name| std::env::var(name).ok())
+                    .unwrap_or_default();
+                machine.push_byte_string(value.as_bytes());
+            }
+            Instruction::GetArg(pointer) => {
+                let n = machine.stack[machine.stack_index(*pointer, "GETARG")?];
+                let arg = usize::try_from(n)
+                    .ok()
+                    .and_then(|n| machine.args.get(n))
+                    .cloned()
+                    .unwrap_or_default();
+                machine.push_byte_string(arg.as_bytes());
+            }
+            Instruction::ExitCode(pointer) => {
+                let code = machine.stack[machine.stack_index(*pointer, "EXITCODE")?];
+                let code =
+                    i32::try_from(code).map_err(|_| VmError::InvalidValue { value: code })?;
+                machine.exit_code = Some(code);
+            }
+            Instruction::StrAlloc(len) => {
+                let len = machine.stack_value(*len, "STRALLOC")?;
+                // Checked before allocating, since the quota is otherwise only
+                // enforced after the instruction ran
+                if let Some(max) = machine.config.max_heap_bytes {
+                    if (machine.heap_bytes() as u64).saturating_add(len) > max as u64 {
+                        return Err(VmError::QuotaExceeded { resource: "heap" });
+                    }
+                }
+                let mut bytes = Vec::new();
+                usize::try_from(len)
+                    .ok()
+                    .and_then(|len| bytes.try_reserve_exact(len).ok())
+                    .ok_or(VmError::AllocationFailed { bytes: len })?;
+                bytes.resize(len as usize, 0);
+                let handle = machine.strings.alloc(bytes);
+                machine.stack.push(handle);
+            }
+            Instruction::StrStore(handle, offset, byte) => {
+                let handle = machine.stack_value(*handle, "STRSTORE")?;
+                let offset = machine.stack_value(*offset, "STRSTORE")?;
+                let value = machine.stack_value(*byte, "STRSTORE")?;
+                let byte = u8::try_from(value).map_err(|_| VmError::InvalidValue { value })?;
+                let string = machine.strings.get_mut(handle)?;
+                let len = string.len() as u64;
+                *string
+                    .get_mut(offset as usize)
+                    .ok_or(VmError::IndexOutOfBounds { index: offset, len })? = byte;
+            }
+            Instruction::StrLoad(handle, offset) => {
+                let handle = machine.stack_value(*handle, "STRLOAD")?;
+                let offset = machine.stack_value(*offset, "STRLOAD")?;
+                let string = machine.strings.get(handle)?;
+                let byte = *string
+                    .get(offset as usize)
+                    .ok_or(VmError::IndexOutOfBounds {
+                        index: offset,
+                        len: string.len() as u64,
+                    })?;
+                machine.stack.push(byte as u64);
+            }
+            Instruction::StrLen(handle) => {
+                let handle = machine.stack_value(*handle, "STRLEN")?;
+                let len = machine.strings.get(handle)?.len();
+                machine.stack.push(len as u64);
+            }
+            Instruction::StrCat(first, second) => {
+                let first = machine.stack_value(*first, "STRCAT")?;
+                let second = machine.stack_value(*second, "STRCAT")?;
+                let concatenated =
+                    [machine.strings.get(first)?, machine.strings.get(second)?].concat();
+                let handle = machine.strings.alloc(concatenated);
+                machine.stack.push(handle);
+            }
+            Instruction::StrFree(handle) => {
+                let handle = machine.stack_value(*handle, "STRFREE")?;
+                machine.strings.free(handle)?;
+            }
+            Instruction::OutStrHandle(handle) => {
+                let handle = machine.stack_value(*handle, "OUTSTRHANDLE")?;
+                output.write_all(machine.strings.get(handle)?)?;
+            }
+            Instruction::OutStrHandleLn(handle) => {
+                let handle = machine.stack_value(*handle, "OUTSTRHANDLELN")?;
+                output.write_all(machine.strings.get(handle)?)?;
+                output.write_all(newline.as_bytes())?;
+            }
+            Instruction::StrCmp(first, second, pc) => {
+                let first = machine.stack_value(*first, "STRCMP")?;
+                let second = machine.stack_value(*second, "STRCMP")?;
+                if machine.strings.get(first)? == machine.strings.get(second)? {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::StrToInt(handle) => {
+                let handle = machine.stack_value(*handle, "STRTOINT")?;
+                let text = String::from_utf8_lossy(machine.strings.get(handle)?);
+                let value = text.parse().map_err(|_| VmError::ParseError {
+                    raw_input: text.to_string(),
+                    expected_type: "u64",
+                    instruction: "STRTOINT",
+                })?;
+                machine.stack.push(value);
+            }
+            Instruction::IntToStr(pointer) => {
+                let value = machine.stack_value(*pointer, "INTTOSTR")?;
+                let handle = machine.strings.alloc(value.to_string().into_bytes());
+                machine.stack.push(handle);
+            }
+            Instruction::StrSlice(handle, start, len) => {
+                let handle = machine.stack_value(*handle, "STRSLICE")?;
+                let start = machine.stack_value(*start, "STRSLICE")?;
+                let len = machine.stack_value(*len, "STRSLICE")?;
+                let string = machine.strings.get(handle)?;
+                let end = start.saturating_add(len);
+                let slice = string
+                    .get(start as usize..end as usize)
+                    .ok_or(VmError::IndexOutOfBounds {
+                        index: end,
+                        len: string.len() as u64,
+                    })?
+                    .to_vec();
+                let handle = machine.strings.alloc(slice);
+                machine.stack.push(handle);
+            }
+            Instruction::MultiRet(n) => {
+                let index = machine.stack_index(*n, "MULTIRET").map_err(|_| {
+                    VmError::CallingConventionViolation {
+                        expected: n.saturating_add(1),
+                        found: machine.stack.len() as u64,
+                    }
+                })?;
+                return Ok(machine.stack.remove(index));
+            }
+            Instruction::CondMove(src, dst, cond) => {
+                let value = machine.stack_value(*src, "CONDMOVE")?;
+                let dst = machine.stack_index(*dst, "CONDMOVE")?;
+                if machine.stack_value(*cond, "CONDMOVE")? != 0 {
+                    machine.stack[dst] = value;
+                }
+            }
+            Instruction::Select(a, b, cond) => {
+                let mut indices = [
+                    machine.stack_index(*a, "SELECT")?,
+                    machine.stack_index(*b, "SELECT")?,
+                    machine.stack_index(*cond, "SELECT")?,
+                ];
+                let value = if machine.stack[indices[2]] != 0 {
+                    machine.stack[indices[0]]
+                } else {
+                    machine.stack[indices[1]]
+                };
+                // Remove from the top down so the remaining indices stay valid
+                indices.sort_unstable_by(|l, r| r.cmp(l));
+                let mut previous = None;
+                for index in indices {
+                    if previous != Some(index) {
+                        machine.stack.remove(index);
+                    }
+                    previous = Some(index);
+                }
+                machine.stack.push(value);
+            }
+            Instruction::TwoDup() => {
+                let index = machine.stack_index(1, "2DUP")?;
+                machine.stack.extend_from_within(index..);
+            }
+            Instruction::TwoDrop() => {
+                let index = machine.stack_index(1, "2DROP")?;
+                machine.stack.truncate(index);
+            }
+            Instruction::TwoSwap() => {
+                let index = machine.stack_index(3, "2SWAP")?;
+                machine.stack[index..].rotate_left(2);
+            }
+            Instruction::OutCSV(pointer) => {
+                let index = machine.stack_index(*pointer, "OUTCSV")?;
+                let start = index.checked_sub(machine.stack[index] as usize).ok_or(
+                    VmError::StackUnderflow {
+                        instruction: "OUTCSV",
+                    },
+                )?;
+                let line = machine.stack[start..index]
+                    .iter()
+                    .rev()
+                    .map(u64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                write!(output, "{}{}", line, newline)?;
+            }
+            Instruction::CmpPush(l, r) => {
+                let l_value = machine.stack_value(*l, "CMPPUSH")?;
+                let r_value = machine.stack_value(*r, "CMPPUSH")?;
+                machine.stack.push(l_value.cmp(&r_value) as i64 as u64);
+            }
+            #[cfg(feature = "crc")]
+            Instruction::Crc32(last, count) => {
+                let count = machine.stack_value(*count, "CRC32")?;
+                let bytes = machine.stack_bytes(*last, count, "CRC32")?;
+                machine.stack.push(checksum::crc32(&bytes) as u64);
+            }
+            #[cfg(not(feature = "crc"))]
+            Instruction::Crc32(_, _) => {
+                return Err(VmError::FeatureDisabled {
+                    instruction: "CRC32",
+                    feature: "crc",
+                });
+            }
+            Instruction::Hash64(last, count) => {
+                let count = machine.stack_value(*count, "HASH64")?;
+                let bytes = machine.stack_bytes(*last, count, "HASH64")?;
+                let hash = machine.config.hash_algorithm.hash(&bytes);
+                machine.stack.push(hash);
+            }
+            Instruction::FdOpen(path, mode) => {
+                let path = machine.stack_value(*path, "FDOPEN")?;
+                let mode = machine.stack_value(*mode, "FDOPEN")?;
+                let mut options = OpenOptions::new();
+                match mode {
+                    0 => options.read(true),
+                    1 => options.write(true).create(true).truncate(true),
+                    2 => options.append(true).create(true),
+                    value => return Err(VmError::InvalidValue { value }),
+                };
+                let path = String::from_utf8_lossy(machine.strings.get(path)?).into_owned();
+                let fd = match options.open(path) {
+                    Ok(file) => machine.insert_file(file),
+                    Err(_) => u64::MAX,
+                };
+                machine.stack.push(fd);
+            }
+            Instruction::FdRead(fd, count) => {
+                let fd = machine.stack_value(*fd, "FDREAD")?;
+                let count = machine.stack_value(*count, "FDREAD")?;
+                let mut bytes = Vec::new();
+                machine.file(fd)?.take(count).read_to_end(&mut bytes)?;
+                let handle = machine.strings.alloc(bytes);
+                machine.stack.push(handle);
+            }
+            Instruction::FdWrite(fd, handle) => {
+                let fd = machine.stack_value(*fd, "FDWRITE")?;
+                let handle = machine.stack_value(*handle, "FDWRITE")?;
+                let bytes = machine.strings.get(handle)?.to_vec();
+                machine.file(fd)?.write_all(&bytes)?;
+            }
+            Instruction::FdClose(fd) => {
+                let fd = machine.stack_value(*fd, "FDCLOSE")?;
+                machine.file(fd)?;
+                machine.file_table[fd as usize] = None;
+            }
+            Instruction::CopyRange(pos, count) => {
+                if *count > 0 {
+                    let top = machine.stack_index(*pos, "COPYRANGE")?;
+                    let bottom = pos.checked_add(count - 1).ok_or(VmError::StackUnderflow {
+                        instruction: "COPYRANGE",
+                    })?;
+                    let bottom = machine.stack_index(bottom, "COPYRANGE")?;
+                    let values = machine.stack[bottom..=top].to_vec();
+                    machine.stack.extend(values.into_iter().rev());
+                }
+            }
+            Instruction::DropRange(pos, count) => {
+                if *count > 0 {
+                    let top = machine.stack_index(*pos, "DROPRANGE")?;
+                    let bottom = pos.checked_add(count - 1).ok_or(VmError::StackUnderflow {
+                        instruction: "DROPRANGE",
+                    })?;
+                    let bottom = machine.stack_index(bottom, "DROPRANGE")?;
+                    machine.stack.drain(bottom..=top);
+                }
+            }
+            Instruction::SatAdd(l, r) => {
+                let (l_value, r_value) = machine.take_operands(*l, *r, "SATADD")?;
+                machine.stack.push(l_value.saturating_add(r_value));
+            }
+            Instruction::SatSub(l, r) => {
+                let (l_value, r_value) = machine.take_operands(*l, *r, "SATSUB")?;
+                machine.stack.push(l_value.saturating_sub(r_value));
+            }
+            Instruction::SatInc(pointer) => {
+                let index = machine.stack_index(*pointer, "SATINC")?;
+                machine.stack[index] = machine.stack[index].saturating_add(1);
+            }
+            Instruction::SatDec(pointer) => {
+                let index = machine.stack_index(*pointer, "SATDEC")?;
+                machine.stack[index] = machine.stack[index].saturating_sub(1);
+            }
+            Instruction::WrapAdd(l, r) => {
+                let (l_value, r_value) = machine.take_operands(*l, *r, "WRAPADD")?;
+                machine.stack.push(l_value.wrapping_add(r_value));
+            }
+            Instruction::WrapSub(l, r) => {
+                let (l_value, r_value) = machine.take_operands(*l, *r, "WRAPSUB")?;
+                machine.stack.push(l_value.wrapping_sub(r_value));
+            }
+            Instruction::WrapInc(pointer) => {
+                let index = machine.stack_index(*pointer, "WRAPINC")?;
+                machine.stack[index] = machine.stack[index].wrapping_add(1);
+            }
+            Instruction::WrapDec(pointer) => {
+                let index = machine.stack_index(*pointer, "WRAPDEC")?;
+                machine.stack[index] = machine.stack[index].wrapping_sub(1);
+            }
+            Instruction::Push128(value) => {
+                machine.push_u128(*value);
+            }
+            Instruction::Add128() => {
+                let r_value = machine.pop_u128("ADD128")?;
+                let l_value = machine.pop_u128("ADD128")?;
+                machine.push_u128(l_value.wrapping_add(r_value));
+            }
+            Instruction::Mul128() => {
+                let r_value = machine.pop_u128("MUL128")?;
+                let l_value = machine.pop_u128("MUL128")?;
+                machine.push_u128(l_value.wrapping_mul(r_value));
+            }
+            Instruction::Compare128(l, r, pc) => {
+                let l_value = machine.stack_u128(*l, "COMPARE128")?;
+                let r_value = machine.stack_u128(*r, "COMPARE128")?;
+                if l_value > r_value {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::ClearStack() => {
+                machine.stack.clear();
+            }
+            Instruction::ClearGlobals() => {
+                machine.globals.fill(0);
+            }
+            Instruction::AbsJmpIfNeg(pointer, pc) => {
+                if (machine.stack_value(*pointer, "ABSJMPIFNEG")? as i64) < 0 {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::InPeek() => {
+                let value = match input.fill_buf()?.first() {
+                    Some(&byte) => byte as u64,
+                    None => machine.config.eof_sentinel,
+                };
+                machine.stack.push(value);
+            }
+            Instruction::FAdd(l, r) => {
+                let (l_value, r_value) = machine.take_f64_operands(*l, *r, "FADD")?;
+                machine.stack.push((l_value + r_value).to_bits());
+            }
+            Instruction::FSub(l, r) => {
+                let (l_value, r_value) = machine.take_f64_operands(*l, *r, "FSUB")?;
+                machine.stack.push((l_value - r_value).to_bits());
+            }
+            Instruction::FMul(l, r) => {
+                let (l_value, r_value) = machine.take_f64_operands(*l, *r, "FMUL")?;
+                machine.stack.push((l_value * r_value).to_bits());
+            }
+            Instruction::FDiv(l, r) => {
+                let (l_value, r_value) = machine.take_f64_operands(*l, *r, "FDIV")?;
+                machine.stack.push((l_value / r_value).to_bits());
+            }
+            Instruction::FGt(l, r, pc) => {
+                if machine.stack_f64(*l, "FGT")? > machine.stack_f64(*r, "FGT")? {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::FLt(l, r, pc) => {
+                if machine.stack_f64(*l, "FLT")? < machine.stack_f64(*r, "FLT")? {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::FEq(l, r, pc) => {
+                if machine.stack_f64(*l, "FEQ")? == machine.stack_f64(*r, "FEQ")? {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::Gcd(l, r) => {
+                let (l_value, r_value) = machine.take_operands(*l, *r, "GCD")?;
+                machine.stack.push(gcd(l_value, r_value));
+            }
+            Instruction::Lcm(l, r) => {
+                let (l_value, r_value) = machine.take_operands(*l, *r, "LCM")?;
+                let lcm = match gcd(l_value, r_value) {
+                    0 => 0,
+                    divisor => (l_value / divisor)
+                        .checked_mul(r_value)
+                        .ok_or(VmError::ArithmeticOverflow { instruction: "LCM" })?,
+                };
+                machine.stack.push(lcm);
+            }
+            Instruction::OutFormatted(format, count) => {
+                let (handle, count) = machine.take_operands(*format, *count, "OUTFORMATTED")?;
+                let first = (machine.stack.len() as u64).checked_sub(count).ok_or(
+                    VmError::StackUnderflow {
+                        instruction: "OUTFORMATTED",
+                    },
+                )? as usize;
+                let formatted = format::format_output(
+                    machine.strings.get(handle)?,
+                    &machine.stack[first..],
+                    &machine.strings,
+                )?;
+                machine.stack.truncate(first);
+                output.write_all(&formatted)?;
+            }
+            Instruction::NotEq(l, r, pc) => {
+                if machine.stack_value(*l, "NOTEQ")? != machine.stack_value(*r, "NOTEQ")? {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::LookupTable(key, start, size, default_pc) => {
+                let key = machine.stack_value(*key, "LOOKUPTABLE")?;
+                let entry = |index| {
+                    lookup_table_entry(&machine.code, machine.pc, *start, index)
+                        .map_err(|error| VmError::InvalidProgram(vec![error]))
+                };
+                let (mut low, mut high) = (0, *size);
+                while low < high {
+                    let middle = low + (high - low) / 2;
+                    let (entry_key, target) = entry(middle)?;
+                    match entry_key.cmp(&key) {
+                        std::cmp::Ordering::Less => low = middle + 1,
+                        std::cmp::Ordering::Greater => high = middle,
+                        std::cmp::Ordering::Equal => return Ok(target),
+                    }
+                }
+                return Ok(*default_pc);
+            }
+            Instruction::Checksum(pos, count) => {
+                let mut checksum = 0;
+                if *count > 0 {
+                    let top = machine.stack_index(*pos, "CHECKSUM")?;
+                    let bottom = pos.checked_add(count - 1).ok_or(VmError::StackUnderflow {
+                        instruction: "CHECKSUM",
+                    })?;
+                    let bottom = machine.stack_index(bottom, "CHECKSUM")?;
+                    checksum = machine.stack[bottom..=top].iter().fold(0, |acc, v| acc ^ v);
+                }
+                machine.stack.push(checksum);
+            }
+            Instruction::TypeAssert(pos, type_tag) => {
+                let value = machine.stack_value(*pos, "TYPEASSERT")?;
+                let (expected, max) = match *type_tag {
+                    TYPE_U8 => ("u8", u8::MAX as u64),
+                    TYPE_U16 => ("u16", u16::MAX as u64),
+                    TYPE_U32 => ("u32", u32::MAX as u64),
+                    TYPE_U64 => ("u64", u64::MAX),
+                    _ => return Err(VmError::InvalidValue { value: *type_tag }),
+                };
+                if value > max {
+                    return Err(VmError::TypeError { value, expected });
+                }
+            }
+            Instruction::MeasureStart(pos) => {
+                let id = machine.stack_value(*pos, "MEASURESTART")?;
+                if !cfg!(feature = "measure_noop") {
+                    machine.measure_starts.insert(id, Instant::now());
+                }
+            }
+            Instruction::MeasureEnd(pos) => {
+                let id = machine.stack_value(*pos, "MEASUREEND")?;
+                if let Some(start) = machine.measure_starts.remove(&id) {
+                    *machine.timers.entry(id).or_default() += start.elapsed();
+                }
+            }
+            Instruction::InBytes(count_pos, base_pos) => {
+                let count = machine.stack_value(*count_pos, "INBYTES")?;
+                let base = machine.stack_value(*base_pos, "INBYTES")?;
+                // Checked before reading so a bad range consumes no input
+                for offset in 0..count {
+                    machine.heap.read(base.wrapping_add(offset))?;
+                }
+                let mut bytes = Vec::new();
+                input.take(count).read_to_end(&mut bytes)?;
+                for (address, byte) in (base..).zip(&bytes) {
+                    machine.heap.write(address, *byte as u64)?;
+                }
+                machine.stack.push(bytes.len() as u64);
+            }
+            Instruction::OutBytes(base_pos, count_pos) => {
+                let base = machine.stack_value(*base_pos, "OUTBYTES")?;
+                let count = machine.stack_value(*count_pos, "OUTBYTES")?;
+                let bytes = (0..count)
+                    .map(|offset| {
+                        let value = machine.heap.read(base.wrapping_add(offset))?;
+                        u8::try_from(value).map_err(|_| VmError::InvalidValue { value })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                output.write_all(&bytes)?;
+            }
+            Instruction::GtOrEq(l, r, pc) => {
+                if machine.stack_value(*l, "GTOREQ")? >= machine.stack_value(*r, "GTOREQ")? {
+                    return Ok(*pc);
+                }
+            }
+            Instruction::ModInverse(a, m) => {
+                let (a_value, m_value) = machine.take_operands(*a, *m, "MODINVERSE")?;
+                let inverse = mod_inverse(a_value, m_value).ok_or(VmError::ArithmeticError {
+                    reason: "ModularInverseDoesNotExist",
+                })?;
+                machine.stack.push(inverse);
+            }
+            Instruction::StoreGlobal(index, pos) => {
+                let value = machine.stack_value(*pos, "STOREGLOBAL")?;
+                *machine.global_mut(*index)? = value;
+            }
+            Instruction::StoreGlobalPop(index) => {
+                let value = machine.stack_value(0, "STOREGLOBALPOP")?;
+                *machine.global_mut(*index)? = value;
+                machine.stack.pop();
+            }
+            Instruction::Fused(instructions) => {
+                // The components never branch, so their next pc is ignored
+                for instruction in instructions.iter() {
+                    instruction.execute(machine, input, output)?;
+                }
             }
         };
 
-        machine.pc += 1;
+        Ok(machine.pc + 1)
+    }
+}
 
-        Ok(0)
+// Key and target of entry index of the table starting at start, used by
+// the LookupTable at pc
+fn lookup_table_entry(
+    code: &[Instruction],
+    pc: u64,
+    start: u64,
+    index: u64,
+) -> Result<(u64, u64), ValidationError> {
+    let error = |reason| ValidationError::InvalidLookupTable { pc, reason };
+    let entry = index
+        .checked_mul(2)
+        .and_then(|offset| start.checked_add(offset))
+        .and_then(|entry| Some(entry..entry.checked_add(2)?))
+        .and_then(|entry| code.get(entry.start as usize..entry.end as usize))
+        .ok_or(error("table extends past the end of the program"))?;
+    match entry {
+        [Instruction::Push(key), Instruction::Jmp(target)] => Ok((*key, *target)),
+        _ => Err(error("entry is not a PUSH followed by a JMP")),
     }
 }
 
-pub struct Machine {
-    pub code: Vec<Instruction>,
-    pub stack: Vec<u64>,
-    pub pc: u64,
+// Greatest common divisor by the Euclidean algorithm
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
 }
 
-impl Machine {
-    pub fn run<W: Write, R: Read>(&mut self, input: &mut R, output: &mut W) -> io::Result<usize> {
-        let mut input = BufReader::new(input);
-        loop {
-            match self.code.get(self.pc as usize) {
-                Some(instruction) => {
-                    // println!("{:?}", instruction);
-                    match instruction.clone().execute(self, &mut input, output) {
-                        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                        Err(e) => return Err(e),
-                        Ok(_) => (),
+// The x in [0, m) with a * x = 1 modulo m, found by the extended Euclidean
+// algorithm, or None if a and m are not coprime
+fn mod_inverse(a: u64, m: u64) -> Option<u64> {
+    if m == 0 {
+        return None;
+    }
+    let (mut old_r, mut r) = ((a % m) as i128, m as i128);
+    let (mut old_s, mut s) = (1i128, 0i128);
+    while r != 0 {
+        let quotient = old_r / r;
+        (old_r, r) = (r, old_r - quotient * r);
+        (old_s, s) = (s, old_s - quotient * s);
+    }
+    (old_r == 1).then(|| old_s.rem_euclid(m as i128) as u64)
+}
+
+// Formats the instruction as a line of assembler source. A fused
+// instruction is written as its components, one per line
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutStr(string) | Self::OutStrNoNl(string) => {
+                write!(f, "{} \"", self.mnemonic())?;
+                for c in string.chars() {
+                    match c {
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '"' => f.write_str("\\\"")?,
+                        c => write!(f, "{}", c)?,
                     }
                 }
-                None => break,
-            };
-            // println!("{:?}", self.stack);
-            // println!("{}", self.pc);
+                f.write_str("\"")
+            }
+            Self::Fused(instructions) => {
+                for (i, instruction) in instructions.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", instruction)?;
+                }
+                Ok(())
+            }
+            _ => {
+                f.write_str(self.mnemonic())?;
+                for operand in self.operands() {
+                    write!(f, " {}", operand)?;
+                }
+                Ok(())
+            }
         }
-
-        Ok(0)
     }
 }
 
-pub fn serialize_code<W: Write>(instructions: &[Instruction], writer: &mut W) -> io::Result<()> {
-    for instr in instructions {
-        instr.serialize(writer)?;
-    }
-    Ok(())
+// Everything in a machine except its native functions and open files.
+// Unlike Machine it is Send and Sync, so forks can be made on other threads
+#[derive(Clone)]
+struct MachineState {
+    code: Vec<Instruction>,
+    stack: Vec<u64>,
+    pc: u64,
+    heap: HeapAllocator,
+    strings: StringHeap,
+    globals: Vec<u64>,
+    config: VmConfig,
+    usage: ResourceUsage,
+    args: Vec<String>,
+    exit_code: Option<i32>,
+    consumed_cost: u64,
+    io_suspended: Option<IoType>,
+    io_resumed: bool,
+    tags: Vec<TypeTag>,
 }
 
-pub fn deserialize_code<R: Read>(reader: &mut R) -> io::Result<Vec<Instruction>> {
-    let mut instructions = Vec::new();
-    loop {
-        match Instruction::deserialize(reader) {
-            Ok(instr) => instructions.push(instr),
-            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-            Err(e) => return Err(e),
+impl MachineState {
+    // Fails if the machine has open files. A duplicated handle would share
+    // its position with the original, so the copy would not be independent
+    fn of(machine: &Machine) -> Result<Self, VmError> {
+        if machine.file_table.iter().any(Option::is_some) {
+            return Err(VmError::ForkWithOpenFiles);
+        }
+        Ok(MachineState {
+            code: machine.code.clone(),
+            stack: machine.stack.clone(),
+            pc: machine.pc,
+            heap: machine.heap.clone(),
+            strings: machine.strings.clone(),
+            globals: machine.globals.clone(),
+            config: machine.config.clone(),
+            usage: machine.usage,
+            args: machine.args.clone(),
+            exit_code: machine.exit_code,
+            consumed_cost: machine.consumed_cost,
+            io_suspended: machine.io_suspended,
+            io_resumed: machine.io_resumed,
+            tags: machine.tags.clone(),
+        })
+    }
+
+    fn into_machine(self) -> Machine {
+        Machine {
+            code: self.code,
+            stack: self.stack,
+            pc: self.pc,
+            heap: self.heap,
+            strings: self.strings,
+            globals: self.globals,
+            file_table: Vec::new(),
+            config: self.config,
+            usage: self.usage,
+            natives: HashMap::new(),
+            args: self.args,
+            exit_code: self.exit_code,
+            consumed_cost: self.consumed_cost,
+            io_suspended: self.io_suspended,
+            io_resumed: self.io_resumed,
+            timing: HashMap::new(),
+            history: VecDeque::new(),
+            timers: HashMap::new(),
+            measure_starts: HashMap::new(),
+            tags: self.tags,
         }
     }
-    Ok(instructions)
 }
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
 
-    fn test_instruction_execution(
-        instruction: Instruction,
-        machine: &mut Machine,
-        expected_machine: Machine,
-        input_data: &[u8],
-        expected_output: &[u8],
-    ) {
-        let mut input = Cursor::new(input_data);
-        let mut output = Vec::new();
-        instruction
-            .execute(machine, &mut input, &mut output)
-            .unwrap();
+// A host function callable from bytecode through CallNative
+pub trait NativeFn: FnMut(&mut Machine) -> Result<(), VmError> {}
 
-        assert_eq!(machine.stack, expected_machine.stack);
-        assert_eq!(machine.pc, expected_machine.pc);
-        assert_eq!(output, expected_output);
-    }
+impl<F: FnMut(&mut Machine) -> Result<(), VmError>> NativeFn for F {}
 
-    #[test]
-    fn test_push() {
-        let instruction = Instruction::Push(42);
+// A host function that also gets the input and output of the machine
+pub trait NativeIoFn:
+    FnMut(&mut Machine, &mut dyn BufRead, &mut dyn Write) -> Result<(), VmError>
+{
+}
+
+impl<F: FnMut(&mut Machine, &mut dyn BufRead, &mut dyn Write) -> Result<(), VmError>> NativeIoFn
+    for F
+{
+}
+
+enum Native {
+    Plain(Box<dyn NativeFn>),
+    Io(Box<dyn NativeIoFn>),
+}
+
+// Why a run of the machine stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunResult {
+    // The program ran past its last instruction or the input was exhausted
+    Finished,
+    // The program executed ExitCode, the host should exit with the code
+    ProcessExit(i32),
+    // In I/O break mode, the instruction at instruction_index is waiting
+    // for the host to call resume_io
+    SuspendedAtIo {
+        instruction_index: u64,
+        io_type: IoType,
+    },
+}
+
+// The kind of I/O instruction a machine in I/O break mode stopped at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoType {
+    In,
+    InByte,
+    Out,
+}
+
+// Summary of a run returned by the convenience runners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionStats {
+    pub result: RunResult,
+    pub instructions_executed: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Default)]
+pub struct Machine {
+    pub code: Vec<Instruction>,
+    pub stack: Vec<u64>,
+    pub pc: u64,
+    pub heap: HeapAllocator,
+    pub strings: StringHeap,
+    // Global variables. They live outside the stack and keep their
+    // values until ClearGlobals or the host resets them
+    pub globals: Vec<u64>,
+    // Files opened by FdOpen, indexed by descriptor. Closed slots are None
+    pub file_table: Vec<Option<File>>,
+    pub config: VmConfig,
+    usage: ResourceUsage,
+    natives: HashMap<u64, Native>,
+    args: Vec<String>,
+    exit_code: Option<i32>,
+    consumed_cost: u64,
+    // The I/O instruction at pc the machine is suspended at
+    io_suspended: Option<IoType>,
+    // Lets the I/O instruction at pc run once without suspending
+    io_resumed: bool,
+    // Time spent per mnemonic, see take_timing
+    timing: HashMap<&'static str, Duration>,
+    // State before each recent instruction, oldest first, see step_back
+    history: VecDeque<Snapshot>,
+    // Time spent per MeasureStart and MeasureEnd section, see take_timers
+    timers: HashMap<u64, Duration>,
+    // When each running section was started
+    measure_starts: HashMap<u64, Instant>,
+    // Parallel to the stack when VmConfig::type_tagging is set
+    tags: Vec<TypeTag>,
+}
+
+impl Machine {
+    pub fn new(code: Vec<Instruction>) -> Self {
+        Self::with_config(code, VmConfig::default())
+    }
+
+    pub fn with_config(code: Vec<Instruction>, config: VmConfig) -> Self {
+        Machine {
+            code,
+            globals: vec![0; config.initial_globals],
+            config,
+            ..Default::default()
+        }
+    }
+
+    // Sets the values the program starts with, the last one on top. This
+    // is how the host passes arguments to the program, e.g.
+    // Machine::new(code).with_initial_stack(vec![a, b]).build()?
+    // leaves b at position 0 and a at position 1
+    pub fn with_initial_stack(mut self, stack: Vec<u64>) -> Self {
+        self.stack = stack;
+        self
+    }
+
+    // Checks the initial state against the configured limits so an
+    // oversized argument list fails before the program runs
+    pub fn build(self) -> Result<Self, VmError> {
+        if matches!(self.config.max_stack_depth, Some(max) if self.stack.len() > max) {
+            return Err(VmError::QuotaExceeded { resource: "stack" });
+        }
+        Ok(self)
+    }
+
+    // The instruction at pc, or None past the end of the program
+    pub fn instruction_at(&self, pc: u64) -> Option<&Instruction> {
+        self.code.get(usize::try_from(pc).ok()?)
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.code.len()
+    }
+
+    // Replaces the instruction at pc. The replacement must encode to the
+    // same number of bytes as the original in every encoding, so the
+    // byte offsets of the following instructions stay valid, and must not
+    // jump past the end of the program
+    pub fn patch(&mut self, pc: u64, instruction: Instruction) -> Result<(), VmError> {
+        let Some(old) = self.instruction_at(pc) else {
+            return Err(VmError::IncompatiblePatch {
+                pc,
+                reason: "pc is past the end of the program",
+            });
+        };
+        for encoding in [
+            Encoding::Fixed(Endianness::Little),
+            Encoding::Varint,
+            Encoding::Compact,
+        ] {
+            if old.to_bytes(encoding).len() != instruction.to_bytes(encoding).len() {
+                return Err(VmError::IncompatiblePatch {
+                    pc,
+                    reason: "the encoded size differs",
+                });
+            }
+        }
+        if matches!(instruction.jump_target(), Some(target) if target > self.code.len() as u64) {
+            return Err(VmError::IncompatiblePatch {
+                pc,
+                reason: "the jump target is past the end of the program",
+            });
+        }
+        self.code[pc as usize] = instruction;
+        Ok(())
+    }
+
+    // Checks the program before it runs. Every jump must target an
+    // instruction or the end of the program, where it stops like after
+    // running past the last instruction
+    pub fn validate(&self) -> Result<(), VmError> {
+        let mut errors: Vec<_> = self
+            .code
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, instruction)| match instruction.jump_target() {
+                Some(target) if target > self.code.len() as u64 => {
+                    Some(ValidationError::InvalidJumpTarget {
+                        pc: pc as u64,
+                        target,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        for (pc, instruction) in self.code.iter().enumerate() {
+            if let Instruction::LookupTable(_, start, size, _) = instruction {
+                if let Err(error) = self.validate_lookup_table(pc as u64, *start, *size) {
+                    errors.push(error);
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(VmError::InvalidProgram(errors))
+        }
+    }
+
+    fn validate_lookup_table(&self, pc: u64, start: u64, size: u64) -> Result<(), ValidationError> {
+        let mut previous = None;
+        for index in 0..size {
+            let (key, _) = lookup_table_entry(&self.code, pc, start, index)?;
+            if previous.is_some_and(|previous| previous >= key) {
+                return Err(ValidationError::InvalidLookupTable {
+                    pc,
+                    reason: "keys are not strictly ascending",
+                });
+            }
+            previous = Some(key);
+        }
+        Ok(())
+    }
+
+    pub fn run<VW: VmWrite, VR: VmRead>(
+        &mut self,
+        input: &mut VR,
+        output: &mut VW,
+    ) -> Result<RunResult, VmError> {
+        self.validate()?;
+        let mut input = BufReader::new(VmIo(input));
+        let mut output = VmIo(output);
+        while self.step(&mut input, &mut output)? {
+            // println!("{:?}", self.stack);
+            // println!("{}", self.pc);
+        }
+
+        Ok(self.finish())
+    }
+
+    // Runs the program on the standard input and output
+    pub fn run_stdio(&mut self) -> Result<ExecutionStats, VmError> {
+        let stats = self.run_with_stats(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+        io::stdout().flush()?;
+        Ok(stats)
+    }
+
+    // Runs the program on the given input and returns what it wrote.
+    // Output that is not valid UTF-8 is converted lossily
+    pub fn run_with_strings(&mut self, input: &str) -> Result<(ExecutionStats, String), VmError> {
+        let mut output = Vec::new();
+        let stats = self.run_with_stats(&mut input.as_bytes(), &mut output)?;
+        Ok((stats, String::from_utf8_lossy(&output).into_owned()))
+    }
+
+    fn run_with_stats<W: Write, R: Read>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<ExecutionStats, VmError> {
+        self.validate()?;
+        let start = Instant::now();
+        let mut input = BufReader::new(input);
+        let mut instructions_executed = 0;
+        loop {
+            let running = self.code.get(self.pc as usize).is_some();
+            let still_running = self.step(&mut input, output)?;
+            instructions_executed += running as u64;
+            if !still_running {
+                break;
+            }
+        }
+        Ok(ExecutionStats {
+            result: self.finish(),
+            instructions_executed,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    // Absolute index of the value at relative position pos
+    fn stack_index(&self, pos: u64, instruction: &'static str) -> Result<usize, VmError> {
+        pos.checked_add(1)
+            .and_then(|values| (self.stack.len() as u64).checked_sub(values))
+            .map(|index| index as usize)
+            .ok_or(VmError::StackUnderflow { instruction })
+    }
+
+    // Reads a byte string stored as its bytes followed by its length,
+    // with the length at relative position pos
+    fn read_byte_string(&self, pos: u64, instruction: &'static str) -> Result<Vec<u8>, VmError> {
+        let len_index = self.stack_index(pos, instruction)?;
+        let start = len_index
+            .checked_sub(self.stack[len_index] as usize)
+            .ok_or(VmError::StackUnderflow { instruction })?;
+        self.stack[start..len_index]
+            .iter()
+            .map(|&value| u8::try_from(value).map_err(|_| VmError::InvalidValue { value }))
+            .collect()
+    }
+
+    // Pushes the bytes followed by their count
+    fn push_byte_string(&mut self, bytes: &[u8]) {
+        self.stack.extend(bytes.iter().map(|&b| b as u64));
+        self.stack.push(bytes.len() as u64);
+    }
+
+    // Sets the program arguments read by GetArg
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    // Value at relative position pos
+    fn stack_value(&self, pos: u64, instruction: &'static str) -> Result<u64, VmError> {
+        Ok(self.stack[self.stack_index(pos, instruction)?])
+    }
+
+    fn global_mut(&mut self, index: u64) -> Result<&mut u64, VmError> {
+        self.globals
+            .get_mut(index as usize)
+            .ok_or(VmError::InvalidGlobal { index })
+    }
+
+    // Value at relative position pos, reinterpreted as f64 bits
+    fn stack_f64(&self, pos: u64, instruction: &'static str) -> Result<f64, VmError> {
+        Ok(f64::from_bits(self.stack_value(pos, instruction)?))
+    }
+
+    // Removes the values at relative positions l and r the way Add does
+    // and returns them in that order
+    fn take_operands(
+        &mut self,
+        l: u64,
+        r: u64,
+        instruction: &'static str,
+    ) -> Result<(u64, u64), VmError> {
+        let l = self.stack_index(l, instruction)?;
+        let r = self.stack_index(r, instruction)?;
+        let l_value = self.stack[l];
+        let r_value = self.stack[r];
+        let correct = (r > l) as usize;
+        self.stack.remove(l);
+        self.stack.remove(r - correct);
+        Ok((l_value, r_value))
+    }
+
+    // Like take_operands, reinterpreting both values as f64 bits
+    fn take_f64_operands(
+        &mut self,
+        l: u64,
+        r: u64,
+        instruction: &'static str,
+    ) -> Result<(f64, f64), VmError> {
+        let (l_value, r_value) = self.take_operands(l, r, instruction)?;
+        Ok((f64::from_bits(l_value), f64::from_bits(r_value)))
+    }
+
+    // Reads the u128 whose low word is at relative position pos and
+    // whose high word is right below it
+    fn stack_u128(&self, pos: u64, instruction: &'static str) -> Result<u128, VmError> {
+        let low = self.stack_value(pos, instruction)?;
+        let high = pos
+            .checked_add(1)
+            .ok_or(VmError::StackUnderflow { instruction })?;
+        let high = self.stack_value(high, instruction)?;
+        Ok((high as u128) << 64 | low as u128)
+    }
+
+    fn pop_u128(&mut self, instruction: &'static str) -> Result<u128, VmError> {
+        let value = self.stack_u128(0, instruction)?;
+        self.stack.truncate(self.stack.len() - 2);
+        Ok(value)
+    }
+
+    fn push_u128(&mut self, value: u128) {
+        self.stack.push((value >> 64) as u64);
+        self.stack.push(value as u64);
+    }
+
+    // The count bytes ending at relative position last_pos, bottom first
+    fn stack_bytes(
+        &self,
+        last_pos: u64,
+        count: u64,
+        instruction: &'static str,
+    ) -> Result<Vec<u8>, VmError> {
+        let last = self.stack_index(last_pos, instruction)?;
+        let start = (last as u64 + 1)
+            .checked_sub(count)
+            .ok_or(VmError::StackUnderflow { instruction })? as usize;
+        self.stack[start..=last]
+            .iter()
+            .map(|&value| u8::try_from(value).map_err(|_| VmError::InvalidValue { value }))
+            .collect()
+    }
+
+    // Stores the file in the first free descriptor slot and returns it
+    fn insert_file(&mut self, file: File) -> u64 {
+        match self.file_table.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.file_table[fd] = Some(file);
+                fd as u64
+            }
+            None => {
+                self.file_table.push(Some(file));
+                self.file_table.len() as u64 - 1
+            }
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut File, VmError> {
+        self.file_table
+            .get_mut(fd as usize)
+            .and_then(Option::as_mut)
+            .ok_or(VmError::InvalidFileDescriptor { fd })
+    }
+
+    // An independent copy of the machine that continues from the same
+    // state. Native functions cannot be copied and have to be registered
+    // on the fork again. Fails with ForkWithOpenFiles unless every file
+    // opened by FdOpen has been closed
+    pub fn fork(&self) -> Result<Machine, VmError> {
+        Ok(MachineState::of(self)?.into_machine())
+    }
+
+    // Continues a machine suspended at an I/O instruction. For In and
+    // InByte, Some(value) is pushed in place of reading the input, and the
+    // instruction counts as executed. Otherwise the instruction is performed
+    // normally when the machine runs again, without suspending first
+    pub fn resume_io(&mut self, data: Option<u64>) -> Result<(), VmError> {
+        let io_type = self.io_suspended.ok_or(VmError::NotSuspendedAtIo)?;
+        let (instruction, value) = match (io_type, data) {
+            (IoType::In, Some(value)) => (Instruction::In(), value),
+            (IoType::InByte, Some(value)) => (Instruction::InByte(), value),
+            _ => {
+                self.io_suspended = None;
+                self.io_resumed = true;
+                return Ok(());
+            }
+        };
+        self.check_step_limit()?;
+        self.io_suspended = None;
+        if self.config.reverse_mode {
+            self.record_history();
+        }
+        let len_before = self.stack.len();
+        self.stack.push(value);
+        self.finish_step(&instruction, len_before, self.pc + 1)?;
+        Ok(())
+    }
+
+    // Registers f to be called by CallNative(index), replacing any
+    // function previously registered under the same index
+    pub fn register_native(&mut self, index: u64, f: Box<dyn NativeFn>) {
+        self.natives.insert(index, Native::Plain(f));
+    }
+
+    // Like register_native, for functions that read the input or write the
+    // output. Calling them is a sandbox violation unless I/O is allowed
+    pub fn register_native_io(&mut self, index: u64, f: Box<dyn NativeIoFn>) {
+        self.natives.insert(index, Native::Io(f));
+    }
+
+    // Like run, but calls inspect(pc, instruction, stack) before
+    // each instruction is executed
+    pub fn run_inspected<W: Write, R: Read, F: FnMut(u64, &Instruction, &[u64])>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        mut inspect: F,
+    ) -> Result<RunResult, VmError> {
+        self.validate()?;
+        let mut input = BufReader::new(input);
+        loop {
+            if let Some(instruction) = self.code.get(self.pc as usize) {
+                inspect(self.pc, instruction, &self.stack);
+            }
+            if !self.step(&mut input, output)? {
+                break;
+            }
+        }
+
+        Ok(self.finish())
+    }
+
+    // Why the last run stopped. Clears a pending exit request
+    fn finish(&mut self) -> RunResult {
+        if let Some(io_type) = self.io_suspended {
+            return RunResult::SuspendedAtIo {
+                instruction_index: self.pc,
+                io_type,
+            };
+        }
+        match self.exit_code.take() {
+            Some(code) => RunResult::ProcessExit(code),
+            None => RunResult::Finished,
+        }
+    }
+
+    // Executes the instruction at pc. Returns false once the program
+    // has run past its end, requested an exit or the input is exhausted
+    fn step<W: Write, R: BufRead>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<bool, VmError> {
+        let Some(instruction) = self.code.get(self.pc as usize) else {
+            return Ok(false);
+        };
+        // println!("{:?}", instruction);
+        self.execute_step(&instruction.clone(), input, output)
+    }
+
+    // Executes instruction as the one at pc, see step
+    pub(crate) fn execute_step<W: Write, R: BufRead>(
+        &mut self,
+        instruction: &Instruction,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<bool, VmError> {
+        self.check_step_limit()?;
+        if self.config.io_break_mode && !std::mem::take(&mut self.io_resumed) {
+            let io_type = match instruction {
+                Instruction::In() => Some(IoType::In),
+                Instruction::InByte() => Some(IoType::InByte),
+                Instruction::Out(_) => Some(IoType::Out),
+                _ => None,
+            };
+            if io_type.is_some() {
+                self.io_suspended = io_type;
+                return Ok(false);
+            }
+        }
+        let mut input =
+            QuotaReader::new(input, self.usage.input_bytes, self.config.max_input_bytes);
+        let mut output = QuotaWriter::new(
+            output,
+            self.usage.output_bytes,
+            self.config.max_output_bytes,
+        );
+        if self.config.reverse_mode {
+            self.record_history();
+        }
+        let start = self.config.time_instructions.then(Instant::now);
+        let len_before = self.stack.len();
+        let result = instruction.execute(self, &mut input, &mut output);
+        if let Some(start) = start {
+            *self.timing.entry(instruction.mnemonic()).or_default() += start.elapsed();
+        }
+        self.usage.input_bytes = input.used;
+        self.usage.output_bytes = output.used;
+
+        match result {
+            Err(VmError::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+            Ok(next_pc) => self.finish_step(instruction, len_before, next_pc),
+        }
+    }
+
+    fn check_step_limit(&self) -> Result<(), VmError> {
+        if matches!(self.config.step_limit, Some(limit) if self.usage.steps >= limit) {
+            return Err(VmError::QuotaExceeded { resource: "steps" });
+        }
+        Ok(())
+    }
+
+    // Accounts for an instruction that ran with len_before values on the
+    // stack and moves on to next_pc. Returns whether the machine continues
+    fn finish_step(
+        &mut self,
+        instruction: &Instruction,
+        len_before: usize,
+        next_pc: u64,
+    ) -> Result<bool, VmError> {
+        if self.config.type_tagging {
+            self.update_tags(instruction, len_before);
+        }
+        self.consumed_cost += instruction_cost(instruction);
+        self.usage.steps += 1;
+        self.pc = next_pc;
+        if matches!(self.config.max_stack_depth, Some(max) if self.stack.len() > max) {
+            return Err(VmError::QuotaExceeded { resource: "stack" });
+        }
+        if matches!(self.config.max_heap_bytes, Some(max) if self.heap_bytes() > max) {
+            return Err(VmError::QuotaExceeded { resource: "heap" });
+        }
+        Ok(self.exit_code.is_none())
+    }
+}
+
+pub fn serialize_code<W: Write>(instructions: &[Instruction], writer: &mut W) -> io::Result<()> {
+    serialize_code_with(instructions, Encoding::Fixed(Endianness::Little), writer)
+}
+
+pub(crate) fn serialize_code_with<W: Write>(
+    instructions: &[Instruction],
+    encoding: Encoding,
+    writer: &mut W,
+) -> io::Result<()> {
+    for instr in unfuse(instructions).iter() {
+        instr.serialize(writer, encoding)?;
+    }
+    Ok(())
+}
+
+// The index each instruction ends up at once Fused instructions are replaced
+// by their components, with one more entry for the end of the code
+pub(crate) fn unfused_indices(instructions: &[Instruction]) -> Vec<u64> {
+    let mut indices = Vec::with_capacity(instructions.len() + 1);
+    let mut index = 0;
+    for instruction in instructions {
+        indices.push(index);
+        index += match instruction {
+            Instruction::Fused(components) => components.len() as u64,
+            _ => 1,
+        };
+    }
+    indices.push(index);
+    indices
+}
+
+// Replaces every Fused instruction by its components and rewrites jump
+// targets to match. Serialized code never holds Fused instructions
+pub(crate) fn unfuse(instructions: &[Instruction]) -> Cow<'_, [Instruction]> {
+    if !instructions
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::Fused(_)))
+    {
+        return Cow::Borrowed(instructions);
+    }
+    let mut code: Vec<Instruction> = instructions
+        .iter()
+        .flat_map(|instruction| match instruction {
+            Instruction::Fused(components) => components.to_vec(),
+            instruction => vec![instruction.clone()],
+        })
+        .collect();
+    remap_targets(&mut code, &unfused_indices(instructions));
+    Cow::Owned(code)
+}
+
+// Rewrites every jump target and lookup table start t to new_index[t].
+// Targets past the end of new_index are left unchanged
+pub(crate) fn remap_targets(code: &mut [Instruction], new_index: &[u64]) {
+    for instruction in code.iter_mut() {
+        if let Instruction::LookupTable(_, start, _, _) = instruction {
+            if let Some(&index) = new_index.get(*start as usize) {
+                *start = index;
+            }
+        }
+        if let Some(target) = instruction.jump_target_mut() {
+            if let Some(&index) = new_index.get(*target as usize) {
+                *target = index;
+            }
+        }
+    }
+}
+
+pub fn deserialize_code<R: Read>(reader: &mut R) -> io::Result<Vec<Instruction>> {
+    deserialize_code_with(reader, Encoding::Fixed(Endianness::Little), 0)
+}
+
+// Reads instructions until the end of the input. capacity is only
+// a hint for how many there are
+pub(crate) fn deserialize_code_with<R: Read>(
+    reader: &mut R,
+    encoding: Encoding,
+    capacity: usize,
+) -> io::Result<Vec<Instruction>> {
+    let mut instructions = Vec::with_capacity(capacity);
+    loop {
+        match Instruction::deserialize(reader, encoding) {
+            Ok(instr) => instructions.push(instr),
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(instructions)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn test_instruction_execution(
+        instruction: Instruction,
+        machine: &mut Machine,
+        expected_machine: Machine,
+        input_data: &[u8],
+        expected_output: &[u8],
+    ) {
+        let mut input = Cursor::new(input_data);
+        let mut output = Vec::new();
+        machine.pc = instruction
+            .execute(machine, &mut input, &mut output)
+            .unwrap();
+
+        assert_eq!(machine.stack, expected_machine.stack);
+        assert_eq!(machine.pc, expected_machine.pc);
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn test_push() {
+        let instruction = Instruction::Push(42);
         let mut machine = Machine {
             code: Vec::new(),
             stack: Vec::new(),
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![42],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -336,11 +2740,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![5],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![5],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], b"5\n");
     }
@@ -352,15 +2758,37 @@ mod tests {
             code: Vec::new(),
             stack: Vec::new(),
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![42],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, b"42\n", &[]);
     }
 
+    #[test]
+    fn test_newline_mode() {
+        let mut machine = Machine {
+            code: vec![
+                Instruction::In(),
+                Instruction::Out(0),
+                Instruction::OutStr("ok".to_string()),
+            ],
+            config: VmConfig {
+                newline_mode: NewlineMode::Crlf,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        machine.run(&mut &b"42\r"[..], &mut output).unwrap();
+
+        assert_eq!(output, b"42\r\nok\r\n");
+    }
+
     #[test]
     fn test_add() {
         let instruction = Instruction::Add(0, 1);
@@ -368,11 +2796,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![2, 3],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![5],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -384,11 +2814,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![5],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![5, 5],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -400,11 +2832,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![2, 4],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![2, 4],
             pc: 5,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -416,11 +2850,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![4, 2],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![4, 2],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -432,11 +2868,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![4, 4],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![4, 4],
             pc: 5,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -448,11 +2886,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![2, 4],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![2, 4],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -464,11 +2904,13 @@ mod tests {
             code: Vec::new(),
             stack: Vec::new(),
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: Vec::new(),
             pc: 5,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -480,11 +2922,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![5],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![4],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -496,11 +2940,13 @@ mod tests {
             code: Vec::new(),
             stack: vec![5],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![6],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
     }
@@ -512,15 +2958,45 @@ mod tests {
             code: Vec::new(),
             stack: Vec::new(),
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![65],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, b"A", &[]);
     }
 
+    #[test]
+    fn test_in_byte_eof_sentinel() {
+        // Echoes the input until the sentinel is read
+        let code = vec![
+            Instruction::InByte(),
+            Instruction::Push(u64::MAX),
+            Instruction::Eq(0, 1, 5),
+            Instruction::OutByte(1),
+            Instruction::Jmp(0),
+            Instruction::OutStr("eof".to_string()),
+        ];
+        let mut machine = Machine::new(code.clone());
+        let mut output = Vec::new();
+        machine.run(&mut &b"hi"[..], &mut output).unwrap();
+        assert_eq!(output, b"hieof\n");
+
+        let mut machine = Machine {
+            code: vec![Instruction::InByte()],
+            config: VmConfig {
+                eof_sentinel: 7,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.stack, vec![7]);
+    }
+
     #[test]
     fn test_out_byte() {
         let instruction = Instruction::OutByte(0);
@@ -528,37 +3004,1945 @@ mod tests {
             code: Vec::new(),
             stack: vec![65],
             pc: 0,
+            ..Default::default()
         };
         let expected_machine = Machine {
             code: Vec::new(),
             stack: vec![65],
             pc: 1,
+            ..Default::default()
         };
         test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[65]);
     }
-}
-
-#[cfg(test)]
-mod test_serialization {
-    use super::*;
-
-    fn test_serialize_deserialize(instruction: Instruction) {
-        let mut serialized = Vec::new();
-        instruction.serialize(&mut serialized).unwrap();
-
-        let mut deserialized = &serialized[..];
-        let instruction_back = Instruction::deserialize(&mut deserialized).unwrap();
 
-        assert_eq!(instruction, instruction_back);
+    #[test]
+    fn test_out_str_no_nl() {
+        let instruction = Instruction::OutStrNoNl("Hello".to_string());
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: Vec::new(),
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: Vec::new(),
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], b"Hello");
     }
 
     #[test]
-    fn test_serialize_push() {
-        test_serialize_deserialize(Instruction::Push(42));
+    fn test_is_pure() {
+        for instruction in [
+            Instruction::Push(1),
+            Instruction::Add(0, 1),
+            Instruction::Copy(0),
+            Instruction::Dec(0),
+            Instruction::Jmp(0),
+            Instruction::Fused(Box::new([Instruction::Copy(0), Instruction::Add(0, 1)])),
+        ] {
+            assert!(instruction.is_pure(), "{:?}", instruction);
+        }
+        for instruction in [
+            Instruction::In(),
+            Instruction::InByte(),
+            Instruction::Out(0),
+            Instruction::OutStr("a".to_string()),
+            Instruction::OutByte(0),
+            Instruction::FdRead(0, 1),
+            Instruction::FdWrite(0, 1),
+            Instruction::GetArg(0),
+            Instruction::InEnv(),
+            Instruction::CallNative(0),
+            Instruction::Fused(Box::new([Instruction::Copy(0), Instruction::Out(0)])),
+        ] {
+            assert!(!instruction.is_pure(), "{:?}", instruction);
+        }
     }
 
     #[test]
-    fn test_serialize_out() {
+    fn test_instruction_at() {
+        let machine = Machine::new(vec![Instruction::Push(1), Instruction::Out(0)]);
+
+        assert_eq!(machine.instruction_count(), 2);
+        assert_eq!(machine.instruction_at(1), Some(&Instruction::Out(0)));
+        assert_eq!(machine.instruction_at(2), None);
+        assert_eq!(machine.instruction_at(u64::MAX), None);
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::Push(2),
+            Instruction::Add(0, 1),
+        ]);
+
+        machine.patch(1, Instruction::Push(3)).unwrap();
+        machine.patch(2, Instruction::Eq(0, 1, 0)).unwrap_err();
+        machine.patch(2, Instruction::CmpPush(0, 1)).unwrap();
+        assert_eq!(
+            machine.code,
+            vec![
+                Instruction::Push(1),
+                Instruction::Push(3),
+                Instruction::CmpPush(0, 1),
+            ]
+        );
+
+        for (pc, instruction) in [
+            (0, Instruction::Push(1000)),
+            (0, Instruction::Jmp(4)),
+            (3, Instruction::Push(1)),
+        ] {
+            assert!(matches!(
+                machine.patch(pc, instruction),
+                Err(VmError::IncompatiblePatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_jump_targets() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(0),
+            Instruction::Jmp(3),
+            Instruction::Eq(0, 0, 2),
+            Instruction::Gt(0, 0, 7),
+            Instruction::Jmp(9),
+        ]);
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+
+        let VmError::InvalidProgram(errors) = error else {
+            panic!("unexpected error {:?}", error);
+        };
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::InvalidJumpTarget { pc: 3, target: 7 },
+                ValidationError::InvalidJumpTarget { pc: 4, target: 9 },
+            ]
+        );
+        assert!(machine.stack.is_empty());
+
+        machine.code[4] = Instruction::Jmp(5);
+        machine.code[3] = Instruction::Gt(0, 0, 5);
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn test_with_config() {
+        let config = VmConfig {
+            initial_globals: 3,
+            eof_sentinel: 0,
+            ..Default::default()
+        };
+        let mut machine = Machine::with_config(vec![Instruction::InByte()], config.clone());
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+
+        assert_eq!(machine.globals, vec![0, 0, 0]);
+        assert_eq!(machine.stack, vec![0]);
+        assert_eq!(machine.config, config);
+    }
+
+    #[test]
+    fn test_with_initial_stack() {
+        let code = vec![Instruction::Add(0, 1), Instruction::Out(0)];
+        let mut machine = Machine::new(code.clone())
+            .with_initial_stack(vec![40, 2])
+            .build()
+            .unwrap();
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"42\n");
+
+        let config = VmConfig {
+            max_stack_depth: Some(2),
+            ..Default::default()
+        };
+        assert!(Machine::with_config(code.clone(), config.clone())
+            .with_initial_stack(vec![1, 2])
+            .build()
+            .is_ok());
+        assert!(matches!(
+            Machine::with_config(code, config)
+                .with_initial_stack(vec![1, 2, 3])
+                .build(),
+            Err(VmError::QuotaExceeded { resource: "stack" })
+        ));
+    }
+
+    #[test]
+    fn test_sandbox_denies_io() {
+        for instruction in [
+            Instruction::In(),
+            Instruction::InByte(),
+            Instruction::Out(0),
+        ] {
+            let mut machine = Machine {
+                code: vec![Instruction::Push(1), instruction],
+                config: VmConfig {
+                    sandbox_policy: SandboxPolicy {
+                        allow_io: false,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let error = machine.run(&mut &b"1\n"[..], &mut Vec::new()).unwrap_err();
+
+            assert!(matches!(error, VmError::SandboxViolation { .. }));
+            assert_eq!(machine.stack, vec![1]);
+            assert_eq!(machine.pc, 1);
+        }
+    }
+
+    #[test]
+    fn test_deterministic_mode() {
+        for instruction in [
+            Instruction::InEnv(),
+            Instruction::GetArg(0),
+            Instruction::FdOpen(0, 0),
+        ] {
+            let mut machine = Machine {
+                code: vec![Instruction::Push(1), Instruction::Push(0), instruction],
+                config: VmConfig {
+                    deterministic_mode: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let error = machine.run(&mut &b""[..], &mut Vec::new()).unwrap_err();
+
+            assert!(matches!(
+                error,
+                VmError::NonDeterministicInstructionForbidden { .. }
+            ));
+            assert_eq!(machine.pc, 2);
+        }
+    }
+
+    #[test]
+    fn test_call_native() {
+        let instruction = Instruction::CallNative(7);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![2, 3],
+            pc: 0,
+            ..Default::default()
+        };
+        machine.register_native(
+            7,
+            Box::new(|machine: &mut Machine| {
+                let r = machine.stack.pop().unwrap();
+                let l = machine.stack.pop().unwrap();
+                machine.stack.push(l * r);
+                Ok(())
+            }),
+        );
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![6],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_call_native_unknown() {
+        let mut machine = Machine::new(vec![Instruction::CallNative(0)]);
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(error, VmError::UnknownNative { index: 0 }));
+    }
+
+    #[test]
+    fn test_in_env() {
+        std::env::set_var("BYTECODE_VM_TEST_IN_ENV", "xy");
+        let name = b"BYTECODE_VM_TEST_IN_ENV";
+        let mut stack: Vec<u64> = name.iter().map(|&b| b as u64).collect();
+        stack.push(name.len() as u64);
+
+        let instruction = Instruction::InEnv();
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: [&stack[..], &[0]].concat(),
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: [&stack[..], &[120, 121, 2]].concat(),
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_in_env_missing() {
+        let instruction = Instruction::InEnv();
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![b'?' as u64, 1, 0],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![b'?' as u64, 1, 0],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_get_arg() {
+        let instruction = Instruction::GetArg(1);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 7],
+            pc: 0,
+            ..Default::default()
+        };
+        machine.set_args(vec!["first".to_string(), "ab".to_string()]);
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 7, 97, 98, 2],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_get_arg_missing() {
+        let instruction = Instruction::GetArg(0);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![5],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![5, 0],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(3),
+            Instruction::Push(0),
+            Instruction::ExitCode(1),
+            Instruction::OutStr("unreachable".to_string()),
+        ]);
+        let mut output = Vec::new();
+        let result = machine.run(&mut &[][..], &mut output).unwrap();
+
+        assert_eq!(result, RunResult::ProcessExit(3));
+        assert_eq!(machine.pc, 3);
+        assert!(output.is_empty());
+
+        // Running again resumes after the exit
+        let result = machine.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(result, RunResult::Finished);
+        assert_eq!(output, b"unreachable\n");
+    }
+
+    #[test]
+    fn test_exit_code_out_of_range() {
+        let mut machine = Machine::new(vec![Instruction::Push(1 << 32), Instruction::ExitCode(0)]);
+        let error = machine.run(&mut &b""[..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            error,
+            VmError::InvalidValue {
+                value: 0x1_0000_0000
+            }
+        ));
+        assert_eq!(machine.exit_code, None);
+    }
+
+    #[test]
+    fn test_string_heap_instructions() {
+        // Builds "hi" and "!" and checks the bytes of their concatenation
+        let mut machine = Machine::new(vec![
+            Instruction::Push(2),
+            Instruction::StrAlloc(0),
+            Instruction::Push(0),
+            Instruction::Push(104),
+            Instruction::StrStore(2, 1, 0),
+            Instruction::Push(1),
+            Instruction::Push(105),
+            Instruction::StrStore(4, 1, 0),
+            Instruction::Push(1),
+            Instruction::StrAlloc(0),
+            Instruction::Push(33),
+            Instruction::StrStore(1, 6, 0),
+            Instruction::StrCat(7, 1),
+            Instruction::StrLen(0),
+            Instruction::Out(0),
+            Instruction::Push(2),
+            Instruction::StrLoad(2, 0),
+            Instruction::OutByte(0),
+            Instruction::StrFree(10),
+        ]);
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+
+        assert_eq!(output, b"3\n!");
+        assert_eq!(machine.strings.get(2).unwrap(), b"hi!");
+        assert!(machine.strings.get(0).is_err());
+    }
+
+    #[test]
+    fn test_string_heap_errors() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::StrAlloc(0),
+            Instruction::StrLoad(0, 1),
+        ]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(VmError::IndexOutOfBounds { index: 1, len: 1 })
+        ));
+
+        let mut machine = Machine::new(vec![Instruction::Push(3), Instruction::StrLen(0)]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(VmError::InvalidStringHandle { handle: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_out_str_handle() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(2),
+            Instruction::StrAlloc(0),
+            Instruction::Push(0),
+            Instruction::Push(111),
+            Instruction::StrStore(2, 1, 0),
+            Instruction::Push(1),
+            Instruction::Push(107),
+            Instruction::StrStore(4, 1, 0),
+            Instruction::OutStrHandle(4),
+            Instruction::OutStrHandleLn(4),
+        ]);
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+
+        assert_eq!(output, b"okok\n");
+    }
+
+    #[test]
+    fn test_str_cmp() {
+        let mut machine = Machine::new(Vec::new());
+        let a = machine.strings.alloc(b"run".to_vec());
+        let b = machine.strings.alloc(b"run".to_vec());
+        let c = machine.strings.alloc(b"ru".to_vec());
+        machine.stack = vec![a, b, c];
+
+        let instruction = Instruction::StrCmp(2, 0, 7);
+        let next_pc = instruction
+            .execute(&mut machine, &mut Cursor::new(&[]), &mut Vec::new())
+            .unwrap();
+        assert_eq!(next_pc, 1);
+
+        let instruction = Instruction::StrCmp(2, 1, 7);
+        let next_pc = instruction
+            .execute(&mut machine, &mut Cursor::new(&[]), &mut Vec::new())
+            .unwrap();
+        assert_eq!(next_pc, 7);
+        assert_eq!(machine.pc, 0);
+    }
+
+    #[test]
+    fn test_str_to_int_and_back() {
+        let mut machine = Machine::new(vec![
+            Instruction::StrToInt(0),
+            Instruction::Inc(0),
+            Instruction::IntToStr(0),
+            Instruction::OutStrHandleLn(0),
+        ]);
+        let handle = machine.strings.alloc(b"41".to_vec());
+        machine.stack.push(handle);
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+
+        assert_eq!(output, b"42\n");
+    }
+
+    #[test]
+    fn test_str_to_int_parse_error() {
+        let mut machine = Machine::new(vec![Instruction::StrToInt(0)]);
+        let handle = machine.strings.alloc(b"4x".to_vec());
+        machine.stack.push(handle);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+
+        assert!(matches!(
+            result,
+            Err(VmError::ParseError { raw_input, .. }) if raw_input == "4x"
+        ));
+    }
+
+    #[test]
+    fn test_str_slice() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::Push(3),
+            Instruction::StrSlice(2, 1, 0),
+            Instruction::OutStrHandleLn(0),
+        ]);
+        let handle = machine.strings.alloc(b"hello".to_vec());
+        machine.stack.push(handle);
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"ell\n");
+
+        let mut machine = Machine::new(vec![
+            Instruction::Push(3),
+            Instruction::Push(3),
+            Instruction::StrSlice(2, 1, 0),
+        ]);
+        let handle = machine.strings.alloc(b"hello".to_vec());
+        machine.stack.push(handle);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(VmError::IndexOutOfBounds { index: 6, len: 5 })
+        ));
+    }
+
+    #[test]
+    fn test_multi_ret() {
+        let instruction = Instruction::MultiRet(2);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![9, 5, 1, 2],
+            pc: 3,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![9, 1, 2],
+            pc: 5,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_multi_ret_violation() {
+        let mut machine = Machine::new(vec![Instruction::Push(1), Instruction::MultiRet(1)]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+
+        assert!(matches!(
+            result,
+            Err(VmError::CallingConventionViolation {
+                expected: 2,
+                found: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cond_move() {
+        let instruction = Instruction::CondMove(2, 1, 0);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![7, 3, 0],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![7, 3, 0],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+
+        let instruction = Instruction::CondMove(2, 1, 0);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![7, 3, 1],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![7, 7, 1],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_select() {
+        let instruction = Instruction::Select(2, 1, 0);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![3, 5, 1],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![3],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+
+        let instruction = Instruction::Select(2, 1, 0);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![9, 3, 5, 0],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![9, 5],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+    }
+
+    #[test]
+    fn test_pair_instructions() {
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 2, 3],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(
+            Instruction::TwoDup(),
+            &mut machine,
+            expected_machine,
+            &[],
+            &[],
+        );
+
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 4, 5],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 4, 5, 2, 3],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(
+            Instruction::TwoSwap(),
+            &mut machine,
+            expected_machine,
+            &[],
+            &[],
+        );
+
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(
+            Instruction::TwoDrop(),
+            &mut machine,
+            expected_machine,
+            &[],
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_pair_instructions_underflow() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::Push(2),
+            Instruction::TwoSwap(),
+        ]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+
+        assert!(matches!(
+            result,
+            Err(VmError::StackUnderflow {
+                instruction: "2SWAP"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_largest_stack_position_underflows() {
+        for instruction in [
+            Instruction::NotEq(u64::MAX, 0, 0),
+            Instruction::StrAlloc(u64::MAX),
+            Instruction::TypeAssert(u64::MAX, TYPE_U8),
+        ] {
+            let mut machine = Machine {
+                stack: vec![1],
+                ..Default::default()
+            };
+            assert!(
+                matches!(
+                    instruction.execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+                    Err(VmError::StackUnderflow { .. })
+                ),
+                "{}",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn test_out_csv() {
+        let instruction = Instruction::OutCSV(0);
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 3],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 3],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(instruction, &mut machine, expected_machine, &[], b"3,2,1\n");
+
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::Push(2),
+            Instruction::OutCSV(0),
+        ]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(result, Err(VmError::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn test_branch_target_is_next_instruction() {
+        // Each branch lands exactly on its target, not one past it
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::Push(0),
+            Instruction::Jmp(4),
+            Instruction::OutStr("skipped".to_string()),
+            Instruction::Gt(1, 0, 6),
+            Instruction::OutStr("skipped".to_string()),
+            Instruction::Eq(0, 0, 8),
+            Instruction::OutStr("skipped".to_string()),
+            Instruction::OutStr("done".to_string()),
+        ]);
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+
+        assert_eq!(output, b"done\n");
+        assert_eq!(machine.pc, 9);
+    }
+
+    #[test]
+    fn test_operands_round_trip() {
+        let instructions = [
+            Instruction::Push(7),
+            Instruction::In(),
+            Instruction::Gt(1, 2, 3),
+            Instruction::StrStore(3, 2, 1),
+            Instruction::TwoSwap(),
+            Instruction::OutStr("s".to_string()),
+        ];
+        for instruction in instructions {
+            let operands = instruction.operands();
+            assert_eq!(instruction.with_operands(&operands).unwrap(), instruction);
+        }
+
+        assert_eq!(Instruction::Gt(1, 2, 3).operands(), vec![1, 2, 3]);
+        assert_eq!(
+            Instruction::Add(0, 1).with_operands(&[4, 5]).unwrap(),
+            Instruction::Add(4, 5)
+        );
+        assert!(matches!(
+            Instruction::Add(0, 1).with_operands(&[4]),
+            Err(VmError::OperandCountMismatch {
+                instruction: "ADD",
+                expected: 2,
+                found: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cmp_push() {
+        for (stack, result) in [(vec![1, 2], u64::MAX), (vec![2, 2], 0), (vec![3, 2], 1)] {
+            let mut machine = Machine {
+                code: Vec::new(),
+                stack: stack.clone(),
+                pc: 0,
+                ..Default::default()
+            };
+            let expected_machine = Machine {
+                code: Vec::new(),
+                stack: [stack, vec![result]].concat(),
+                pc: 1,
+                ..Default::default()
+            };
+            test_instruction_execution(
+                Instruction::CmpPush(1, 0),
+                &mut machine,
+                expected_machine,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    #[test]
+    fn test_instruction_hash_and_order() {
+        let code = [
+            Instruction::Jmp(0),
+            Instruction::Push(2),
+            Instruction::Push(1),
+            Instruction::Push(2),
+        ];
+        let mut counts = HashMap::new();
+        for instruction in &code {
+            *counts.entry(instruction).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&Instruction::Push(2)], 2);
+
+        // Ordered by variant declaration order, then by operands
+        let sorted: std::collections::BTreeSet<_> = code.into_iter().collect();
+        assert_eq!(
+            sorted.into_iter().collect::<Vec<_>>(),
+            vec![
+                Instruction::Push(1),
+                Instruction::Push(2),
+                Instruction::Jmp(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_with_strings() {
+        let mut machine = Machine::new(vec![
+            Instruction::In(),
+            Instruction::Inc(0),
+            Instruction::Out(0),
+            Instruction::OutStrNoNl("done".to_string()),
+        ]);
+        let (stats, output) = machine.run_with_strings("41\n").unwrap();
+
+        assert_eq!(output, "42\ndone");
+        assert_eq!(stats.result, RunResult::Finished);
+        assert_eq!(stats.instructions_executed, 4);
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn test_crc32() {
+        let mut machine = Machine::new(vec![Instruction::Push(9), Instruction::Crc32(1, 0)]);
+        machine.stack = b"123456789".iter().map(|&b| b as u64).collect();
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.stack.last(), Some(&0xCBF4_3926));
+
+        let mut machine = Machine::new(vec![Instruction::Push(2), Instruction::Crc32(1, 0)]);
+        machine.stack = vec![1, 256];
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(result, Err(VmError::InvalidValue { value: 256 })));
+    }
+
+    #[cfg(not(feature = "crc"))]
+    #[test]
+    fn test_crc32_without_feature() {
+        let mut machine = Machine::new(vec![Instruction::Push(0), Instruction::Crc32(0, 0)]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(VmError::FeatureDisabled {
+                instruction: "CRC32",
+                feature: "crc"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_hash64() {
+        let mut machine = Machine::new(vec![Instruction::Push(1), Instruction::Hash64(1, 0)]);
+        machine.stack = vec![7, b'a' as u64];
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.stack, vec![7, 97, 1, 0xaf63_dc4c_8601_ec8c]);
+
+        let mut machine = Machine {
+            code: vec![Instruction::Push(1), Instruction::Hash64(1, 0)],
+            stack: vec![b'a' as u64],
+            config: VmConfig {
+                hash_algorithm: HashAlgorithm::Fnv1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.stack.last(), Some(&0xaf63_bd4c_8601_b7be));
+    }
+
+    #[test]
+    fn test_file_descriptors() {
+        let path = std::env::temp_dir().join(format!("bytecode_vm_fd_{}", std::process::id()));
+        let mut machine = Machine::new(vec![
+            // Write "hello" and close
+            Instruction::Push(1),
+            Instruction::FdOpen(1, 0),
+            Instruction::FdWrite(0, 3),
+            Instruction::FdClose(0),
+            // Read it back, three bytes at a time
+            Instruction::Push(0),
+            Instruction::FdOpen(3, 0),
+            Instruction::Push(3),
+            Instruction::FdRead(1, 0),
+            Instruction::OutStrHandleLn(0),
+            Instruction::FdRead(2, 1),
+            Instruction::OutStrHandleLn(0),
+            Instruction::FdClose(3),
+        ]);
+        let path_handle = machine
+            .strings
+            .alloc(path.to_str().unwrap().as_bytes().to_vec());
+        let contents = machine.strings.alloc(b"hello".to_vec());
+        machine.stack = vec![contents, path_handle];
+        let mut output = Vec::new();
+        let result = machine.run(&mut &[][..], &mut output);
+        std::fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+        assert_eq!(output, b"hel\nlo\n");
+        assert!(machine.file_table.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_file_descriptor_errors() {
+        let mut machine = Machine::new(vec![Instruction::Push(0), Instruction::FdOpen(1, 0)]);
+        let missing = machine.strings.alloc(b"/nonexistent/bytecode_vm".to_vec());
+        machine.stack.push(missing);
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.stack.last(), Some(&u64::MAX));
+
+        let mut machine = Machine::new(vec![Instruction::Push(3), Instruction::FdClose(0)]);
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(VmError::InvalidFileDescriptor { fd: 3 })
+        ));
+
+        let mut machine = Machine {
+            code: vec![Instruction::Push(0), Instruction::FdClose(0)],
+            config: VmConfig {
+                sandbox_policy: SandboxPolicy {
+                    allow_file_ops: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = machine.run(&mut &[][..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(VmError::SandboxViolation {
+                instruction: "FDCLOSE"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 4],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 4, 3, 2],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(
+            Instruction::CopyRange(1, 2),
+            &mut machine,
+            expected_machine,
+            &[],
+            &[],
+        );
+
+        assert!(matches!(
+            Instruction::CopyRange(5, 2).execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        assert!(matches!(
+            Instruction::CopyRange(2, u64::MAX).execute(
+                &mut machine,
+                &mut &b""[..],
+                &mut Vec::new()
+            ),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        assert_eq!(
+            Instruction::CopyRange(9, 0)
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap(),
+            2
+        );
+        assert_eq!(machine.stack, vec![1, 2, 3, 4, 3, 2]);
+    }
+
+    #[test]
+    fn test_drop_range() {
+        let mut machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 2, 3, 4, 5],
+            pc: 0,
+            ..Default::default()
+        };
+        let expected_machine = Machine {
+            code: Vec::new(),
+            stack: vec![1, 5],
+            pc: 1,
+            ..Default::default()
+        };
+        test_instruction_execution(
+            Instruction::DropRange(1, 3),
+            &mut machine,
+            expected_machine,
+            &[],
+            &[],
+        );
+
+        assert!(matches!(
+            Instruction::DropRange(1, 2).execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        assert_eq!(machine.stack, vec![1, 5]);
+
+        let mut machine = Machine {
+            stack: vec![1, 2, 3],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Instruction::DropRange(2, u64::MAX).execute(
+                &mut machine,
+                &mut &b""[..],
+                &mut Vec::new()
+            ),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        assert_eq!(machine.stack, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_saturating_arithmetic() {
+        for (instruction, stack, result) in [
+            (Instruction::SatAdd(0, 1), vec![5, u64::MAX], vec![u64::MAX]),
+            (Instruction::SatAdd(0, 1), vec![2, 3], vec![5]),
+            (Instruction::SatSub(1, 0), vec![3, 5], vec![0]),
+            (Instruction::SatSub(0, 1), vec![3, 5], vec![2]),
+            (Instruction::SatInc(1), vec![u64::MAX, 0], vec![u64::MAX, 0]),
+            (Instruction::SatInc(0), vec![u64::MAX - 1], vec![u64::MAX]),
+            (Instruction::SatDec(0), vec![0], vec![0]),
+            (Instruction::SatDec(0), vec![1], vec![0]),
+        ] {
+            let mut machine = Machine {
+                code: Vec::new(),
+                stack,
+                pc: 0,
+                ..Default::default()
+            };
+            let expected_machine = Machine {
+                code: Vec::new(),
+                stack: result,
+                pc: 1,
+                ..Default::default()
+            };
+            test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+        }
+    }
+
+    #[test]
+    fn test_wrapping_arithmetic() {
+        for (instruction, stack, result) in [
+            (Instruction::WrapAdd(0, 1), vec![5, u64::MAX], vec![4]),
+            (Instruction::WrapAdd(0, 1), vec![2, 3], vec![5]),
+            (Instruction::WrapSub(1, 0), vec![3, 5], vec![u64::MAX - 1]),
+            (Instruction::WrapSub(0, 1), vec![3, 5], vec![2]),
+            (Instruction::WrapInc(1), vec![u64::MAX, 0], vec![0, 0]),
+            (Instruction::WrapDec(0), vec![0], vec![u64::MAX]),
+        ] {
+            let mut machine = Machine {
+                code: Vec::new(),
+                stack,
+                pc: 0,
+                ..Default::default()
+            };
+            let expected_machine = Machine {
+                code: Vec::new(),
+                stack: result,
+                pc: 1,
+                ..Default::default()
+            };
+            test_instruction_execution(instruction, &mut machine, expected_machine, &[], &[]);
+        }
+    }
+
+    #[test]
+    fn test_u128_arithmetic() {
+        let big = u64::MAX as u128 + 2;
+        let mut machine = Machine::new(vec![
+            Instruction::Push128(big),
+            Instruction::Push128(3),
+            Instruction::Mul128(),
+            Instruction::Push128(u128::MAX),
+            Instruction::Add128(),
+            Instruction::Push128(big * 3),
+            Instruction::Compare128(2, 0, 8),
+            Instruction::OutStr("not greater".to_string()),
+            Instruction::Compare128(0, 2, 10),
+            Instruction::OutStr("not greater".to_string()),
+        ]);
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+
+        // big * 3 + u128::MAX wraps to big * 3 - 1
+        assert_eq!(machine.stack, vec![3, 2, 3, 3]);
+        assert_eq!(output, b"not greater\n");
+        assert!(matches!(
+            Instruction::Add128().execute(
+                &mut Machine::new(Vec::new()),
+                &mut &b""[..],
+                &mut Vec::new()
+            ),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        let mut machine = Machine {
+            stack: vec![1, 2],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Instruction::Compare128(u64::MAX, 0, 0).execute(
+                &mut machine,
+                &mut &b""[..],
+                &mut Vec::new()
+            ),
+            Err(VmError::StackUnderflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut machine = Machine::new(vec![
+            Instruction::In(),
+            Instruction::Push(0),
+            Instruction::Eq(0, 1, 5),
+            Instruction::OutStr("nonzero".to_string()),
+            Instruction::Jmp(6),
+            Instruction::OutStr("zero".to_string()),
+        ]);
+        let mut input = BufReader::new(&b"0\n"[..]);
+        machine.step(&mut input, &mut Vec::new()).unwrap();
+        machine.step(&mut input, &mut Vec::new()).unwrap();
+
+        let mut fork = machine.fork().unwrap();
+        fork.stack[0] = 1;
+        fork.strings.alloc(b"fork".to_vec());
+
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"zero\n");
+        let mut output = Vec::new();
+        fork.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"nonzero\n");
+
+        assert_eq!(machine.stack, vec![0, 0]);
+        assert_eq!(fork.stack, vec![1, 0]);
+        assert!(machine.strings.is_empty());
+        assert_eq!(fork.strings.len(), 1);
+    }
+
+    #[test]
+    fn test_fork_with_open_files() {
+        let mut machine = Machine::new(Vec::new());
+        let file = File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+        let fd = machine.insert_file(file);
+        assert!(matches!(machine.fork(), Err(VmError::ForkWithOpenFiles)));
+
+        machine.file_table[fd as usize] = None;
+        assert!(machine.fork().unwrap().file_table.is_empty());
+    }
+
+    #[test]
+    fn test_io_break_mode() {
+        let mut machine = Machine {
+            code: vec![
+                Instruction::In(),
+                Instruction::InByte(),
+                Instruction::Out(1),
+            ],
+            config: VmConfig {
+                io_break_mode: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+
+        let result = machine.run(&mut &b"7\n"[..], &mut output).unwrap();
+        assert_eq!(
+            result,
+            RunResult::SuspendedAtIo {
+                instruction_index: 0,
+                io_type: IoType::In
+            }
+        );
+        machine.resume_io(Some(42)).unwrap();
+
+        let result = machine.run(&mut &b"A"[..], &mut output).unwrap();
+        assert_eq!(
+            result,
+            RunResult::SuspendedAtIo {
+                instruction_index: 1,
+                io_type: IoType::InByte
+            }
+        );
+        machine.resume_io(None).unwrap();
+
+        let result = machine.run(&mut &b"A"[..], &mut output).unwrap();
+        assert_eq!(
+            result,
+            RunResult::SuspendedAtIo {
+                instruction_index: 2,
+                io_type: IoType::Out
+            }
+        );
+        assert_eq!(machine.stack, vec![42, 65]);
+        assert!(output.is_empty());
+        machine.resume_io(None).unwrap();
+
+        let result = machine.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(result, RunResult::Finished);
+        assert_eq!(output, b"42\n");
+        assert!(matches!(
+            machine.resume_io(None),
+            Err(VmError::NotSuspendedAtIo)
+        ));
+    }
+
+    #[test]
+    fn test_resume_io_counts_as_step() {
+        let mut machine = Machine {
+            code: vec![Instruction::Push(1), Instruction::In()],
+            config: VmConfig {
+                io_break_mode: true,
+                max_stack_depth: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        let cost = machine.consumed_cost;
+        assert!(matches!(
+            machine.resume_io(Some(42)),
+            Err(VmError::QuotaExceeded { resource: "stack" })
+        ));
+        assert_eq!(machine.usage.steps, 2);
+        assert_eq!(
+            machine.consumed_cost,
+            cost + instruction_cost(&Instruction::In())
+        );
+    }
+
+    #[test]
+    fn test_clear_state() {
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::Push(2),
+            Instruction::Push(3),
+            Instruction::Push(4),
+            Instruction::Push(5),
+            Instruction::ClearStack(),
+            Instruction::ClearGlobals(),
+        ]);
+        machine.globals = vec![7, 8];
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+
+        assert!(machine.stack.is_empty());
+        assert_eq!(machine.globals, vec![0, 0]);
+
+        for instruction in [Instruction::ClearStack(), Instruction::ClearGlobals()] {
+            let mut machine = Machine {
+                code: vec![Instruction::Push(1), instruction],
+                config: VmConfig {
+                    sandbox_policy: SandboxPolicy {
+                        allow_state_reset: false,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            assert!(matches!(
+                machine.run(&mut &[][..], &mut Vec::new()),
+                Err(VmError::SandboxViolation { .. })
+            ));
+            assert_eq!(machine.stack, vec![1]);
+        }
+    }
+
+    #[test]
+    fn test_abs_jmp_if_neg() {
+        for (value, pc) in [
+            (-1i64 as u64, 7),
+            (1 << 63, 7),
+            (i64::MAX as u64, 1),
+            (0, 1),
+        ] {
+            let mut machine = Machine {
+                code: Vec::new(),
+                stack: vec![value, 0],
+                pc: 0,
+                ..Default::default()
+            };
+            let expected_machine = Machine {
+                code: Vec::new(),
+                stack: vec![value, 0],
+                pc,
+                ..Default::default()
+            };
+            test_instruction_execution(
+                Instruction::AbsJmpIfNeg(1, 7),
+                &mut machine,
+                expected_machine,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    #[test]
+    fn test_in_peek() {
+        let mut machine = Machine::new(vec![
+            Instruction::InPeek(),
+            Instruction::InByte(),
+            Instruction::InByte(),
+            Instruction::InPeek(),
+        ]);
+        machine.run(&mut &b"AB"[..], &mut Vec::new()).unwrap();
+
+        assert_eq!(machine.stack, vec![65, 65, 66, u64::MAX]);
+    }
+
+    #[test]
+    fn test_display_with_labels() {
+        let label_map = HashMap::from([(0, "loop".to_string()), (4, "end".to_string())]);
+
+        for (instruction, text) in [
+            (Instruction::Jmp(0), "JMP @loop"),
+            (Instruction::Eq(0, 1, 4), "EQ 0 1 @end"),
+            (Instruction::AbsJmpIfNeg(2, 0), "ABSJMPIFNEG 2 @loop"),
+            (Instruction::Gt(0, 1, 3), "GT 0 1 3"),
+            (Instruction::Push(0), "PUSH 0"),
+            (Instruction::Inc(4), "INC 4"),
+        ] {
+            assert_eq!(instruction.display_with_labels(&label_map), text);
+        }
+    }
+
+    #[test]
+    fn test_input_and_output_errors() {
+        let mut machine = Machine::new(vec![Instruction::In()]);
+        let error = machine
+            .run(&mut &b"12a\n"[..], &mut Vec::new())
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            VmError::ParseError {
+                ref raw_input,
+                expected_type: "u64",
+                instruction: "IN",
+            } if raw_input == "12a"
+        ));
+        assert_eq!(error.to_string(), "IN cannot parse \"12a\" as u64");
+
+        // The end of the input stops the machine like InByte
+        let mut machine = Machine::new(vec![Instruction::In(), Instruction::Push(1)]);
+        machine.run(&mut &b""[..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.pc, 0);
+
+        let mut machine = Machine::new(vec![Instruction::Push(256), Instruction::OutByte(0)]);
+        let error = machine.run(&mut &b""[..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(error, VmError::InvalidValue { value: 256 }));
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        let bits = f64::to_bits;
+        for (instruction, stack, result) in [
+            (Instruction::FAdd(0, 1), vec![bits(1.5), bits(2.25)], 3.75),
+            (Instruction::FSub(1, 0), vec![bits(1.5), bits(2.25)], -0.75),
+            (Instruction::FSub(0, 1), vec![bits(1.5), bits(2.25)], 0.75),
+            (Instruction::FMul(0, 1), vec![bits(-3.0), bits(0.5)], -1.5),
+            (Instruction::FDiv(1, 0), vec![bits(1.0), bits(4.0)], 0.25),
+            (
+                Instruction::FDiv(0, 1),
+                vec![bits(0.0), bits(1.0)],
+                f64::INFINITY,
+            ),
+            (
+                Instruction::FDiv(0, 1),
+                vec![bits(0.0), bits(-1.0)],
+                f64::NEG_INFINITY,
+            ),
+            (
+                Instruction::FAdd(0, 1),
+                vec![bits(f64::INFINITY), bits(1.0)],
+                f64::INFINITY,
+            ),
+        ] {
+            let mut machine = Machine {
+                stack,
+                ..Default::default()
+            };
+            instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(machine.stack, vec![bits(result)], "{}", instruction);
+        }
+
+        for (instruction, l, r) in [
+            (Instruction::FAdd(1, 0), f64::NAN, 1.0),
+            (Instruction::FSub(1, 0), f64::INFINITY, f64::INFINITY),
+            (Instruction::FMul(1, 0), f64::INFINITY, 0.0),
+            (Instruction::FDiv(1, 0), 0.0, 0.0),
+        ] {
+            let mut machine = Machine {
+                stack: vec![bits(l), bits(r)],
+                ..Default::default()
+            };
+            instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(machine.stack.len(), 1);
+            assert!(f64::from_bits(machine.stack[0]).is_nan(), "{}", instruction);
+        }
+
+        assert!(Instruction::FMul(0, 1).is_float_op());
+        assert!(Instruction::FEq(0, 1, 2).is_float_op());
+        assert!(!Instruction::Add(0, 1).is_float_op());
+    }
+
+    #[test]
+    fn test_float_comparisons() {
+        let bits = f64::to_bits;
+        for (instruction, l, r, jumps) in [
+            (Instruction::FGt(1, 0, 5), 2.5, -1.0, true),
+            (Instruction::FGt(1, 0, 5), -1.0, 2.5, false),
+            (Instruction::FLt(1, 0, 5), -1.0, 2.5, true),
+            (Instruction::FLt(1, 0, 5), 2.5, 2.5, false),
+            (Instruction::FEq(1, 0, 5), 2.5, 2.5, true),
+            (Instruction::FEq(1, 0, 5), 0.0, -0.0, true),
+            (Instruction::FGt(1, 0, 5), f64::INFINITY, f64::MAX, true),
+            (Instruction::FGt(1, 0, 5), f64::NAN, f64::NAN, false),
+            (Instruction::FGt(1, 0, 5), f64::NAN, 1.0, false),
+            (Instruction::FLt(1, 0, 5), f64::NAN, 1.0, false),
+            (Instruction::FLt(1, 0, 5), 1.0, f64::NAN, false),
+            (Instruction::FEq(1, 0, 5), f64::NAN, f64::NAN, false),
+        ] {
+            let mut machine = Machine {
+                stack: vec![bits(l), bits(r)],
+                ..Default::default()
+            };
+            let next = instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(
+                next,
+                if jumps { 5 } else { 1 },
+                "{} {} {}",
+                instruction,
+                l,
+                r
+            );
+            assert_eq!(machine.stack, vec![bits(l), bits(r)]);
+        }
+    }
+
+    #[test]
+    fn test_gcd_lcm() {
+        for (instruction, stack, result) in [
+            (Instruction::Gcd(0, 1), vec![12, 18], 6),
+            (Instruction::Gcd(1, 0), vec![7, 0], 7),
+            (Instruction::Gcd(1, 0), vec![0, 7], 7),
+            (Instruction::Gcd(0, 1), vec![0, 0], 0),
+            (Instruction::Lcm(0, 1), vec![4, 6], 12),
+            (Instruction::Lcm(0, 1), vec![0, 5], 0),
+            (Instruction::Lcm(0, 1), vec![0, 0], 0),
+            (Instruction::Lcm(0, 1), vec![u64::MAX, u64::MAX], u64::MAX),
+        ] {
+            let mut machine = Machine {
+                stack,
+                ..Default::default()
+            };
+            instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(machine.stack, vec![result], "{}", instruction);
+        }
+
+        let mut machine = Machine {
+            stack: vec![1, u64::MAX, u64::MAX - 1],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Instruction::Lcm(0, 1).execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+            Err(VmError::ArithmeticOverflow { instruction: "LCM" })
+        ));
+    }
+
+    #[test]
+    fn test_mod_inverse() {
+        for (a, m, inverse) in [
+            (3, 7, 5),
+            (10, 7, 5),
+            (1, 1, 0),
+            (u64::MAX - 1, u64::MAX, u64::MAX - 1),
+        ] {
+            let mut machine = Machine {
+                stack: vec![m, a],
+                ..Default::default()
+            };
+            Instruction::ModInverse(0, 1)
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(machine.stack, vec![inverse], "{} mod {}", a, m);
+        }
+
+        for (a, m) in [(4, 8), (0, 7), (3, 0)] {
+            let mut machine = Machine {
+                stack: vec![m, a],
+                ..Default::default()
+            };
+            assert!(matches!(
+                Instruction::ModInverse(0, 1).execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+                Err(VmError::ArithmeticError {
+                    reason: "ModularInverseDoesNotExist"
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_out_formatted() {
+        let mut machine = Machine::new(vec![Instruction::OutFormatted(1, 0)]);
+        let format = machine.strings.alloc(b"%s=%04x (%d)\n".to_vec());
+        let name = machine.strings.alloc(b"value".to_vec());
+        machine.stack = vec![7, name, 255, 255, format, 3];
+        let mut output = Vec::new();
+        machine.run(&mut &b""[..], &mut output).unwrap();
+        assert_eq!(output, b"value=00ff (255)\n");
+        assert_eq!(machine.stack, vec![7]);
+
+        let mut machine = Machine::new(vec![Instruction::OutFormatted(1, 0)]);
+        let format = machine.strings.alloc(b"%d %d".to_vec());
+        machine.stack = vec![1, format, 1];
+        assert!(matches!(
+            machine.run(&mut &b""[..], &mut Vec::new()),
+            Err(VmError::FormatError { position: 3, .. })
+        ));
+
+        let mut machine = Machine::new(vec![Instruction::OutFormatted(1, 0)]);
+        let format = machine.strings.alloc(b"%d".to_vec());
+        machine.stack = vec![format, 2];
+        assert!(matches!(
+            machine.run(&mut &b""[..], &mut Vec::new()),
+            Err(VmError::StackUnderflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_not_eq() {
+        for (stack, next) in [(vec![4, 4], 1), (vec![4, 5], 5)] {
+            let mut machine = Machine {
+                stack: stack.clone(),
+                ..Default::default()
+            };
+            let result = Instruction::NotEq(0, 1, 5)
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(result, next);
+            assert_eq!(machine.stack, stack);
+        }
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        // The table sits right after the lookup, which always jumps
+        let source = "IN\n\
+            LOOKUPTABLE 0 2 3 8\n\
+            PUSH 2\nJMP 10\n\
+            PUSH 5\nJMP 12\n\
+            PUSH 40\nJMP 14\n\
+            OUTSTR \"default\"\nJMP 15\n\
+            OUTSTR \"two\"\nJMP 15\n\
+            OUTSTR \"five\"\nJMP 15\n\
+            OUTSTR \"forty\"\n";
+        for (input, expected) in [
+            ("2", "two\n"),
+            ("5", "five\n"),
+            ("40", "forty\n"),
+            ("1", "default\n"),
+            ("7", "default\n"),
+            ("41", "default\n"),
+        ] {
+            let mut machine = Machine::new(assemble(source).unwrap());
+            let (_, output) = machine.run_with_strings(input).unwrap();
+            assert_eq!(output, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_validate_lookup_tables() {
+        let machine = Machine::new(
+            assemble("LOOKUPTABLE 0 2 2 6\nJMP 6\nPUSH 5\nJMP 6\nPUSH 3\nJMP 6").unwrap(),
+        );
+        assert!(matches!(
+            machine.validate(),
+            Err(VmError::InvalidProgram(errors)) if errors == vec![
+                ValidationError::InvalidLookupTable { pc: 0, reason: "keys are not strictly ascending" }
+            ]
+        ));
+
+        let machine = Machine::new(assemble("LOOKUPTABLE 0 1 1 3\nOUT 0\nJMP 3").unwrap());
+        assert!(matches!(
+            machine.validate(),
+            Err(VmError::InvalidProgram(errors)) if errors == vec![
+                ValidationError::InvalidLookupTable { pc: 0, reason: "entry is not a PUSH followed by a JMP" }
+            ]
+        ));
+
+        let machine = Machine::new(assemble("LOOKUPTABLE 0 1 5 2\nPUSH 1\nJMP 0").unwrap());
+        assert!(matches!(
+            machine.validate(),
+            Err(VmError::InvalidProgram(errors)) if errors == vec![
+                ValidationError::InvalidLookupTable { pc: 0, reason: "table extends past the end of the program" }
+            ]
+        ));
+
+        let mut machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::LookupTable(0, u64::MAX, 1, 0),
+        ]);
+        assert!(matches!(
+            machine.run(&mut &b""[..], &mut Vec::new()),
+            Err(VmError::InvalidProgram(errors)) if errors == vec![
+                ValidationError::InvalidLookupTable { pc: 1, reason: "table extends past the end of the program" }
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_checksum() {
+        for (instruction, result) in [
+            (Instruction::Checksum(0, 2), 0b0110),
+            (Instruction::Checksum(1, 3), 0b1110),
+            (Instruction::Checksum(3, 1), 0b1000),
+            (Instruction::Checksum(2, 0), 0),
+        ] {
+            let mut machine = Machine {
+                stack: vec![0b1000, 0b0100, 0b0010, 0b0100],
+                ..Default::default()
+            };
+            instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(
+                machine.stack,
+                vec![0b1000, 0b0100, 0b0010, 0b0100, result],
+                "{}",
+                instruction
+            );
+        }
+
+        let mut machine = Machine {
+            stack: vec![1, 2],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Instruction::Checksum(1, 2).execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        machine.stack.push(3);
+        assert!(matches!(
+            Instruction::Checksum(2, u64::MAX).execute(
+                &mut machine,
+                &mut &b""[..],
+                &mut Vec::new()
+            ),
+            Err(VmError::StackUnderflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_type_assert() {
+        for (value, type_tag, fits) in [
+            (255, TYPE_U8, true),
+            (256, TYPE_U8, false),
+            (65535, TYPE_U16, true),
+            (65536, TYPE_U16, false),
+            (u32::MAX as u64, TYPE_U32, true),
+            (u32::MAX as u64 + 1, TYPE_U32, false),
+            (u64::MAX, TYPE_U64, true),
+        ] {
+            let mut machine = Machine {
+                stack: vec![value, 0],
+                ..Default::default()
+            };
+            let result = Instruction::TypeAssert(1, type_tag).execute(
+                &mut machine,
+                &mut &b""[..],
+                &mut Vec::new(),
+            );
+            if fits {
+                assert_eq!(result.unwrap(), 1);
+                assert_eq!(machine.stack, vec![value, 0]);
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(VmError::TypeError { value: v, .. }) if v == value
+                ));
+            }
+        }
+
+        let mut machine = Machine {
+            stack: vec![0],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Instruction::TypeAssert(0, 9).execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+            Err(VmError::InvalidValue { value: 9 })
+        ));
+    }
+
+    #[test]
+    fn test_stack_effect() {
+        assert_eq!(Instruction::Push(1).stack_effect(), (0, 1));
+        assert_eq!(Instruction::Add(0, 1).stack_effect(), (2, 1));
+        assert_eq!(Instruction::Copy(2).stack_effect(), (0, 1));
+        assert_eq!(Instruction::Out(0).stack_effect(), (0, 0));
+        assert_eq!(Instruction::In().stack_effect(), (0, 1));
+        assert_eq!(Instruction::Gt(0, 1, 5).stack_effect(), (0, 0));
+        assert_eq!(Instruction::DropRange(1, 3).stack_effect(), (3, 0));
+        assert_eq!(Instruction::Select(0, 0, 1).stack_effect(), (2, 1));
+        assert_eq!(
+            Instruction::CallNative(0).stack_effect(),
+            (usize::MAX, usize::MAX)
+        );
+
+        // Copy(0), Add(0, 1) doubles the top of the stack
+        let fused = Instruction::fuse(vec![Instruction::Copy(0), Instruction::Add(0, 1)]).unwrap();
+        assert_eq!(fused.stack_effect(), (1, 1));
+        let fused = Instruction::fuse(vec![Instruction::Push(1), Instruction::Push(2)]).unwrap();
+        assert_eq!(fused.stack_effect(), (0, 2));
+
+        // Running an instruction changes the depth by its effect
+        for (instruction, stack) in [
+            (Instruction::Add128(), vec![1, 2, 3, 4]),
+            (Instruction::TwoDup(), vec![1, 2]),
+            (Instruction::CopyRange(0, 2), vec![1, 2]),
+            (Instruction::Gcd(1, 0), vec![4, 6]),
+        ] {
+            let depth = stack.len();
+            let (pops, pushes) = instruction.stack_effect();
+            let mut machine = Machine {
+                stack,
+                ..Default::default()
+            };
+            instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(
+                machine.stack.len(),
+                depth - pops + pushes,
+                "{}",
+                instruction
+            );
+        }
+
+        let fused = Instruction::Fused(Box::new([
+            Instruction::CopyRange(0, u64::MAX),
+            Instruction::CopyRange(0, u64::MAX),
+        ]));
+        assert_eq!(fused.stack_effect(), (0, usize::MAX));
+    }
+
+    #[test]
+    fn test_in_out_bytes() {
+        let mut machine = Machine::default();
+        let base = machine.heap.alloc(4);
+        machine.stack = vec![base, 4];
+        let mut output = Vec::new();
+        Instruction::InBytes(0, 1)
+            .execute(&mut machine, &mut &b"abc"[..], &mut output)
+            .unwrap();
+        assert_eq!(machine.stack, vec![base, 4, 3]);
+        assert_eq!(machine.heap.read(base + 2).unwrap(), b'c' as u64);
+        assert_eq!(machine.heap.read(base + 3).unwrap(), 0);
+
+        Instruction::OutBytes(2, 0)
+            .execute(&mut machine, &mut &b""[..], &mut output)
+            .unwrap();
+        assert_eq!(output, b"abc");
+
+        // The block is only 4 words long
+        machine.stack = vec![base, 5];
+        let mut input = &b"abcde"[..];
+        assert!(matches!(
+            Instruction::InBytes(0, 1).execute(&mut machine, &mut input, &mut output),
+            Err(VmError::InvalidHeapAddress { address }) if address == base + 4
+        ));
+        assert_eq!(input, b"abcde");
+
+        machine.heap.write(base, 256).unwrap();
+        assert!(matches!(
+            Instruction::OutBytes(1, 0).execute(&mut machine, &mut &b""[..], &mut output),
+            Err(VmError::InvalidValue { value: 256 })
+        ));
+    }
+
+    #[test]
+    fn test_gt_or_eq() {
+        for (stack, next) in [(vec![5, 4], 1), (vec![4, 4], 5), (vec![4, 5], 5)] {
+            let mut machine = Machine {
+                stack: stack.clone(),
+                ..Default::default()
+            };
+            let result = Instruction::GtOrEq(0, 1, 5)
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+            assert_eq!(result, next);
+            assert_eq!(machine.stack, stack);
+        }
+    }
+
+    #[test]
+    fn test_store_global() {
+        let mut machine = Machine {
+            stack: vec![5, 6],
+            globals: vec![0, 0],
+            ..Default::default()
+        };
+        for instruction in [
+            Instruction::StoreGlobal(0, 1),
+            Instruction::StoreGlobalPop(1),
+        ] {
+            instruction
+                .execute(&mut machine, &mut &b""[..], &mut Vec::new())
+                .unwrap();
+        }
+        assert_eq!(machine.globals, vec![5, 6]);
+        assert_eq!(machine.stack, vec![5]);
+
+        for instruction in [
+            Instruction::StoreGlobal(2, 0),
+            Instruction::StoreGlobalPop(2),
+        ] {
+            assert!(matches!(
+                instruction.execute(&mut machine, &mut &b""[..], &mut Vec::new()),
+                Err(VmError::InvalidGlobal { index: 2 })
+            ));
+        }
+        assert_eq!(machine.stack, vec![5]);
+    }
+
+    #[test]
+    fn test_run_inspected() {
+        let mut machine = Machine {
+            code: vec![
+                Instruction::Push(2),
+                Instruction::Push(0),
+                Instruction::Dec(1),
+                Instruction::Eq(0, 1, 5),
+                Instruction::Jmp(2),
+            ],
+            stack: Vec::new(),
+            pc: 0,
+            ..Default::default()
+        };
+        let mut trace = Vec::new();
+        machine
+            .run_inspected(&mut &[][..], &mut Vec::new(), |pc, _, stack| {
+                trace.push((pc, stack.len()))
+            })
+            .unwrap();
+
+        assert_eq!(
+            trace,
+            vec![(0, 0), (1, 1), (2, 2), (3, 2), (4, 2), (2, 2), (3, 2)]
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_serialization {
+    use super::*;
+
+    fn test_serialize_deserialize(instruction: Instruction) {
+        let mut serialized = Vec::new();
+        instruction
+            .serialize(&mut serialized, Encoding::Fixed(Endianness::Little))
+            .unwrap();
+
+        let mut deserialized = &serialized[..];
+        let instruction_back =
+            Instruction::deserialize(&mut deserialized, Encoding::Fixed(Endianness::Little))
+                .unwrap();
+
+        assert_eq!(instruction, instruction_back);
+    }
+
+    #[test]
+    fn test_serialize_push() {
+        test_serialize_deserialize(Instruction::Push(42));
+    }
+
+    #[test]
+    fn test_serialize_out() {
         test_serialize_deserialize(Instruction::Out(1));
     }
 
@@ -616,4 +5000,235 @@ mod test_serialization {
     fn test_serialization_jmp() {
         test_serialize_deserialize(Instruction::Jmp(6));
     }
+
+    #[test]
+    fn test_serialization_out_str_no_nl() {
+        test_serialize_deserialize(Instruction::OutStrNoNl("Hello, world!".to_string()));
+    }
+
+    #[test]
+    fn test_serialization_call_native() {
+        test_serialize_deserialize(Instruction::CallNative(80));
+    }
+
+    #[test]
+    fn test_serialization_in_env() {
+        test_serialize_deserialize(Instruction::InEnv());
+    }
+
+    #[test]
+    fn test_serialization_get_arg() {
+        test_serialize_deserialize(Instruction::GetArg(2));
+    }
+
+    #[test]
+    fn test_serialization_exit_code() {
+        test_serialize_deserialize(Instruction::ExitCode(1));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_string_heap() {
+        test_serialize_deserialize(Instruction::StrAlloc(1));
+        test_serialize_deserialize(Instruction::StrStore(2, 1, 0));
+        test_serialize_deserialize(Instruction::StrLoad(1, 0));
+        test_serialize_deserialize(Instruction::StrLen(3));
+        test_serialize_deserialize(Instruction::StrCat(1, 0));
+        test_serialize_deserialize(Instruction::StrFree(4));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_out_str_handle() {
+        test_serialize_deserialize(Instruction::OutStrHandle(1));
+        test_serialize_deserialize(Instruction::OutStrHandleLn(0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_str_cmp() {
+        test_serialize_deserialize(Instruction::StrCmp(1, 0, 12));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_str_int_conversion() {
+        test_serialize_deserialize(Instruction::StrToInt(0));
+        test_serialize_deserialize(Instruction::IntToStr(2));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_str_slice() {
+        test_serialize_deserialize(Instruction::StrSlice(2, 1, 0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_multi_ret() {
+        test_serialize_deserialize(Instruction::MultiRet(3));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_cond_move() {
+        test_serialize_deserialize(Instruction::CondMove(2, 1, 0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_select() {
+        test_serialize_deserialize(Instruction::Select(2, 1, 0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_pair_instructions() {
+        test_serialize_deserialize(Instruction::TwoDup());
+        test_serialize_deserialize(Instruction::TwoDrop());
+        test_serialize_deserialize(Instruction::TwoSwap());
+    }
+
+    #[test]
+    fn test_serialize_deserialize_out_csv() {
+        test_serialize_deserialize(Instruction::OutCSV(0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_cmp_push() {
+        test_serialize_deserialize(Instruction::CmpPush(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_crc32() {
+        test_serialize_deserialize(Instruction::Crc32(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_hash64() {
+        test_serialize_deserialize(Instruction::Hash64(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_deserialize_file_descriptors() {
+        test_serialize_deserialize(Instruction::FdOpen(1, 0));
+        test_serialize_deserialize(Instruction::FdRead(1, 0));
+        test_serialize_deserialize(Instruction::FdWrite(0, 1));
+        test_serialize_deserialize(Instruction::FdClose(2));
+    }
+
+    #[test]
+    fn test_serialize_copy_range() {
+        test_serialize_deserialize(Instruction::CopyRange(1, 2));
+    }
+
+    #[test]
+    fn test_serialize_drop_range() {
+        test_serialize_deserialize(Instruction::DropRange(1, 3));
+    }
+
+    #[test]
+    fn test_serialize_saturating_arithmetic() {
+        test_serialize_deserialize(Instruction::SatAdd(0, 1));
+        test_serialize_deserialize(Instruction::SatSub(1, 0));
+        test_serialize_deserialize(Instruction::SatInc(2));
+        test_serialize_deserialize(Instruction::SatDec(3));
+    }
+
+    #[test]
+    fn test_serialize_wrapping_arithmetic() {
+        test_serialize_deserialize(Instruction::WrapAdd(0, 1));
+        test_serialize_deserialize(Instruction::WrapSub(1, 0));
+        test_serialize_deserialize(Instruction::WrapInc(2));
+        test_serialize_deserialize(Instruction::WrapDec(3));
+    }
+
+    #[test]
+    fn test_serialize_u128() {
+        test_serialize_deserialize(Instruction::Push128(u128::MAX - 7));
+        test_serialize_deserialize(Instruction::Add128());
+        test_serialize_deserialize(Instruction::Mul128());
+        test_serialize_deserialize(Instruction::Compare128(2, 0, 5));
+    }
+
+    #[test]
+    fn test_serialize_clear_state() {
+        test_serialize_deserialize(Instruction::ClearStack());
+        test_serialize_deserialize(Instruction::ClearGlobals());
+    }
+
+    #[test]
+    fn test_serialize_abs_jmp_if_neg() {
+        test_serialize_deserialize(Instruction::AbsJmpIfNeg(1, 7));
+    }
+
+    #[test]
+    fn test_serialize_in_peek() {
+        test_serialize_deserialize(Instruction::InPeek());
+    }
+
+    #[test]
+    fn test_serialize_float_arithmetic() {
+        test_serialize_deserialize(Instruction::FAdd(0, 1));
+        test_serialize_deserialize(Instruction::FSub(1, 0));
+        test_serialize_deserialize(Instruction::FMul(2, 3));
+        test_serialize_deserialize(Instruction::FDiv(3, 2));
+    }
+
+    #[test]
+    fn test_serialize_float_comparisons() {
+        test_serialize_deserialize(Instruction::FGt(0, 1, 2));
+        test_serialize_deserialize(Instruction::FLt(1, 0, 300));
+        test_serialize_deserialize(Instruction::FEq(2, 3, 4));
+    }
+
+    #[test]
+    fn test_serialize_gcd_lcm() {
+        test_serialize_deserialize(Instruction::Gcd(0, 1));
+        test_serialize_deserialize(Instruction::Lcm(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_out_formatted() {
+        test_serialize_deserialize(Instruction::OutFormatted(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_not_eq() {
+        test_serialize_deserialize(Instruction::NotEq(0, 1, 7));
+    }
+
+    #[test]
+    fn test_serialize_lookup_table() {
+        test_serialize_deserialize(Instruction::LookupTable(0, 10, 4, 300));
+    }
+
+    #[test]
+    fn test_serialize_checksum() {
+        test_serialize_deserialize(Instruction::Checksum(1, 4));
+    }
+
+    #[test]
+    fn test_serialize_type_assert() {
+        test_serialize_deserialize(Instruction::TypeAssert(2, TYPE_U16));
+    }
+
+    #[test]
+    fn test_serialize_measure() {
+        test_serialize_deserialize(Instruction::MeasureStart(0));
+        test_serialize_deserialize(Instruction::MeasureEnd(3));
+    }
+
+    #[test]
+    fn test_serialize_in_out_bytes() {
+        test_serialize_deserialize(Instruction::InBytes(0, 1));
+        test_serialize_deserialize(Instruction::OutBytes(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_gt_or_eq() {
+        test_serialize_deserialize(Instruction::GtOrEq(0, 1, 7));
+    }
+
+    #[test]
+    fn test_serialize_mod_inverse() {
+        test_serialize_deserialize(Instruction::ModInverse(1, 0));
+    }
+
+    #[test]
+    fn test_serialize_store_global() {
+        test_serialize_deserialize(Instruction::StoreGlobal(3, 0));
+        test_serialize_deserialize(Instruction::StoreGlobalPop(3));
+    }
 }
@@ -0,0 +1,212 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Cursor, Read, Write},
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    bundle::read_symbol_table,
+    encoding::{
+        check_flags, Endianness, FLAG_DEBUG_INFO, FLAG_INSTRUCTION_COUNT, FLAG_MSGPACK,
+        FLAG_SYMBOLS, MAGIC,
+    },
+    Encoding, Instruction, Machine, RunResult, ValidationError, VmError,
+};
+
+// A bytecode file mapped into memory. Instructions are decoded from the
+// mapping when they run, only their offsets are kept
+pub struct MappedBytecode {
+    mmap: Mmap,
+    encoding: Encoding,
+    // Byte offset of every instruction, indexed by pc
+    offsets: Vec<usize>,
+    errors: Vec<ValidationError>,
+}
+
+// Maps the bytecode file at path, with or without a header. The file must
+// not be modified while it is mapped
+pub fn mmap_bytecode(path: &Path) -> io::Result<MappedBytecode> {
+    let file = File::open(path)?;
+    // Safety: the mapping is only read, and the caller guarantees that the
+    // file is not changed while it is mapped
+    let mmap = unsafe { Mmap::map(&file)? };
+    let (encoding, start, count) = code_section(&mmap)?;
+
+    let mut cursor = Cursor::new(&mmap[..]);
+    cursor.set_position(start as u64);
+    let mut offsets = Vec::new();
+    let mut targets = Vec::new();
+    while count.map_or(cursor.position() < mmap.len() as u64, |count| {
+        (offsets.len() as u64) < count
+    }) {
+        offsets.push(cursor.position() as usize);
+        if let Some(target) = Instruction::deserialize(&mut cursor, encoding)?.jump_target() {
+            targets.push((offsets.len() as u64 - 1, target));
+        }
+    }
+    let errors = targets
+        .into_iter()
+        .filter(|&(_, target)| target > offsets.len() as u64)
+        .map(|(pc, target)| ValidationError::InvalidJumpTarget { pc, target })
+        .collect();
+
+    Ok(MappedBytecode {
+        mmap,
+        encoding,
+        offsets,
+        errors,
+    })
+}
+
+// The encoding, the offset of the first instruction and the instruction
+// count of a bytecode file, if the count is needed to find the end of the code
+fn code_section(bytes: &[u8]) -> io::Result<(Encoding, usize, Option<u64>)> {
+    let Some(rest) = bytes.strip_prefix(&MAGIC[..]) else {
+        return Ok((Encoding::Fixed(Endianness::Little), 0, None));
+    };
+    let [version, endianness, flags, ..] = *rest else {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated bytecode header",
+        ));
+    };
+    let encoding = Encoding::from_header(version, endianness)?;
+    if flags & FLAG_MSGPACK != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "MessagePack bytecode cannot be mapped",
+        ));
+    }
+    check_flags(flags)?;
+
+    let mut cursor = Cursor::new(&rest[3..]);
+    let count = if flags & FLAG_INSTRUCTION_COUNT != 0 {
+        let mut buf = [0; 4];
+        cursor.read_exact(&mut buf)?;
+        Some(match endianness {
+            0x02 => u32::from_be_bytes(buf),
+            _ => u32::from_le_bytes(buf),
+        } as u64)
+    } else {
+        None
+    };
+    if flags & FLAG_SYMBOLS != 0 {
+        read_symbol_table(&mut cursor, encoding)?;
+    }
+    // The debug section follows the code, so the count marks its end
+    let count = count.filter(|_| flags & FLAG_DEBUG_INFO != 0);
+    Ok((
+        encoding,
+        MAGIC.len() + 3 + cursor.position() as usize,
+        count,
+    ))
+}
+
+impl MappedBytecode {
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    // Decodes the instruction at pc, or returns None past the end
+    pub fn instruction(&self, pc: u64) -> io::Result<Option<Instruction>> {
+        let Some(&offset) = self.offsets.get(pc as usize) else {
+            return Ok(None);
+        };
+        Instruction::deserialize(&mut &self.mmap[offset..], self.encoding).map(Some)
+    }
+}
+
+impl Machine {
+    // Like run, but executes the mapped program in place of the code
+    // of the machine
+    pub fn run_mapped<W: Write, R: Read>(
+        &mut self,
+        mapped: &MappedBytecode,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<RunResult, VmError> {
+        if !mapped.errors.is_empty() {
+            return Err(VmError::InvalidProgram(mapped.errors.clone()));
+        }
+        let mut input = BufReader::new(input);
+        while let Some(instruction) = mapped.instruction(self.pc)? {
+            if !self.execute_step(&instruction, &mut input, output)? {
+                break;
+            }
+        }
+        Ok(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, serialize_code, write_bytecode, write_bytecode_with_debug, DebugInfo};
+
+    fn map(name: &str, bytes: &[u8]) -> MappedBytecode {
+        let path = std::env::temp_dir().join(format!(
+            "bytecode_vm_mapped_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, bytes).unwrap();
+        let mapped = mmap_bytecode(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        mapped
+    }
+
+    #[test]
+    fn test_run_mapped() {
+        let code =
+            assemble("PUSH 0\nPUSH 3\nOUT 0\nDEC 0\nEQ 0 1 6\nJMP 2\nOUTSTR \"done\"").unwrap();
+        let mut expected = Vec::new();
+        Machine::new(code.clone())
+            .run(&mut &[][..], &mut expected)
+            .unwrap();
+
+        let mut headerless = Vec::new();
+        serialize_code(&code, &mut headerless).unwrap();
+        let mut compact = Vec::new();
+        write_bytecode(&code, Encoding::Compact, &mut compact).unwrap();
+        let mut with_debug = Vec::new();
+        let debug_info = DebugInfo {
+            line_map: vec![Some(1); code.len()],
+            source_file: Some("loop.src".to_string()),
+        };
+        write_bytecode_with_debug(&code, &debug_info, Encoding::Varint, &mut with_debug).unwrap();
+
+        for (name, bytes) in [
+            ("headerless", headerless),
+            ("compact", compact),
+            ("debug", with_debug),
+        ] {
+            let mapped = map(name, &bytes);
+            assert_eq!(mapped.len(), code.len());
+            assert_eq!(mapped.instruction(6).unwrap(), code.get(6).cloned());
+
+            let mut output = Vec::new();
+            let mut machine = Machine::default();
+            machine
+                .run_mapped(&mapped, &mut &[][..], &mut output)
+                .unwrap();
+            assert_eq!(output, expected);
+        }
+        assert_eq!(expected, b"3\n2\n1\ndone\n");
+    }
+
+    #[test]
+    fn test_run_mapped_validates() {
+        let mut bytes = Vec::new();
+        serialize_code(&[Instruction::Jmp(5)], &mut bytes).unwrap();
+        let mapped = map("invalid", &bytes);
+
+        let result = Machine::default().run_mapped(&mapped, &mut &[][..], &mut Vec::new());
+        assert!(matches!(result, Err(VmError::InvalidProgram(_))));
+    }
+}
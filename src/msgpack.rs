@@ -0,0 +1,44 @@
+use std::io::{self, Read, Write};
+
+use crate::Instruction;
+
+// MessagePack encoding of instructions for tools outside of Rust. The code
+// is a single array of instructions in rmp-serde's default representation
+
+pub fn serialize_msgpack<W: Write>(instructions: &[Instruction], writer: &mut W) -> io::Result<()> {
+    rmp_serde::encode::write(writer, instructions).map_err(io::Error::other)
+}
+
+pub fn deserialize_msgpack<R: Read>(reader: &mut R) -> io::Result<Vec<Instruction>> {
+    rmp_serde::decode::from_read(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_bytecode, write_msgpack_bytecode};
+
+    fn sample() -> Vec<Instruction> {
+        vec![
+            Instruction::Push(u64::MAX),
+            Instruction::In(),
+            Instruction::OutStr("hi".to_string()),
+            Instruction::Gt(0, 1, 2),
+            Instruction::fuse(vec![Instruction::Copy(0), Instruction::Add(0, 1)]).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let mut buf = Vec::new();
+        serialize_msgpack(&sample(), &mut buf).unwrap();
+        assert_eq!(deserialize_msgpack(&mut &buf[..]).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_msgpack_bytecode_is_detected() {
+        let mut buf = Vec::new();
+        write_msgpack_bytecode(&sample(), &mut buf).unwrap();
+        assert_eq!(read_bytecode(&mut &buf[..]).unwrap(), sample());
+    }
+}
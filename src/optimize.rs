@@ -0,0 +1,520 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{remap_targets, Instruction};
+
+// Replaces every Copy(0), Add(0, 1) pair, which doubles the top of the
+// stack, with a single fused instruction and rewrites jump targets to match.
+// A pair is left alone when something jumps to its second instruction
+pub fn fuse_copy_add(code: &[Instruction]) -> Vec<Instruction> {
+    replace_sequences(code, |_, window| match window {
+        [Instruction::Copy(0), Instruction::Add(0, 1), ..] => {
+            Some((2, Instruction::fuse(window[..2].to_vec()).unwrap()))
+        }
+        _ => None,
+    })
+}
+
+// Replaces every Eq(l, r, pc + 2), Jmp(target) pair at pc, which jumps to
+// target when the values differ, with NotEq(l, r, target) and rewrites jump
+// targets to match. A pair is left alone when something jumps to its Jmp
+pub fn fuse_eq_jmp(code: &[Instruction]) -> Vec<Instruction> {
+    replace_sequences(code, |pc, window| match window {
+        [Instruction::Eq(l, r, skip), Instruction::Jmp(target), ..] if *skip == pc as u64 + 2 => {
+            Some((2, Instruction::NotEq(*l, *r, *target)))
+        }
+        _ => None,
+    })
+}
+
+// Calls replace with each index and the code starting at it. When it returns
+// Some((n, instruction)), the next n instructions are replaced by that one
+// instruction, unless something jumps into the middle of them
+fn replace_sequences<F>(code: &[Instruction], mut replace: F) -> Vec<Instruction>
+where
+    F: FnMut(usize, &[Instruction]) -> Option<(usize, Instruction)>,
+{
+    let mut is_target = vec![false; code.len() + 1];
+    for target in code.iter().filter_map(Instruction::jump_target) {
+        if let Some(flag) = is_target.get_mut(target as usize) {
+            *flag = true;
+        }
+    }
+
+    // new_index[i] is the index of the instruction that old index i ends up in
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut replaced = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        new_index.push(replaced.len() as u64);
+        if let Some((n, instruction)) = replace(i, &code[i..]) {
+            if n > 0 && !is_target[i + 1..i + n].contains(&true) {
+                new_index.extend(std::iter::repeat_n(replaced.len() as u64, n - 1));
+                replaced.push(instruction);
+                i += n;
+                continue;
+            }
+        }
+        replaced.push(code[i].clone());
+        i += 1;
+    }
+    new_index.push(replaced.len() as u64);
+
+    remap_targets(&mut replaced, &new_index);
+    replaced
+}
+
+// Removes the instructions that cannot be reached from the first one and
+// rewrites jump targets to match. Code containing MultiRet, whose target is
+// only known at run time, or LookupTable, whose table is never executed, is
+// returned unchanged
+pub fn eliminate_dead_code(code: &[Instruction]) -> Vec<Instruction> {
+    if code.iter().any(|i| {
+        matches!(
+            i,
+            Instruction::MultiRet(_) | Instruction::LookupTable(_, _, _, _)
+        )
+    }) {
+        return code.to_vec();
+    }
+
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        match reachable.get_mut(pc) {
+            Some(flag) if !*flag => *flag = true,
+            _ => continue,
+        }
+        if let Some(target) = code[pc].jump_target() {
+            pending.push(target as usize);
+        }
+        if !matches!(code[pc], Instruction::Jmp(_)) {
+            pending.push(pc + 1);
+        }
+    }
+
+    retain_instructions(code, &reachable)
+}
+
+// Removes every TypeAssert, for code whose types have been checked
+// statically, and rewrites jump targets to match
+pub fn strip_type_asserts(code: &[Instruction]) -> Vec<Instruction> {
+    let keep: Vec<_> = code
+        .iter()
+        .map(|i| !matches!(i, Instruction::TypeAssert(_, _)))
+        .collect();
+    retain_instructions(code, &keep)
+}
+
+// Keeps the instructions whose flag in keep is set
+fn retain_instructions(code: &[Instruction], keep: &[bool]) -> Vec<Instruction> {
+    // A removed instruction maps to the next kept one, so every jump lands
+    // where execution would have continued
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = Vec::with_capacity(code.len());
+    for (instruction, &keep) in code.iter().zip(keep) {
+        new_index.push(kept.len() as u64);
+        if keep {
+            kept.push(instruction.clone());
+        }
+    }
+    new_index.push(kept.len() as u64);
+
+    remap_targets(&mut kept, &new_index);
+    kept
+}
+
+// Evaluates arithmetic on constants ahead of time: Push(a), Push(b),
+// Add(0, 1) becomes Push(a + b) and Push(a) followed by Inc(0) or Dec(0)
+// becomes a single push. Folds that would overflow are skipped
+pub fn fold_constants(code: &[Instruction]) -> Vec<Instruction> {
+    let mut code = code.to_vec();
+    loop {
+        let folded = replace_sequences(&code, |_, window| match *window {
+            [Instruction::Push(a), Instruction::Push(b), Instruction::Add(0, 1) | Instruction::Add(1, 0), ..] => {
+                Some((3, Instruction::Push(a.checked_add(b)?)))
+            }
+            [Instruction::Push(a), Instruction::Inc(0), ..] => {
+                Some((2, Instruction::Push(a.checked_add(1)?)))
+            }
+            [Instruction::Push(a), Instruction::Dec(0), ..] => {
+                Some((2, Instruction::Push(a.checked_sub(1)?)))
+            }
+            _ => None,
+        });
+        // Each fold may expose another one, stop once nothing changes
+        if folded.len() == code.len() {
+            return folded;
+        }
+        code = folded;
+    }
+}
+
+// A transformation of code that keeps what the program does
+pub trait OptimizationPass {
+    fn name(&self) -> &'static str;
+
+    fn optimize(&self, code: Vec<Instruction>) -> Vec<Instruction>;
+}
+
+// Runs fold_constants
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConstantFolding;
+
+impl OptimizationPass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "constant-folding"
+    }
+
+    fn optimize(&self, code: Vec<Instruction>) -> Vec<Instruction> {
+        fold_constants(&code)
+    }
+}
+
+// Runs eliminate_dead_code
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeadCodeElimination;
+
+impl OptimizationPass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dead-code-elimination"
+    }
+
+    fn optimize(&self, code: Vec<Instruction>) -> Vec<Instruction> {
+        eliminate_dead_code(&code)
+    }
+}
+
+// Runs the peephole optimizations, fuse_eq_jmp and fuse_copy_add
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Peephole;
+
+impl OptimizationPass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn optimize(&self, code: Vec<Instruction>) -> Vec<Instruction> {
+        fuse_copy_add(&fuse_eq_jmp(&code))
+    }
+}
+
+// What a single pass of a pipeline run did
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassReport {
+    pub name: &'static str,
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PipelineReport {
+    pub passes: Vec<PassReport>,
+}
+
+impl PipelineReport {
+    pub fn instructions_removed(&self) -> usize {
+        self.passes
+            .iter()
+            .map(|pass| {
+                pass.instructions_before
+                    .saturating_sub(pass.instructions_after)
+            })
+            .sum()
+    }
+
+    pub fn total_duration(&self) -> Duration {
+        self.passes.iter().map(|pass| pass.duration).sum()
+    }
+}
+
+// An ordered list of optimization passes. The default pipeline folds
+// constants, removes dead code and then applies the peephole optimizations
+pub struct OptimizationPipeline {
+    pub passes: Vec<Box<dyn OptimizationPass>>,
+}
+
+impl OptimizationPipeline {
+    // A pipeline without any passes
+    pub fn new() -> Self {
+        OptimizationPipeline { passes: Vec::new() }
+    }
+
+    pub fn with_pass<P: OptimizationPass + 'static>(mut self, pass: P) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    // Runs every pass in order, timing each of them
+    pub fn run(&self, mut code: Vec<Instruction>) -> (Vec<Instruction>, PipelineReport) {
+        let mut report = PipelineReport::default();
+        for pass in &self.passes {
+            let instructions_before = code.len();
+            let start = Instant::now();
+            code = pass.optimize(code);
+            report.passes.push(PassReport {
+                name: pass.name(),
+                instructions_before,
+                instructions_after: code.len(),
+                duration: start.elapsed(),
+            });
+        }
+        (code, report)
+    }
+}
+
+impl Default for OptimizationPipeline {
+    fn default() -> Self {
+        OptimizationPipeline::new()
+            .with_pass(ConstantFolding)
+            .with_pass(DeadCodeElimination)
+            .with_pass(Peephole)
+    }
+}
+
+// Replaces every jump target found in offset_map with the pc it maps to.
+// Targets that are not in the map are left unchanged
+pub fn rewrite_targets(instructions: &mut [Instruction], offset_map: &HashMap<u64, u64>) {
+    for instruction in instructions.iter_mut() {
+        if let Some(target) = instruction.jump_target_mut() {
+            if let Some(&new_target) = offset_map.get(target) {
+                *target = new_target;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_bytecode, serialize_code, write_bytecode, Encoding, Machine};
+
+    fn run(code: Vec<Instruction>) -> Vec<u8> {
+        let mut machine = Machine {
+            code,
+            stack: vec![],
+            pc: 0,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_fuse_copy_add() {
+        // Doubles 1 until it exceeds 100, printing each step
+        let code = vec![
+            Instruction::Push(100),
+            Instruction::Push(1),
+            Instruction::Copy(0),
+            Instruction::Add(0, 1),
+            Instruction::Out(0),
+            Instruction::Gt(1, 0, 2),
+        ];
+        let fused = fuse_copy_add(&code);
+
+        assert_eq!(fused.len(), 5);
+        assert_eq!(
+            fused[2],
+            Instruction::Fused(vec![Instruction::Copy(0), Instruction::Add(0, 1)].into())
+        );
+        assert_eq!(fused[4], Instruction::Gt(1, 0, 2));
+        assert_eq!(run(fused), run(code));
+    }
+
+    #[test]
+    fn test_fuse_skips_jump_into_pair() {
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::Copy(0),
+            Instruction::Add(0, 1),
+            Instruction::Jmp(2),
+        ];
+        assert_eq!(fuse_copy_add(&code), code);
+    }
+
+    #[test]
+    fn test_fuse_eq_jmp() {
+        // Counts down from 3, printing each value until it reaches 0
+        let code = vec![
+            Instruction::Push(0),
+            Instruction::Push(3),
+            Instruction::Out(0),
+            Instruction::Dec(0),
+            Instruction::Eq(0, 1, 6),
+            Instruction::Jmp(2),
+            Instruction::Out(1),
+        ];
+        let fused = fuse_eq_jmp(&code);
+
+        assert_eq!(fused.len(), 6);
+        assert_eq!(fused[4], Instruction::NotEq(0, 1, 2));
+        assert_eq!(run(fused), run(code));
+
+        // Eq that does not skip exactly the Jmp is not an inequality test
+        let code = vec![
+            Instruction::Push(0),
+            Instruction::Eq(0, 0, 4),
+            Instruction::Jmp(3),
+            Instruction::Out(0),
+            Instruction::Out(0),
+        ];
+        assert_eq!(fuse_eq_jmp(&code), code);
+    }
+
+    #[test]
+    fn test_fused_serializes_as_components() {
+        let components = vec![Instruction::Copy(0), Instruction::Add(0, 1)];
+        let mut expected = Vec::new();
+        serialize_code(&components, &mut expected).unwrap();
+
+        let mut serialized = Vec::new();
+        serialize_code(&[Instruction::fuse(components).unwrap()], &mut serialized).unwrap();
+
+        assert_eq!(serialized, expected);
+    }
+
+    #[test]
+    fn test_fuse_rejects_branches() {
+        assert_eq!(Instruction::fuse(vec![]), None);
+        assert_eq!(
+            Instruction::fuse(vec![Instruction::Copy(0), Instruction::Jmp(0)]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rewrite_targets() {
+        let mut code = vec![
+            Instruction::Jmp(3),
+            Instruction::Gt(0, 1, 5),
+            Instruction::Eq(0, 1, 2),
+            Instruction::Push(3),
+        ];
+        rewrite_targets(&mut code, &HashMap::from([(3, 2), (5, 4)]));
+
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Jmp(2),
+                Instruction::Gt(0, 1, 4),
+                Instruction::Eq(0, 1, 2),
+                Instruction::Push(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_eliminate_dead_code() {
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::Jmp(3),
+            Instruction::OutStr("dead".to_string()),
+            Instruction::Out(0),
+            Instruction::Eq(0, 0, 6),
+            Instruction::OutStr("not taken".to_string()),
+            Instruction::Jmp(8),
+            Instruction::OutStr("dead".to_string()),
+        ];
+        let optimized = eliminate_dead_code(&code);
+
+        assert_eq!(
+            optimized,
+            vec![
+                Instruction::Push(1),
+                Instruction::Jmp(2),
+                Instruction::Out(0),
+                Instruction::Eq(0, 0, 5),
+                Instruction::OutStr("not taken".to_string()),
+                Instruction::Jmp(6),
+            ]
+        );
+        assert_eq!(run(optimized), run(code));
+    }
+
+    #[test]
+    fn test_strip_type_asserts() {
+        let code = vec![
+            Instruction::Push(3),
+            Instruction::TypeAssert(0, crate::TYPE_U8),
+            Instruction::Out(0),
+            Instruction::Dec(0),
+            Instruction::TypeAssert(0, crate::TYPE_U8),
+            Instruction::Push(0),
+            Instruction::Eq(0, 1, 9),
+            Instruction::DropRange(0, 1),
+            Instruction::Jmp(1),
+        ];
+        let stripped = strip_type_asserts(&code);
+
+        assert_eq!(stripped.len(), 7);
+        assert_eq!(stripped[5], Instruction::DropRange(0, 1));
+        assert_eq!(stripped[6], Instruction::Jmp(1));
+        assert_eq!(stripped[4], Instruction::Eq(0, 1, 7));
+        assert_eq!(run(stripped), run(code));
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let code = vec![
+            Instruction::Push(1),
+            Instruction::Push(2),
+            Instruction::Push(3),
+            Instruction::Add(0, 1),
+            Instruction::Add(1, 0),
+            Instruction::Inc(0),
+            Instruction::Out(0),
+            Instruction::Push(u64::MAX),
+            Instruction::Inc(0),
+        ];
+        let folded = fold_constants(&code);
+
+        assert_eq!(
+            folded,
+            vec![
+                Instruction::Push(7),
+                Instruction::Out(0),
+                Instruction::Push(u64::MAX),
+                Instruction::Inc(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_report() {
+        let code = vec![
+            Instruction::Push(20),
+            Instruction::Push(1),
+            Instruction::Add(0, 1),
+            Instruction::Copy(0),
+            Instruction::Add(0, 1),
+            Instruction::Eq(0, 0, 7),
+            Instruction::OutStr("not taken".to_string()),
+            Instruction::Out(0),
+            Instruction::Jmp(10),
+            Instruction::OutStr("dead".to_string()),
+        ];
+        let (optimized, report) = OptimizationPipeline::default().run(code.clone());
+
+        assert_eq!(
+            report.passes.iter().map(|p| p.name).collect::<Vec<_>>(),
+            ["constant-folding", "dead-code-elimination", "peephole"]
+        );
+        assert_eq!(optimized.len(), 6);
+        assert_eq!(report.instructions_removed(), 4);
+        assert_eq!(run(optimized.clone()), run(code.clone()));
+
+        // The optimized code still behaves the same once written to a file
+        let mut buf = Vec::new();
+        write_bytecode(&optimized, Encoding::Varint, &mut buf).unwrap();
+        assert_eq!(run(read_bytecode(&mut &buf[..]).unwrap()), run(code));
+
+        let (unchanged, report) = OptimizationPipeline::new().run(vec![Instruction::In()]);
+        assert_eq!(unchanged, vec![Instruction::In()]);
+        assert!(report.passes.is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::{self, Write};
+
+use crate::Machine;
+
+// Number of instructions shown on each side of pc
+const CONTEXT: u64 = 5;
+
+impl Machine {
+    // Human readable dump of the code around pc, the stack and the heaps.
+    // Meant for debugging, the format is stable but not parseable
+    pub fn pretty_print(&self) -> String {
+        let mut buf = Vec::new();
+        self.write_pretty_print(&mut buf)
+            .expect("writing to a Vec cannot fail");
+        String::from_utf8(buf).expect("the dump is valid UTF-8")
+    }
+
+    pub fn write_pretty_print<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "pc: {}", self.pc)?;
+
+        writeln!(w, "code:")?;
+        let start = self.pc.saturating_sub(CONTEXT) as usize;
+        let end = self.pc.saturating_add(CONTEXT + 1) as usize;
+        let end = end.min(self.code.len());
+        if start >= end {
+            writeln!(w, "  (pc is past the end of the code)")?;
+        }
+        for (index, instruction) in self.code.iter().enumerate().take(end).skip(start) {
+            let marker = if index as u64 == self.pc { "->" } else { "  " };
+            // Fused instructions display one component per line
+            let text = instruction.to_string().replace('\n', "; ");
+            writeln!(w, "{} {:>4}  {}", marker, index, text)?;
+        }
+
+        writeln!(w, "stack ({} values, bottom first):", self.stack.len())?;
+        for (index, value) in self.stack.iter().enumerate() {
+            let position = self.stack.len() - 1 - index;
+            writeln!(w, "  [{}] {}", position, value)?;
+        }
+
+        writeln!(w, "heap: {} words allocated", self.heap.allocated_words())?;
+        writeln!(w, "strings: {} live", self.strings.len())
+    }
+
+    // The loaded program as "index: instruction" lines
+    pub fn disassemble(&self) -> String {
+        self.disassemble_range(0, self.code.len() as u64)
+    }
+
+    // Like disassemble, for the instructions from start up to but not
+    // including end. The range is clamped to the program
+    pub fn disassemble_range(&self, start: u64, end: u64) -> String {
+        let end = end.min(self.code.len() as u64);
+        let mut text = String::new();
+        for index in start..end {
+            // Fused instructions display one component per line
+            let instruction = self.code[index as usize].to_string().replace('\n', "; ");
+            text.push_str(&format!("{}: {}\n", index, instruction));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Instruction, Machine};
+
+    #[test]
+    fn test_pretty_print() {
+        let mut machine = Machine::new(
+            (0..12)
+                .map(Instruction::Push)
+                .chain([
+                    Instruction::fuse(vec![Instruction::Copy(0), Instruction::Inc(0)]).unwrap(),
+                ])
+                .collect(),
+        );
+        machine.pc = 7;
+        machine.stack = vec![4, 2];
+        machine.heap.alloc(3);
+        machine.strings.alloc(b"s".to_vec());
+
+        assert_eq!(
+            machine.pretty_print(),
+            "\
+pc: 7
+code:
+      2  PUSH 2
+      3  PUSH 3
+      4  PUSH 4
+      5  PUSH 5
+      6  PUSH 6
+->    7  PUSH 7
+      8  PUSH 8
+      9  PUSH 9
+     10  PUSH 10
+     11  PUSH 11
+     12  COPY 0; INC 0
+stack (2 values, bottom first):
+  [1] 4
+  [0] 2
+heap: 3 words allocated
+strings: 1 live
+"
+        );
+
+        machine.pc = 20;
+        assert!(machine
+            .pretty_print()
+            .contains("(pc is past the end of the code)"));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let machine = Machine::new(vec![
+            Instruction::Push(1),
+            Instruction::OutStr("hi".to_string()),
+            Instruction::fuse(vec![Instruction::Copy(0), Instruction::Inc(0)]).unwrap(),
+        ]);
+
+        assert_eq!(
+            machine.disassemble(),
+            "0: PUSH 1\n1: OUTSTR \"hi\"\n2: COPY 0; INC 0\n"
+        );
+        assert_eq!(machine.disassemble_range(1, 2), "1: OUTSTR \"hi\"\n");
+        assert_eq!(machine.disassemble_range(2, 10), "2: COPY 0; INC 0\n");
+        assert_eq!(machine.disassemble_range(5, 10), "");
+    }
+}
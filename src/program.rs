@@ -0,0 +1,92 @@
+use std::{slice, vec};
+
+use crate::{Instruction, Machine};
+
+// The code of a program, ready to be loaded into a Machine
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+}
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Program { instructions }
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Instruction> {
+        self.instructions.iter()
+    }
+
+    pub fn into_instructions(self) -> Vec<Instruction> {
+        self.instructions
+    }
+}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Program::new(instructions)
+    }
+}
+
+impl FromIterator<Instruction> for Program {
+    fn from_iter<I: IntoIterator<Item = Instruction>>(iter: I) -> Self {
+        Program::new(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for Program {
+    type Item = Instruction;
+    type IntoIter = vec::IntoIter<Instruction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.instructions.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Program {
+    type Item = &'a Instruction;
+    type IntoIter = slice::Iter<'a, Instruction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.instructions.iter()
+    }
+}
+
+impl From<Program> for Machine {
+    fn from(program: Program) -> Self {
+        Machine::new(program.instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn test_collect_and_run() {
+        let program: Program = assemble("PUSH 2\nOUT 0\n").unwrap().into_iter().collect();
+        assert_eq!(program.len(), 2);
+        assert_eq!(program.iter().filter(|i| i.mnemonic() == "OUT").count(), 1);
+
+        let mut machine = Machine::from(program.clone());
+        let mut output = Vec::new();
+        machine.run(&mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"2\n");
+
+        let instructions: Vec<Instruction> = program.into_iter().collect();
+        assert_eq!(instructions, machine.code);
+    }
+}
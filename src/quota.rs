@@ -0,0 +1,259 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read, Write},
+};
+
+use crate::Machine;
+
+// Resources consumed by a machine, accumulated across runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub stack_depth: usize,
+    // Words allocated on the heap and bytes of live strings, 8 bytes per word
+    pub heap_bytes: usize,
+    pub output_bytes: u64,
+    pub input_bytes: u64,
+    // Instructions executed
+    pub steps: u64,
+}
+
+impl Machine {
+    pub fn current_usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            stack_depth: self.stack.len(),
+            heap_bytes: self.heap_bytes(),
+            ..self.usage
+        }
+    }
+
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.heap.allocated_words() * 8 + self.strings.total_bytes()
+    }
+}
+
+// Carried inside an io::Error so that a quota violation deep inside
+// a read or write surfaces as VmError::QuotaExceeded
+#[derive(Debug)]
+pub(crate) struct QuotaError(pub &'static str);
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} quota exceeded", self.0)
+    }
+}
+
+impl Error for QuotaError {}
+
+fn quota_error(resource: &'static str) -> io::Error {
+    io::Error::other(QuotaError(resource))
+}
+
+fn remaining(used: u64, limit: Option<u64>) -> u64 {
+    limit.map_or(u64::MAX, |limit| limit.saturating_sub(used))
+}
+
+// Counts the bytes written and refuses writes that would exceed the limit
+pub(crate) struct QuotaWriter<'a, W> {
+    inner: &'a mut W,
+    pub used: u64,
+    limit: Option<u64>,
+}
+
+impl<'a, W: Write> QuotaWriter<'a, W> {
+    pub fn new(inner: &'a mut W, used: u64, limit: Option<u64>) -> Self {
+        Self { inner, used, limit }
+    }
+}
+
+impl<W: Write> Write for QuotaWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > remaining(self.used, self.limit) {
+            return Err(quota_error("output"));
+        }
+        let written = self.inner.write(buf)?;
+        self.used += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Counts the bytes consumed and refuses reads once the limit is reached
+pub(crate) struct QuotaReader<'a, R> {
+    inner: &'a mut R,
+    pub used: u64,
+    limit: Option<u64>,
+}
+
+impl<'a, R: BufRead> QuotaReader<'a, R> {
+    pub fn new(inner: &'a mut R, used: u64, limit: Option<u64>) -> Self {
+        Self { inner, used, limit }
+    }
+}
+
+impl<R: BufRead> Read for QuotaReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for QuotaReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let remaining = remaining(self.used, self.limit);
+        if remaining == 0 {
+            return Err(quota_error("input"));
+        }
+        let buf = self.inner.fill_buf()?;
+        Ok(&buf[..buf.len().min(remaining.try_into().unwrap_or(usize::MAX))])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.used += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Instruction, Machine, VmConfig, VmError};
+
+    fn machine(code: Vec<Instruction>, config: VmConfig) -> Machine {
+        Machine::with_config(code, config)
+    }
+
+    #[test]
+    fn test_output_quota() {
+        let mut machine = machine(
+            vec![Instruction::OutStr("abc".to_string()); 3],
+            VmConfig {
+                max_output_bytes: Some(8),
+                ..Default::default()
+            },
+        );
+        let mut output = Vec::new();
+        let error = machine.run(&mut &[][..], &mut output).unwrap_err();
+
+        assert!(matches!(
+            error,
+            VmError::QuotaExceeded { resource: "output" }
+        ));
+        assert_eq!(output, b"abc\nabc\n");
+        assert_eq!(machine.pc, 2);
+        assert_eq!(machine.current_usage().output_bytes, 8);
+    }
+
+    #[test]
+    fn test_input_quota_persists_across_runs() {
+        let config = VmConfig {
+            max_input_bytes: Some(3),
+            ..Default::default()
+        };
+        let mut machine = machine(vec![Instruction::InByte(); 2], config);
+        machine.run(&mut &b"ab"[..], &mut Vec::new()).unwrap();
+        assert_eq!(machine.current_usage().input_bytes, 2);
+
+        machine.pc = 0;
+        let error = machine.run(&mut &b"cd"[..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            error,
+            VmError::QuotaExceeded { resource: "input" }
+        ));
+        assert_eq!(machine.stack, vec![97, 98, 99]);
+        assert_eq!(machine.current_usage().input_bytes, 3);
+    }
+
+    #[test]
+    fn test_stack_quota() {
+        let mut machine = machine(
+            vec![
+                Instruction::Push(1),
+                Instruction::Copy(0),
+                Instruction::Copy(0),
+            ],
+            VmConfig {
+                max_stack_depth: Some(2),
+                ..Default::default()
+            },
+        );
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+
+        assert!(matches!(
+            error,
+            VmError::QuotaExceeded { resource: "stack" }
+        ));
+        assert_eq!(machine.current_usage().stack_depth, 3);
+    }
+
+    #[test]
+    fn test_heap_quota() {
+        let mut machine = machine(
+            vec![
+                Instruction::Push(2),
+                Instruction::StrAlloc(0),
+                Instruction::Push(1),
+                Instruction::StrAlloc(0),
+            ],
+            VmConfig {
+                max_heap_bytes: Some(2),
+                ..Default::default()
+            },
+        );
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+
+        assert!(matches!(error, VmError::QuotaExceeded { resource: "heap" }));
+        assert_eq!(machine.current_usage().heap_bytes, 2);
+        assert_eq!(machine.pc, 3);
+    }
+
+    #[test]
+    fn test_heap_quota_checked_before_allocating() {
+        // Refused before allocating, so this does not abort the host
+        let mut machine = machine(
+            vec![Instruction::Push(1 << 62), Instruction::StrAlloc(0)],
+            VmConfig {
+                max_heap_bytes: Some(1024),
+                ..Default::default()
+            },
+        );
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(error, VmError::QuotaExceeded { resource: "heap" }));
+        assert_eq!(machine.current_usage().heap_bytes, 0);
+    }
+
+    #[test]
+    fn test_failed_allocation() {
+        let mut machine = machine(
+            vec![Instruction::Push(1 << 62), Instruction::StrAlloc(0)],
+            VmConfig::default(),
+        );
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+        assert!(matches!(error, VmError::AllocationFailed { bytes } if bytes == 1 << 62));
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut machine = machine(
+            vec![Instruction::Push(1), Instruction::Jmp(0)],
+            VmConfig {
+                step_limit: Some(5),
+                ..Default::default()
+            },
+        );
+        let error = machine.run(&mut &[][..], &mut Vec::new()).unwrap_err();
+
+        assert!(matches!(
+            error,
+            VmError::QuotaExceeded { resource: "steps" }
+        ));
+        assert_eq!(machine.stack, vec![1, 1, 1]);
+        assert_eq!(machine.pc, 1);
+        assert_eq!(machine.current_usage().steps, 5);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+// A replay file is a stream of (source: u8, value: u64) pairs, with the
+// value in little-endian byte order
+const SOURCE_INPUT: u8 = 0;
+const SOURCE_RANDOM: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayEvent {
+    // A byte read from the input
+    Input(u8),
+    // A random value handed to the program, e.g. by a native function
+    Random(u64),
+}
+
+impl ReplayEvent {
+    fn write<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        let (source, value) = match self {
+            ReplayEvent::Input(byte) => (SOURCE_INPUT, byte as u64),
+            ReplayEvent::Random(value) => (SOURCE_RANDOM, value),
+        };
+        writer.write_all(&[source])?;
+        writer.write_all(&value.to_le_bytes())
+    }
+
+    // Returns None at the end of the replay
+    fn read<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut source = [0];
+        if reader.read(&mut source)? == 0 {
+            return Ok(None);
+        }
+        let mut value = [0; 8];
+        reader.read_exact(&mut value)?;
+        let value = u64::from_le_bytes(value);
+        match source[0] {
+            SOURCE_INPUT => u8::try_from(value)
+                .map(|byte| Some(ReplayEvent::Input(byte)))
+                .map_err(|_| invalid_data("input event holds more than a byte")),
+            SOURCE_RANDOM => Ok(Some(ReplayEvent::Random(value))),
+            _ => Err(invalid_data("unknown replay event source")),
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Wraps the input of a machine and records every byte read from it,
+// and every random value passed to record_random, to a replay file
+pub struct ReplayRecorder<R, W> {
+    input: R,
+    replay: W,
+}
+
+impl<R: Read, W: Write> ReplayRecorder<R, W> {
+    pub fn new(input: R, replay: W) -> Self {
+        ReplayRecorder { input, replay }
+    }
+
+    pub fn record_random(&mut self, value: u64) -> io::Result<()> {
+        ReplayEvent::Random(value).write(&mut self.replay)
+    }
+
+    pub fn into_replay(mut self) -> io::Result<W> {
+        self.replay.flush()?;
+        Ok(self.replay)
+    }
+}
+
+impl<R: Read, W: Write> Read for ReplayRecorder<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        for &byte in &buf[..n] {
+            ReplayEvent::Input(byte).write(&mut self.replay)?;
+        }
+        Ok(n)
+    }
+}
+
+// Plays a replay file back: reading from it yields the recorded input,
+// and next_random the recorded random values, in their original order
+#[derive(Debug, Clone, Default)]
+pub struct ReplayPlayer {
+    events: VecDeque<ReplayEvent>,
+}
+
+impl ReplayPlayer {
+    pub fn new<R: Read>(replay: &mut R) -> io::Result<Self> {
+        let mut events = VecDeque::new();
+        while let Some(event) = ReplayEvent::read(replay)? {
+            events.push_back(event);
+        }
+        Ok(ReplayPlayer { events })
+    }
+
+    // The next recorded random value. Fails if the program asks for one
+    // where the recording read the input instead
+    pub fn next_random(&mut self) -> io::Result<u64> {
+        match self.events.front() {
+            Some(&ReplayEvent::Random(value)) => {
+                self.events.pop_front();
+                Ok(value)
+            }
+            _ => Err(invalid_data("replay diverged: expected a random value")),
+        }
+    }
+}
+
+impl Read for ReplayPlayer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.events.front() {
+                Some(&ReplayEvent::Input(byte)) => {
+                    buf[n] = byte;
+                    n += 1;
+                    self.events.pop_front();
+                }
+                // The input ends here in the recording
+                None => break,
+                Some(ReplayEvent::Random(_)) if n > 0 => break,
+                Some(ReplayEvent::Random(_)) => {
+                    return Err(invalid_data("replay diverged: expected input"));
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, Machine};
+
+    const ECHO: &str = "\
+loop:
+    INBYTE
+    PUSH 18446744073709551615
+    EQ 0 1 @end
+    OUTBYTE 1
+    JMP @loop
+end:
+";
+
+    #[test]
+    fn test_replay_reproduces_output() {
+        let code = assemble(ECHO).unwrap();
+
+        let mut recorder = ReplayRecorder::new(&b"hi\n"[..], Vec::new());
+        let mut recorded_output = Vec::new();
+        Machine::new(code.clone())
+            .run(&mut recorder, &mut recorded_output)
+            .unwrap();
+        let replay = recorder.into_replay().unwrap();
+        assert_eq!(replay.len(), 3 * 9);
+        assert_eq!(replay[..9], [SOURCE_INPUT, b'h', 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut player = ReplayPlayer::new(&mut &replay[..]).unwrap();
+        let mut replayed_output = Vec::new();
+        Machine::new(code)
+            .run(&mut player, &mut replayed_output)
+            .unwrap();
+        assert_eq!(replayed_output, recorded_output);
+        assert_eq!(replayed_output, b"hi\n");
+    }
+
+    #[test]
+    fn test_replay_random_values() {
+        let mut recorder = ReplayRecorder::new(&b"a"[..], Vec::new());
+        recorder.record_random(42).unwrap();
+        let mut byte = [0];
+        recorder.read_exact(&mut byte).unwrap();
+        let replay = recorder.into_replay().unwrap();
+
+        let mut player = ReplayPlayer::new(&mut &replay[..]).unwrap();
+        assert!(player.read(&mut byte).is_err());
+        assert_eq!(player.next_random().unwrap(), 42);
+        assert!(player.next_random().is_err());
+        assert_eq!(player.read(&mut byte).unwrap(), 1);
+        assert_eq!(byte, *b"a");
+        assert_eq!(player.read(&mut byte).unwrap(), 0);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{BufRead, Write};
+
+use crate::{Machine, VmError};
+
+// Native functions every program can rely on, registered by
+// VmStdlib::register_all under the indices below. Arguments are read from
+// the top of the stack without being popped, results are pushed
+
+// Writes the value at position 0 in decimal, without a newline
+pub const STDLIB_PRINT_INT: u64 = 0;
+// Writes a newline in the configured newline mode
+pub const STDLIB_PRINT_NEWLINE: u64 = 1;
+// Reads a line and pushes it parsed as a decimal integer
+pub const STDLIB_READ_INT: u64 = 2;
+// Reads a line, stores it without the line ending on the string heap
+// and pushes its handle
+pub const STDLIB_READ_LINE_AS_HANDLE: u64 = 3;
+// Pushes 1 if the strings with the handles at positions 1 and 0 are
+// equal, otherwise 0
+pub const STDLIB_STR_EQUAL: u64 = 4;
+// Pushes 1 if the string with the handle at position 1 starts with the
+// string with the handle at position 0, otherwise 0
+pub const STDLIB_STR_STARTS_WITH: u64 = 5;
+
+pub struct VmStdlib;
+
+impl VmStdlib {
+    // Registers every routine, replacing natives already registered
+    // under the same indices
+    pub fn register_all(machine: &mut Machine) {
+        machine.register_native_io(STDLIB_PRINT_INT, Box::new(print_int));
+        machine.register_native_io(STDLIB_PRINT_NEWLINE, Box::new(print_newline));
+        machine.register_native_io(STDLIB_READ_INT, Box::new(read_int));
+        machine.register_native_io(STDLIB_READ_LINE_AS_HANDLE, Box::new(read_line_as_handle));
+        machine.register_native(STDLIB_STR_EQUAL, Box::new(str_equal));
+        machine.register_native(STDLIB_STR_STARTS_WITH, Box::new(str_starts_with));
+    }
+}
+
+fn print_int(
+    machine: &mut Machine,
+    _input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<(), VmError> {
+    let value = machine.stack_value(0, "CALLNATIVE")?;
+    write!(output, "{}", value)?;
+    Ok(())
+}
+
+fn print_newline(
+    machine: &mut Machine,
+    _input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<(), VmError> {
+    output.write_all(machine.config.newline_mode.as_str().as_bytes())?;
+    Ok(())
+}
+
+fn read_int(
+    machine: &mut Machine,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<(), VmError> {
+    read_line_as_handle(machine, input, output)?;
+    let handle = machine.stack.pop().expect("a handle was just pushed");
+    let text = String::from_utf8_lossy(machine.strings.get(handle)?).into_owned();
+    machine.strings.free(handle)?;
+    let value = text.trim().parse().map_err(|_| VmError::ParseError {
+        raw_input: text,
+        expected_type: "u64",
+        instruction: "CALLNATIVE",
+    })?;
+    machine.stack.push(value);
+    Ok(())
+}
+
+fn read_line_as_handle(
+    machine: &mut Machine,
+    input: &mut dyn BufRead,
+    _output: &mut dyn Write,
+) -> Result<(), VmError> {
+    let mut line = Vec::new();
+    input.read_until(b'\n', &mut line)?;
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    let handle = machine.strings.alloc(line);
+    machine.stack.push(handle);
+    Ok(())
+}
+
+fn str_equal(machine: &mut Machine) -> Result<(), VmError> {
+    let first = machine.stack_value(1, "CALLNATIVE")?;
+    let second = machine.stack_value(0, "CALLNATIVE")?;
+    let equal = machine.strings.get(first)? == machine.strings.get(second)?;
+    machine.stack.push(equal as u64);
+    Ok(())
+}
+
+fn str_starts_with(machine: &mut Machine) -> Result<(), VmError> {
+    let string = machine.stack_value(1, "CALLNATIVE")?;
+    let prefix = machine.stack_value(0, "CALLNATIVE")?;
+    let starts_with = machine
+        .strings
+        .get(string)?
+        .starts_with(machine.strings.get(prefix)?);
+    machine.stack.push(starts_with as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, SandboxPolicy, VmConfig};
+
+    fn run(source: &str, input: &str) -> Result<(Machine, String), VmError> {
+        let mut machine = Machine::new(assemble(source).unwrap());
+        VmStdlib::register_all(&mut machine);
+        let (_, output) = machine.run_with_strings(input)?;
+        Ok((machine, output))
+    }
+
+    #[test]
+    fn test_read_and_print_int() {
+        let source = "CALLNATIVE 2\nINC 0\nCALLNATIVE 0\nCALLNATIVE 1\n";
+        let (_, output) = run(source, "41\n").unwrap();
+        assert_eq!(output, "42\n");
+
+        assert!(matches!(
+            run(source, "x\n"),
+            Err(VmError::ParseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_string_routines() {
+        let source = "CALLNATIVE 3\nCALLNATIVE 3\nCALLNATIVE 4\nCOPY 2\nCOPY 2\nCALLNATIVE 5\n";
+        let (machine, _) = run(source, "hello\r\nhe\n").unwrap();
+        assert_eq!(machine.stack[2], 0);
+        assert_eq!(machine.stack[5], 1);
+        assert_eq!(machine.strings.get(machine.stack[0]).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_io_routines_respect_sandbox() {
+        let mut machine = Machine {
+            code: assemble("PUSH 1\nCALLNATIVE 0").unwrap(),
+            config: VmConfig {
+                sandbox_policy: SandboxPolicy::deny_all(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        VmStdlib::register_all(&mut machine);
+        assert!(matches!(
+            machine.run_with_strings(""),
+            Err(VmError::SandboxViolation { .. })
+        ));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{Instruction, Machine};
+
+// What a stack value holds, tracked when VmConfig::type_tagging is set. A
+// 128-bit value takes two slots, the high word below the low one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TypeTag {
+    #[default]
+    U64,
+    U128Hi,
+    U128Lo,
+    F64,
+    StringHandle,
+    FdHandle,
+}
+
+impl Machine {
+    // The tag of every stack value, bottom first. Empty unless
+    // VmConfig::type_tagging is set. Values the host pushed are U64
+    pub fn stack_tags(&self) -> &[TypeTag] {
+        &self.tags
+    }
+
+    // Brings the tags up to date after the instruction ran on a stack of
+    // len_before values. Instructions that remove values in the middle of
+    // the stack remove the same tags; where the effect is not known the
+    // tags are only cut or padded with U64 to the length of the stack
+    pub(crate) fn update_tags(&mut self, instruction: &Instruction, len_before: usize) {
+        use Instruction::*;
+        self.tags.resize(len_before, TypeTag::U64);
+        let index = |pos: u64| {
+            let values = usize::try_from(pos).ok()?.checked_add(1)?;
+            len_before.checked_sub(values)
+        };
+        // Index of the bottom value of count values starting at pos
+        let bottom = |pos: u64, count: u64| index(pos.checked_add(count - 1)?);
+        match instruction {
+            Add(l, r)
+            | SatAdd(l, r)
+            | SatSub(l, r)
+            | WrapAdd(l, r)
+            | WrapSub(l, r)
+            | FAdd(l, r)
+            | FSub(l, r)
+            | FMul(l, r)
+            | FDiv(l, r)
+            | Gcd(l, r)
+            | Lcm(l, r)
+            | ModInverse(l, r) => {
+                // Mirrors Machine::take_operands
+                if let (Some(l), Some(r)) = (index(*l), index(*r)) {
+                    self.tags.remove(l);
+                    self.tags.remove(r - (r > l) as usize);
+                }
+            }
+            Select(a, b, cond) => {
+                let mut indices: Vec<_> =
+                    [a, b, cond].iter().filter_map(|pos| index(**pos)).collect();
+                indices.sort_unstable_by(|l, r| r.cmp(l));
+                indices.dedup();
+                for index in indices {
+                    self.tags.remove(index);
+                }
+            }
+            MultiRet(n) => {
+                if let Some(index) = index(*n) {
+                    self.tags.remove(index);
+                }
+            }
+            DropRange(pos, count) if *count > 0 => {
+                if let (Some(top), Some(bottom)) = (index(*pos), bottom(*pos, *count)) {
+                    self.tags.drain(bottom..=top);
+                }
+            }
+            Add128() | Mul128() => self.tags.truncate(len_before.saturating_sub(4)),
+            TwoDrop() => self.tags.truncate(len_before.saturating_sub(2)),
+            StoreGlobalPop(_) => self.tags.truncate(len_before.saturating_sub(1)),
+            TwoSwap() if len_before >= 4 => self.tags[len_before - 4..].rotate_left(2),
+            Copy(pos) => {
+                if let Some(index) = index(*pos) {
+                    self.tags.push(self.tags[index]);
+                }
+            }
+            CopyRange(pos, count) if *count > 0 => {
+                if let (Some(top), Some(bottom)) = (index(*pos), bottom(*pos, *count)) {
+                    self.tags.extend_from_within(bottom..=top);
+                }
+            }
+            TwoDup() if len_before >= 2 => self.tags.extend_from_within(len_before - 2..),
+            CondMove(src, dst, cond) => {
+                if let (Some(src), Some(dst), Some(cond)) = (index(*src), index(*dst), index(*cond))
+                {
+                    if self.stack[cond] != 0 {
+                        self.tags[dst] = self.tags[src];
+                    }
+                }
+            }
+            ClearStack() => self.tags.clear(),
+            _ => {}
+        }
+
+        if matches!(instruction, Push128(_) | Add128() | Mul128()) {
+            self.tags.extend([TypeTag::U128Hi, TypeTag::U128Lo]);
+        }
+        let tag = match instruction {
+            FAdd(_, _) | FSub(_, _) | FMul(_, _) | FDiv(_, _) => TypeTag::F64,
+            StrAlloc(_) | StrCat(_, _) | StrSlice(_, _, _) | IntToStr(_) => TypeTag::StringHandle,
+            FdOpen(_, _) => TypeTag::FdHandle,
+            _ => TypeTag::U64,
+        };
+        self.tags.resize(self.stack.len(), tag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, VmConfig};
+
+    fn run_tagged(source: &str) -> Machine {
+        let mut machine = Machine::with_config(
+            assemble(source).unwrap(),
+            VmConfig {
+                type_tagging: true,
+                ..Default::default()
+            },
+        );
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        machine
+    }
+
+    #[test]
+    fn test_stack_tags() {
+        use TypeTag::*;
+
+        let machine = run_tagged("PUSH 1\nPUSH128 0 5\nPUSH 2\nINTTOSTR 0\nCOPY 4");
+        assert_eq!(
+            machine.stack_tags(),
+            [U64, U128Hi, U128Lo, U64, StringHandle, U64]
+        );
+
+        // ADD removes its operands from under the 128-bit value
+        let machine = run_tagged("PUSH 1\nPUSH 2\nPUSH128 0 5\nADD 2 3\nINTTOSTR 0\n2SWAP");
+        assert_eq!(machine.stack_tags(), [U64, StringHandle, U128Hi, U128Lo]);
+
+        let machine = run_tagged(
+            "PUSH128 0 5\nPUSH128 0 6\nADD128\nPUSH 4607182418800017408\nCOPY 0\nFADD 0 1",
+        );
+        assert_eq!(machine.stack_tags(), [U128Hi, U128Lo, F64]);
+
+        let mut machine = Machine::new(assemble("PUSH128 0 5").unwrap());
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert!(machine.stack_tags().is_empty());
+    }
+
+    #[test]
+    fn test_large_positions() {
+        let mut machine = Machine {
+            stack: vec![1, 2, 3],
+            ..Default::default()
+        };
+        for instruction in [
+            Instruction::CopyRange(2, u64::MAX),
+            Instruction::DropRange(2, u64::MAX),
+            Instruction::Copy(u64::MAX),
+        ] {
+            machine.update_tags(&instruction, 3);
+            assert_eq!(machine.stack_tags(), [TypeTag::U64; 3]);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::Machine;
+
+impl Machine {
+    // Time spent executing each kind of instruction, by mnemonic, since
+    // the last call. Only recorded when VmConfig::time_instructions is set
+    pub fn take_timing(&mut self) -> HashMap<&'static str, Duration> {
+        std::mem::take(&mut self.timing)
+    }
+
+    // Time spent in each section bracketed by MeasureStart and MeasureEnd,
+    // by section id, since the last call
+    pub fn take_timers(&mut self) -> HashMap<u64, Duration> {
+        std::mem::take(&mut self.timers)
+    }
+}
+
+// Prints the timing as a table, slowest instruction first
+pub fn print_timing_report(timing: &HashMap<&'static str, Duration>) -> io::Result<()> {
+    write_timing_report(&mut io::stdout().lock(), timing)
+}
+
+pub fn write_timing_report<W: Write>(
+    w: &mut W,
+    timing: &HashMap<&'static str, Duration>,
+) -> io::Result<()> {
+    let mut rows: Vec<_> = timing.iter().collect();
+    // Ties are broken by name so the report is stable
+    rows.sort_by(|(a_name, a_time), (b_name, b_time)| b_time.cmp(a_time).then(a_name.cmp(b_name)));
+    writeln!(w, "{:<16} {:>12}", "instruction", "total")?;
+    for (name, time) in rows {
+        writeln!(w, "{:<16} {:>12?}", name, time)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instruction, VmConfig};
+
+    #[test]
+    fn test_take_timing() {
+        let mut machine = Machine::with_config(
+            vec![
+                Instruction::Push(1),
+                Instruction::Push(2),
+                Instruction::Add(0, 1),
+            ],
+            VmConfig {
+                time_instructions: true,
+                ..Default::default()
+            },
+        );
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+
+        let timing = machine.take_timing();
+        let mut names: Vec<_> = timing.keys().copied().collect();
+        names.sort();
+        assert_eq!(names, ["ADD", "PUSH"]);
+        assert!(machine.take_timing().is_empty());
+
+        let mut machine = Machine::new(vec![Instruction::Push(1)]);
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+        assert!(machine.take_timing().is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "measure_noop"))]
+    fn test_take_timers() {
+        use crate::assemble;
+
+        let mut machine = Machine::new(
+            assemble(
+                "PUSH 7\nMEASURESTART 0\nPUSH 0\nPUSH 3\nDEC 0\nEQ 0 1 7\nJMP 4\n\
+                 MEASUREEND 2\nPUSH 8\nMEASUREEND 0",
+            )
+            .unwrap(),
+        );
+        machine.run(&mut &[][..], &mut Vec::new()).unwrap();
+
+        let timers = machine.take_timers();
+        assert_eq!(timers.keys().collect::<Vec<_>>(), [&7]);
+        assert!(machine.take_timers().is_empty());
+    }
+
+    #[test]
+    fn test_timing_report() {
+        let timing = HashMap::from([
+            ("PUSH", Duration::from_micros(5)),
+            ("OUT", Duration::from_millis(3)),
+        ]);
+        let mut report = Vec::new();
+        write_timing_report(&mut report, &timing).unwrap();
+
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "instruction             total\n\
+             OUT                       3ms\n\
+             PUSH                      5µs\n"
+        );
+    }
+}
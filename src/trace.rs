@@ -0,0 +1,106 @@
+use std::{
+    io::{BufReader, Read, Write},
+    time::Instant,
+};
+
+use crate::{Machine, RunResult, VmError};
+
+// Runs the machine like Machine::run, additionally writing one Chrome Trace
+// Event Format "complete" event per executed instruction to trace_writer.
+// Timestamps and durations are in nanoseconds since the start of the run
+pub fn run_with_json_trace<R: Read, W: Write, T: Write>(
+    machine: &mut Machine,
+    input: &mut R,
+    output: &mut W,
+    trace_writer: &mut T,
+) -> Result<RunResult, VmError> {
+    machine.validate()?;
+    let mut input = BufReader::new(input);
+    let start = Instant::now();
+    let mut separator = "";
+
+    write!(trace_writer, "[")?;
+    while let Some(instruction) = machine.code.get(machine.pc as usize) {
+        let name = instruction.mnemonic();
+        let pc = machine.pc;
+        let ts = start.elapsed().as_nanos();
+        let running = match machine.step(&mut input, output) {
+            Ok(running) => running,
+            Err(error) => {
+                // Keep the trace valid JSON up to the failing instruction
+                writeln!(trace_writer, "\n]")?;
+                return Err(error);
+            }
+        };
+        let dur = start.elapsed().as_nanos() - ts;
+
+        write!(
+            trace_writer,
+            "{}\n{{\"ph\":\"X\",\"name\":\"{}\",\"ts\":{},\"dur\":{},\"pid\":0,\"tid\":0,\
+             \"args\":{{\"pc\":{},\"stack_depth\":{}}}}}",
+            separator,
+            name,
+            ts,
+            dur,
+            pc,
+            machine.stack.len()
+        )?;
+        separator = ",";
+
+        if !running {
+            break;
+        }
+    }
+    writeln!(trace_writer, "\n]")?;
+
+    Ok(machine.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction;
+
+    #[test]
+    fn test_json_trace() {
+        let mut machine = Machine {
+            code: vec![
+                Instruction::Push(1),
+                Instruction::Copy(0),
+                Instruction::Out(0),
+            ],
+            stack: vec![],
+            pc: 0,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let mut trace = Vec::new();
+        run_with_json_trace(&mut machine, &mut &[][..], &mut output, &mut trace).unwrap();
+
+        let trace = String::from_utf8(trace).unwrap();
+        let events: Vec<&str> = trace.lines().filter(|l| l.starts_with('{')).collect();
+
+        assert_eq!(output, b"1\n");
+        assert!(trace.starts_with('[') && trace.ends_with("]\n"));
+        assert_eq!(events.len(), 3);
+        assert!(events[0].contains("\"name\":\"PUSH\""));
+        assert!(events[0].ends_with("\"args\":{\"pc\":0,\"stack_depth\":1}},"));
+        assert!(events[2].contains("\"name\":\"OUT\""));
+        assert!(events[2].ends_with("\"args\":{\"pc\":2,\"stack_depth\":2}}"));
+    }
+
+    #[test]
+    fn test_json_trace_of_failing_run() {
+        let mut machine = Machine {
+            code: vec![Instruction::Push(1), Instruction::DropRange(0, 2)],
+            ..Default::default()
+        };
+        let mut trace = Vec::new();
+        let result = run_with_json_trace(&mut machine, &mut &[][..], &mut Vec::new(), &mut trace);
+
+        let trace = String::from_utf8(trace).unwrap();
+        assert!(result.is_err());
+        assert!(trace.starts_with('[') && trace.ends_with("]\n"));
+        assert!(trace.trim_end_matches("\n]\n").ends_with('}'));
+    }
+}
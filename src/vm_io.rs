@@ -0,0 +1,99 @@
+use std::io::{self, Read, Write};
+
+// The input of Machine::run. Every io::Read is a VmRead. Hosts whose input
+// is not an io::Read, such as wasm::HostBuffer holding bytes handed over
+// from JavaScript when running as WebAssembly, implement this instead. The methods work like
+// their io counterparts, their names keep calls on types implementing
+// both traits unambiguous
+pub trait VmRead {
+    fn vm_read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+// The output of Machine::run, see VmRead
+pub trait VmWrite {
+    fn vm_write(&mut self, buf: &[u8]) -> io::Result<usize>;
+    fn vm_flush(&mut self) -> io::Result<()>;
+}
+
+impl<R: Read> VmRead for R {
+    fn vm_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+}
+
+impl<W: Write> VmWrite for W {
+    fn vm_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(self, buf)
+    }
+
+    fn vm_flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+// Adapts a VmRead or VmWrite back to io::Read or io::Write for the machine
+pub(crate) struct VmIo<'a, T>(pub &'a mut T);
+
+impl<T: VmRead> Read for VmIo<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.vm_read(buf)
+    }
+}
+
+impl<T: VmWrite> Write for VmIo<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.vm_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.vm_flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instruction, Machine};
+
+    // Neither io::Read nor io::Write
+    struct Bytes {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl VmRead for Bytes {
+        fn vm_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = buf.len().min(self.data.len() - self.position);
+            buf[..read].copy_from_slice(&self.data[self.position..self.position + read]);
+            self.position += read;
+            Ok(read)
+        }
+    }
+
+    impl VmWrite for Bytes {
+        fn vm_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn vm_flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_with_custom_io() {
+        let mut machine = Machine::new(vec![Instruction::In(), Instruction::Out(0)]);
+        let mut input = Bytes {
+            data: b"42\n".to_vec(),
+            position: 0,
+        };
+        let mut output = Bytes {
+            data: Vec::new(),
+            position: 0,
+        };
+        machine.run(&mut input, &mut output).unwrap();
+
+        assert_eq!(output.data, b"42\n");
+    }
+}
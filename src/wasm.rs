@@ -0,0 +1,181 @@
+// Entry points for running the machine as a WebAssembly module on
+// wasm32-unknown-unknown. The host, e.g. JavaScript holding the bytecode
+// and input in Uint8Arrays, copies them into buffers owned by the module
+// and reads the output back from module memory:
+//
+//   code = vm_code_buffer(bytecode.length)     copy bytecode to code
+//   input = vm_input_buffer(input.length)      copy the input to input
+//   status = vm_run()
+//   read vm_output_len() bytes at vm_output_ptr()
+//
+// Build with cargo rustc --lib --release --target wasm32-unknown-unknown
+// --features wasm --crate-type cdylib
+use std::{cell::RefCell, io};
+
+use crate::{read_bytecode, Machine, RunResult, VmRead, VmWrite};
+
+// vm_run statuses
+pub const WASM_FINISHED: u32 = 0;
+// The program executed ExitCode, see vm_exit_code
+pub const WASM_EXITED: u32 = 1;
+// Loading or running failed, the output holds the error message
+pub const WASM_FAILED: u32 = 2;
+
+// Bytes shared with the host. Reading consumes them from the front
+#[derive(Debug, Default)]
+pub struct HostBuffer {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl HostBuffer {
+    pub fn new(data: Vec<u8>) -> Self {
+        HostBuffer { data, position: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl VmRead for HostBuffer {
+    fn vm_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rest = &self.data[self.position..];
+        let read = buf.len().min(rest.len());
+        buf[..read].copy_from_slice(&rest[..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+impl VmWrite for HostBuffer {
+    fn vm_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn vm_flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    code: Vec<u8>,
+    input: Vec<u8>,
+    output: HostBuffer,
+    exit_code: i32,
+}
+
+thread_local! {
+    static BUFFERS: RefCell<Buffers> = RefCell::default();
+}
+
+// Resizes the code buffer to len zeroed bytes for the host to fill
+#[no_mangle]
+pub extern "C" fn vm_code_buffer(len: usize) -> *mut u8 {
+    BUFFERS.with_borrow_mut(|buffers| {
+        buffers.code = vec![0; len];
+        buffers.code.as_mut_ptr()
+    })
+}
+
+// Resizes the input buffer to len zeroed bytes for the host to fill
+#[no_mangle]
+pub extern "C" fn vm_input_buffer(len: usize) -> *mut u8 {
+    BUFFERS.with_borrow_mut(|buffers| {
+        buffers.input = vec![0; len];
+        buffers.input.as_mut_ptr()
+    })
+}
+
+// Runs the bytecode in the code buffer on the input buffer, replacing the
+// output of the previous run
+#[no_mangle]
+pub extern "C" fn vm_run() -> u32 {
+    BUFFERS.with_borrow_mut(|buffers| {
+        let mut output = HostBuffer::default();
+        let result = read_bytecode(&mut &buffers.code[..])
+            .map_err(|e| e.to_string())
+            .and_then(|code| {
+                let mut input = HostBuffer::new(std::mem::take(&mut buffers.input));
+                Machine::new(code)
+                    .run(&mut input, &mut output)
+                    .map_err(|e| e.to_string())
+            });
+        let status = match result {
+            Ok(RunResult::ProcessExit(code)) => {
+                buffers.exit_code = code;
+                WASM_EXITED
+            }
+            Ok(_) => WASM_FINISHED,
+            Err(message) => {
+                output = HostBuffer::new(message.into_bytes());
+                WASM_FAILED
+            }
+        };
+        buffers.output = output;
+        status
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn vm_output_ptr() -> *const u8 {
+    BUFFERS.with_borrow(|buffers| buffers.output.as_bytes().as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn vm_output_len() -> usize {
+    BUFFERS.with_borrow(|buffers| buffers.output.as_bytes().len())
+}
+
+// The code the program exited with when vm_run returned WASM_EXITED
+#[no_mangle]
+pub extern "C" fn vm_exit_code() -> i32 {
+    BUFFERS.with_borrow(|buffers| buffers.exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{write_bytecode, Encoding, Endianness, Instruction};
+
+    fn fill(buffer: *mut u8, bytes: &[u8]) {
+        BUFFERS.with_borrow_mut(|buffers| {
+            for target in [&mut buffers.code, &mut buffers.input] {
+                if target.as_mut_ptr() == buffer {
+                    target.copy_from_slice(bytes);
+                }
+            }
+        });
+    }
+
+    fn output() -> Vec<u8> {
+        BUFFERS.with_borrow(|buffers| buffers.output.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_run() {
+        let mut bytecode = Vec::new();
+        let code = [
+            Instruction::In(),
+            Instruction::Out(0),
+            Instruction::ExitCode(0),
+        ];
+        write_bytecode(&code, Encoding::Fixed(Endianness::Little), &mut bytecode).unwrap();
+
+        fill(vm_code_buffer(bytecode.len()), &bytecode);
+        fill(vm_input_buffer(3), b"42\n");
+        assert_eq!(vm_run(), WASM_EXITED);
+        assert_eq!(vm_exit_code(), 42);
+        assert_eq!(output(), b"42\n");
+        assert_eq!(vm_output_len(), 3);
+
+        let mut bytecode = Vec::new();
+        let code = [Instruction::DropRange(0, 1)];
+        write_bytecode(&code, Encoding::Fixed(Endianness::Little), &mut bytecode).unwrap();
+        fill(vm_code_buffer(bytecode.len()), &bytecode);
+        assert_eq!(vm_run(), WASM_FAILED);
+        assert!(!output().is_empty());
+    }
+}